@@ -0,0 +1,40 @@
+//! Runs the default scene on the CPU backend for a second of simulated time
+//! and prints where the fluid ended up, without opening a window.
+//!
+//! ```bash
+//! cargo run -p fluidsim-core --example headless
+//! ```
+
+use fluidsim_core::{Backend, InitialConditions, Simulation, SimulationError};
+use gpu_shared::Settings;
+
+const STEPS: u32 = 240;
+const DTIME: f32 = 1.0 / 240.0;
+
+fn main() -> Result<(), SimulationError> {
+    let init = InitialConditions {
+        seed: Some(1),
+        ..InitialConditions::default()
+    };
+
+    let mut sim = pollster::block_on(Simulation::new(init, Settings::default(), Backend::Cpu))?;
+
+    for _ in 0..STEPS {
+        sim.step(DTIME)?;
+    }
+
+    let boundary = sim.settings().boundary_particles as usize;
+    let positions = sim.positions()?;
+    let densities = sim.densities()?;
+    let fluid = &positions[boundary..];
+
+    let centre = fluid.iter().map(|p| p.truncate()).sum::<glam::Vec3>() / fluid.len() as f32;
+    let density = densities[boundary..].iter().map(|d| d.x).sum::<f32>() / fluid.len() as f32;
+
+    println!(
+        "{} particles after {STEPS} steps, centred at {centre:.3}, mean density {density:.2}",
+        fluid.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,197 @@
+//! Rigid bodies in a scene, and the boundary particles they are sampled with.
+
+use glam::{Quat, Vec3, Vec4};
+use gpu_shared::{
+    Settings,
+    bodies::{Bodies, MAX_BODIES, RigidBody},
+};
+use serde::{Deserialize, Serialize};
+
+/// The outline of a body, around its own origin.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Shape {
+    Sphere {
+        radius: f32,
+    },
+    Box {
+        size: Vec3,
+    },
+    /// A convex mesh, with each face wound counterclockwise seen from outside
+    Convex {
+        vertices: Vec<Vec3>,
+        faces: Vec<[u32; 3]>,
+    },
+}
+
+impl Shape {
+    /// Signed distance from the surface, negative inside. Only a bound
+    /// outside a box or a mesh, which is all the sampling needs.
+    #[must_use]
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match self {
+            Shape::Sphere { radius } => p.length() - radius,
+            Shape::Box { size } => {
+                let q = p.abs() - *size / 2.0;
+                q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+            }
+            Shape::Convex { vertices, faces } => faces
+                .iter()
+                .map(|face| {
+                    let [a, b, c] = face.map(|i| vertices[i as usize]);
+                    let normal = (b - a).cross(c - a).normalize_or_zero();
+                    normal.dot(p - a)
+                })
+                .fold(f32::NEG_INFINITY, f32::max),
+        }
+    }
+
+    // corners of a box around the shape
+    fn bounds(&self) -> (Vec3, Vec3) {
+        match self {
+            Shape::Sphere { radius } => (Vec3::splat(-radius), Vec3::splat(*radius)),
+            Shape::Box { size } => (-*size / 2.0, *size / 2.0),
+            Shape::Convex { vertices, .. } => vertices.iter().fold(
+                (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+                |(lo, hi), &v| (lo.min(v), hi.max(v)),
+            ),
+        }
+    }
+}
+
+/// A rigid body that floats or sinks in the fluid. Coordinates are relative
+/// to the center of the boundary box, before it is rotated, like a
+/// [`crate::Volume`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Body {
+    pub shape: Shape,
+    pub center: Vec3,
+    #[serde(default)]
+    pub rotation: Quat,
+    /// In the units of the fluid's target density, so a body below it
+    /// floats. Zero pins the body in place
+    pub density: f32,
+}
+
+impl Body {
+    /// Whether `p`, in the same frame as [`Body::center`], is within `margin`
+    /// of the body.
+    #[must_use]
+    pub fn contains(&self, p: Vec3, margin: f32) -> bool {
+        let local = self.rotation.conjugate() * (p - self.center);
+        self.shape.distance(local) < margin
+    }
+
+    /// Samples the surface with particles `spacing` apart, and works out the
+    /// mass and inertia from the inside sampled twice as finely. Returns the
+    /// body at rest and the particles relative to its center of mass, in
+    /// the body's frame.
+    fn sample(&self, spacing: f32) -> (RigidBody, Vec<Vec3>) {
+        let (lo, hi) = self.shape.bounds();
+
+        let grid = |step: f32| {
+            let count = ((hi - lo) / step).ceil().as_uvec3() + 1;
+            let offset = lo + ((hi - lo) - (count - 1).as_vec3() * step) / 2.0;
+
+            (0..count.x).flat_map(move |i| {
+                (0..count.y).flat_map(move |j| {
+                    (0..count.z)
+                        .map(move |k| offset + Vec3::new(i as f32, j as f32, k as f32) * step)
+                })
+            })
+        };
+
+        let step = spacing / 2.0;
+        let inside = grid(step)
+            .filter(|&p| self.shape.distance(p) <= 0.0)
+            .collect::<Vec<_>>();
+
+        let cell = self.density * step.powi(3);
+        let mass = cell * inside.len() as f32;
+        let center = inside.iter().sum::<Vec3>() / inside.len().max(1) as f32;
+        let inertia = inside.iter().fold(Vec3::ZERO, |sum, &p| {
+            let r = (p - center) * (p - center);
+            sum + cell * Vec3::new(r.y + r.z, r.x + r.z, r.x + r.y)
+        });
+
+        let surface = grid(spacing)
+            .filter(|&p| (-spacing..=0.0).contains(&self.shape.distance(p)))
+            .map(|p| p - center)
+            .collect::<Vec<_>>();
+
+        let radius = surface.iter().map(|p| p.length()).fold(0.0, f32::max);
+        let inverse = |x: f32| if x > 0.0 { 1.0 / x } else { 0.0 };
+
+        let body = RigidBody {
+            position: center,
+            inv_mass: inverse(mass),
+            rotation: self.rotation,
+            radius,
+            inv_inertia: Vec3::new(inverse(inertia.x), inverse(inertia.y), inverse(inertia.z)),
+            ..RigidBody::default()
+        };
+
+        (body, surface)
+    }
+}
+
+/// The particles of every body, and their body and anchor.
+pub(crate) struct Sampled {
+    pub bodies: Bodies,
+    pub count: u32,
+    /// World positions, after the box's shell
+    pub positions: Vec<Vec4>,
+    /// Positions in the body's frame, with the body in `w`
+    pub anchors: Vec<Vec4>,
+}
+
+/// Samples `bodies` in the box where `settings` put it. Bodies past
+/// [`MAX_BODIES`] are left out.
+pub(crate) fn sample(bodies: &[Body], spacing: f32, settings: &Settings) -> Sampled {
+    if bodies.len() > MAX_BODIES {
+        warn!(
+            "{} rigid bodies, only the first {MAX_BODIES} are simulated",
+            bodies.len()
+        );
+    }
+
+    let mut sampled = Sampled {
+        bodies: Bodies::default(),
+        count: 0,
+        positions: Vec::new(),
+        anchors: Vec::new(),
+    };
+
+    for (i, body) in bodies.iter().take(MAX_BODIES).enumerate() {
+        let (mut rigid, surface) = body.sample(spacing);
+
+        // into the box's frame, then the world's
+        let local = settings.box_size / 2.0 + body.center + body.rotation * rigid.position;
+        rigid.position = settings.from_box(local);
+        rigid.rotation = settings.box_quat * body.rotation;
+
+        for local in surface {
+            sampled.positions.push(rigid.world(local).extend(0.0));
+            sampled.anchors.push(local.extend(i as f32));
+        }
+
+        sampled.bodies.bodies[i] = rigid;
+        sampled.count += 1;
+    }
+
+    sampled
+}
+
+/// The anchors of body particles at `positions`, whose body is in `w` of
+/// their prediction, for a restored checkpoint.
+pub(crate) fn anchors(bodies: &Bodies, positions: &[Vec4], predictions: &[Vec4]) -> Vec<Vec4> {
+    positions
+        .iter()
+        .zip(predictions)
+        .map(|(pos, pred)| {
+            let body = &bodies.bodies[(pred.w as usize).min(MAX_BODIES - 1)];
+            let local = body.rotation.conjugate() * (pos.truncate() - body.position);
+            local.extend(pred.w)
+        })
+        .collect()
+}
@@ -0,0 +1,164 @@
+//! The volume each boundary particle stands for, which the kernels weight its
+//! density and pressure by (Akinci et al. 2012). Kept in `x` of the boundary
+//! particles' densities, which the kernels don't otherwise use.
+
+use std::collections::HashMap;
+
+use glam::{BVec3, IVec3, Vec3, Vec4};
+use gpu_shared::{kernel, sp_hash};
+use rayon::prelude::*;
+
+/// The boundary particles relative to what they're fixed to, so their
+/// volumes can be worked out again when the smoothing radius or kernel
+/// changes.
+#[derive(Default)]
+pub(crate) struct BoundaryVolumes {
+    // the walls and meshes, in the frame of the box
+    walls: Vec<Vec3>,
+    // body particles in the frame of their body, with the body in w
+    bodies: Vec<Vec4>,
+    box_size: Vec3,
+    periodic: BVec3,
+    // the kernel and smoothing radius of the last volumes, zero before the
+    // first
+    kernel: u32,
+    radius: f32,
+}
+
+impl BoundaryVolumes {
+    pub fn new(walls: Vec<Vec3>, bodies: Vec<Vec4>, box_size: Vec3, periodic: BVec3) -> Self {
+        Self {
+            walls,
+            bodies,
+            box_size,
+            periodic,
+            kernel: 0,
+            radius: 0.0,
+        }
+    }
+
+    /// The volume of every boundary particle, walls first, unless they are
+    /// already up to date for `kernel` and `radius`. The walls only count each
+    /// other, across the periodic axes too, and each body only counts
+    /// itself.
+    #[allow(clippy::float_cmp)]
+    pub fn update(&mut self, kernel: u32, radius: f32) -> Option<Vec<f32>> {
+        if (kernel == self.kernel && radius == self.radius) || radius <= 0.0 {
+            return None;
+        }
+        self.kernel = kernel;
+        self.radius = radius;
+
+        let ghosts = ghosts(&self.walls, self.box_size, self.periodic, radius);
+        let mut out = volumes(&self.walls, &ghosts, kernel, radius);
+
+        for body in self.bodies.chunk_by(|a, b| a.w as u32 == b.w as u32) {
+            let points = body.iter().map(|p| p.truncate()).collect::<Vec<_>>();
+            out.extend(volumes(&points, &[], kernel, radius));
+        }
+
+        Some(out)
+    }
+}
+
+// copies of `points` a box over across the periodic axes, where they are
+// within `radius` of the other side
+fn ghosts(points: &[Vec3], size: Vec3, periodic: BVec3, radius: f32) -> Vec<Vec3> {
+    let mut ghosts = Vec::new();
+
+    for axis in 0..3 {
+        if !periodic.test(axis) {
+            continue;
+        }
+
+        // the ghosts of the earlier axes too, for the edges and corners
+        let shift = Vec3::AXES[axis] * size[axis];
+        let copies = points
+            .iter()
+            .chain(&ghosts)
+            .flat_map(|&p| {
+                let low = (p[axis] < radius).then_some(p + shift);
+                let high = (p[axis] > size[axis] - radius).then_some(p - shift);
+                low.into_iter().chain(high)
+            })
+            .collect::<Vec<_>>();
+        ghosts.extend(copies);
+    }
+
+    ghosts
+}
+
+// `1 / sum W` over `points` and `ghosts`, for each of `points`
+fn volumes(points: &[Vec3], ghosts: &[Vec3], kernel: u32, radius: f32) -> Vec<f32> {
+    let cell = |p: Vec3| (p / radius).floor().as_ivec3();
+
+    let mut grid = HashMap::<IVec3, Vec<Vec3>>::new();
+    for &p in points.iter().chain(ghosts) {
+        grid.entry(cell(p)).or_default().push(p);
+    }
+
+    points
+        .par_iter()
+        .map(|&p| {
+            let home = cell(p);
+            let sum = sp_hash::NEIGHBORS
+                .iter()
+                .filter_map(|offset| grid.get(&(home + *offset)))
+                .flatten()
+                .map(|&other| kernel::value(kernel, p.distance(other), radius))
+                .sum::<f32>();
+
+            // every particle counts itself, so the sum is never zero
+            1.0 / sum
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::IVec3;
+
+    use super::*;
+
+    fn sorted(points: &[Vec3]) -> Vec<[f32; 3]> {
+        let mut points = points.iter().map(Vec3::to_array).collect::<Vec<_>>();
+        points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        points
+    }
+
+    #[test]
+    fn ghosts_cover_every_face_edge_and_corner() {
+        let size = Vec3::splat(4.0);
+        let center = size / 2.0;
+
+        for offset in sp_hash::NEIGHBORS.iter().filter(|&&o| o != IVec3::ZERO) {
+            let dir = offset.as_vec3();
+            let point = center + dir * 1.6;
+            let ghosts = ghosts(&[point], size, BVec3::TRUE, 1.0);
+
+            // one copy for each way of picking the axes it's near the side of
+            let expected: Vec<_> = (1..8)
+                .map(|pick| BVec3::new(pick & 1 != 0, pick & 2 != 0, pick & 4 != 0))
+                .filter(|&pick| (pick & dir.cmpeq(Vec3::ZERO)) == BVec3::FALSE)
+                .map(|pick| point - Vec3::select(pick, dir, Vec3::ZERO) * size)
+                .collect();
+
+            assert_eq!(sorted(&ghosts), sorted(&expected), "{offset}");
+        }
+    }
+
+    #[test]
+    fn no_ghosts_away_from_the_sides_or_across_walls() {
+        let size = Vec3::splat(4.0);
+
+        assert!(ghosts(&[size / 2.0], size, BVec3::TRUE, 1.0).is_empty());
+
+        // near the corner, but only x wraps
+        let near = Vec3::splat(0.5);
+        let periodic = BVec3::new(true, false, false);
+        assert_eq!(
+            sorted(&ghosts(&[near], size, periodic, 1.0)),
+            sorted(&[near + Vec3::X * 4.0])
+        );
+    }
+}
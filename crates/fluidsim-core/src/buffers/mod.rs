@@ -0,0 +1,236 @@
+use std::{marker::PhantomData, sync::mpsc};
+
+use bytemuck::NoUninit;
+use gpu_shared::{
+    Globals, MouseState, Primitive,
+    bodies::Bodies,
+    colors::Coloring,
+    flow::{Flow, FlowState},
+    materials::Materials,
+    obstacles::{Obstacles, SdfGrids},
+    solver::SolverState,
+    timestep::TimeStep,
+};
+
+use crate::prelude::*;
+
+#[derive(Debug, Snafu)]
+pub enum ReadError {
+    #[snafu(display("At {location}: wgpu: failed to map buffer for reading\n{source}"))]
+    Map {
+        source: wgpu::BufferAsyncError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: wgpu: failed to poll device\n{source}"))]
+    Poll {
+        source: wgpu::PollError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+pub struct BufferBinding<A: ?Sized> {
+    pub buffer: Arc<wgpu::Buffer>,
+    pub binding: wgpu::BindingType,
+    _phantom: PhantomData<A>,
+}
+
+impl<A: ?Sized> BufferBinding<A> {
+    pub fn reset<'a, K>(&'a self, queue: &wgpu::Queue, data: &'a A)
+    where
+        &'a A: AsRef<[K]>,
+        K: NoUninit,
+    {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(data.as_ref()));
+    }
+
+    /// Writes `data` to the start of the buffer, leaving the rest untouched.
+    pub fn write<K: NoUninit>(&self, queue: &wgpu::Queue, data: &[K]) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(data));
+    }
+
+    /// Copies the first `len` elements of the buffer back to the host. Blocks
+    /// until the gpu has finished all previously submitted work.
+    pub fn read<K: Pod>(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        len: usize,
+    ) -> Result<Vec<K>, ReadError> {
+        let size = (len * size_of::<K>()) as u64;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("physics/readback"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("physics/readback_encoder"),
+        });
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &staging, 0, size);
+        queue.submit([encoder.finish()]);
+
+        let (tx, rx) = mpsc::channel();
+        staging
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |res| _ = tx.send(res));
+        device
+            .poll(wgpu::PollType::wait_indefinitely())
+            .context(PollSnafu)?;
+
+        // the callback always runs before poll returns when waiting indefinitely
+        rx.recv()
+            .unwrap_or(Err(wgpu::BufferAsyncError))
+            .context(MapSnafu)?;
+
+        let data = bytemuck::pod_collect_to_vec(&staging.slice(..).get_mapped_range());
+        staging.unmap();
+
+        Ok(data)
+    }
+}
+
+macro_rules! buffers {
+    (@usage uniform) => (wgpu::BufferUsages::UNIFORM);
+    (@usage storage) => (wgpu::BufferUsages::STORAGE);
+
+    (@bbt uniform) => (wgpu::BufferBindingType::Uniform);
+    (@bbt storage) => (wgpu::BufferBindingType::Storage { read_only: false });
+
+    (@sliceof [$t:ty]) => ([$t]);
+    (@sliceof $t:ty) => ([$t; 1]);
+
+    // per-particle buffers are written as `[T]` and hold `capacity` elements
+    (@elem [$t:ty]) => ($t);
+    (@elem $t:ty) => ($t);
+
+    (@len $cap:ident [$t:ty]) => ($cap as usize);
+    (@len $cap:ident $t:ty) => (1);
+
+    ($(
+        group $gid:ident($gty:ident) {$(
+            $bid:ident($($bty:tt)+): $type:ident; $($usage:ident)|+
+        ),+ $(,)?}
+    )+) => {
+        $(
+            pub struct $gty {
+                $(pub $bid: BufferBinding<buffers!(@sliceof $($bty)+)>),+
+            }
+
+            impl $gty {
+                #[allow(unused_variables)]
+                fn buffers(device: &wgpu::Device, capacity: u32) -> Self {
+                    Self {$(
+                        $bid: BufferBinding {
+                            buffer: Arc::new(device.create_buffer(&wgpu::BufferDescriptor {
+                                label: Some(concat!("physics/bindgroup:", stringify!($gid), "/buffer:", stringify!($bid))),
+                                size: (buffers!(@len capacity $($bty)+) * ::std::mem::size_of::<buffers!(@elem $($bty)+)>()) as u64,
+                                usage: buffers!(@usage $type) | $(wgpu::BufferUsages::$usage)|+,
+                                mapped_at_creation: false,
+                            })),
+                            binding: wgpu::BindingType::Buffer {
+                                ty: buffers!(@bbt $type),
+                                has_dynamic_offset: false,
+                                min_binding_size: wgpu::BufferSize::new(::std::mem::size_of::<buffers!(@elem $($bty)+)>() as u64),
+                            },
+                            _phantom: ::std::marker::PhantomData,
+                        }
+                    ),+}
+                }
+            }
+        )+
+
+        pub struct Sort {
+            pub keys: BufferBinding<[u32]>,
+            pub lookup: BufferBinding<[u32]>,
+        }
+
+        impl Sort {
+            pub fn new(keys: &wgpu::Buffer, values: &wgpu::Buffer) -> Self {
+                Self {
+                    lookup: BufferBinding {
+                        buffer: Arc::new(keys.clone()),
+                        binding: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(size_of::<u32>() as u64),
+                        },
+                        _phantom: ::std::marker::PhantomData,
+                    },
+                    keys: BufferBinding {
+                        buffer: Arc::new(values.clone()),
+                        binding: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: wgpu::BufferSize::new(size_of::<u32>() as u64),
+                        },
+                        _phantom: ::std::marker::PhantomData,
+                    },
+                }
+            }
+        }
+
+        pub struct Buffers {
+            $(pub $gid: $gty,)+
+            pub sort: Sort,
+        }
+
+        impl Buffers {
+            /// Sizes the per-particle buffers to hold `capacity` particles. The
+            /// sorter must have been created with at least as many keys.
+            pub fn new(device: &wgpu::Device, sorter: &wgpu_sort::Sorter, capacity: u32) -> Self {
+                Self {
+                    $($gid: $gty::buffers(&device, capacity),)+
+                    sort: Sort::new(sorter.buffer_keys(), sorter.buffer_values()),
+                }
+            }
+        }
+    };
+}
+
+buffers!(
+    group uniform(Uniform) {
+        settings(SimSettings): uniform; COPY_DST,
+        mouse(MouseState): uniform; COPY_DST,
+        globals(Globals): uniform; COPY_DST,
+        colors(Coloring): uniform; COPY_DST,
+        materials(Materials): uniform; COPY_DST,
+        obstacles(Obstacles): uniform; COPY_DST,
+        flow(Flow): uniform; COPY_DST,
+    }
+
+    group physics(Physics) {
+        positions([[f32; 4]]): storage; COPY_SRC | COPY_DST, // use vec4 for alignment/padding reasons, [x, y, z, w] where w marks particles a sink removed
+        predictions([[f32; 4]]): storage; COPY_SRC | COPY_DST,
+        velocities([[f32; 4]]): storage; COPY_SRC | COPY_DST,
+        densities([[f32; 2]]): storage; COPY_SRC | COPY_DST,
+        deltas([[f32; 4]]): storage; COPY_SRC | COPY_DST, // pbf position corrections, and the jitter and forces waiting for an apply pass
+        stiffness([f32]): storage; COPY_SRC | COPY_DST, // dfsph pressure over density
+        state(SolverState): storage; COPY_SRC | COPY_DST, // dfsph convergence
+        previous([[f32; 4]]): storage; COPY_SRC | COPY_DST, // velocities at the start of the last step
+        timestep(TimeStep): storage; COPY_SRC | COPY_DST, // adaptive step size
+        normals([[f32; 4]]): storage; COPY_SRC | COPY_DST, // surface tension normals
+        shear([f32]): storage; COPY_SRC | COPY_DST, // shear rates, for the non-newtonian viscosity
+        anchors([[f32; 4]]): storage; COPY_SRC | COPY_DST, // boundary particles in the frame of the box, or of their body with the body in w
+        bodies(Bodies): storage; COPY_SRC | COPY_DST, // rigid bodies
+        sdfs(SdfGrids): storage; COPY_DST, // obstacles baked from meshes
+        flow_state(FlowState): storage; COPY_SRC | COPY_DST, // particle count while emitters or sinks change it
+        blocks([[u32; 2]]): storage; COPY_DST, // holes and particles filling them before each block of slots, far fewer blocks than particles
+        holes([[u32; 2]]): storage; COPY_DST, // slots the sinks emptied, paired with the particles past the new count that fill them
+    }
+
+    group drawing(Drawing) {
+        primitives([Primitive]): storage; COPY_SRC | COPY_DST,
+    }
+
+    group spatial_hash(SpatialHash) {
+        indices([u32]): storage; COPY_SRC | COPY_DST,
+    }
+);
@@ -0,0 +1,290 @@
+//! Checkpoints, which snapshot the full particle state of a simulation so it
+//! can be resumed exactly.
+//!
+//! The file is a small header followed by the raw buffers, all native-endian:
+//!
+//! | field       | size                                |
+//! |-------------|-------------------------------------|
+//! | magic       | 8 bytes, `FLUIDCKP`                 |
+//! | version     | `u32`                               |
+//! | particles   | `u32`, boundary particles included  |
+//! | settings    | [`SimSettings`]                     |
+//! | has camera  | `u32`, 0 or 1                       |
+//! | camera      | [`Camera`]                          |
+//! | bodies      | [`Bodies`]                          |
+//! | flow        | [`FlowState`]                       |
+//! | timestep    | [`TimeStep`]                        |
+//! | positions   | `particles` × `Vec4`                |
+//! | predictions | `particles` × `Vec4`                |
+//! | velocities  | `particles` × `Vec4`                |
+//! | densities   | `particles` × `Vec2`                |
+//! | previous    | `particles` × `Vec4`                |
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use glam::{Quat, Vec2, Vec3, Vec4};
+use gpu_shared::{
+    bodies::{Bodies, MAX_BODIES},
+    flow::FlowState,
+    timestep::TimeStep,
+};
+
+use crate::prelude::*;
+
+const MAGIC: &[u8; 8] = b"FLUIDCKP";
+pub const VERSION: u32 = 13;
+
+#[derive(Debug, Snafu)]
+pub enum CheckpointError {
+    #[snafu(display("At {location}: failed to access {path}\n{source}"))]
+    Io {
+        path: String,
+        source: io::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: {path} is not a checkpoint"))]
+    BadMagic {
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display(
+        "At {location}: checkpoint version {found} is not supported, expected {VERSION}"
+    ))]
+    Version {
+        found: u32,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display(
+        "At {location}: checkpoint claims {found} particles, but {path} is too short"
+    ))]
+    Truncated {
+        path: String,
+        found: u32,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display(
+        "At {location}: checkpoint has {body} body particles in {boundary} boundary particles in {particles} particles, which don't nest"
+    ))]
+    Particles {
+        body: u32,
+        boundary: u32,
+        particles: usize,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display(
+        "At {location}: checkpoint has {found} rigid bodies, at most {MAX_BODIES} fit"
+    ))]
+    TooManyBodies {
+        found: u32,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: checkpoint buffers hold different numbers of particles"))]
+    Mismatched {
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// The viewer's camera, stored alongside the particles so a restored run looks
+/// the same. Headless runs leave it out.
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct Camera {
+    pub translate: Vec3,
+    pub fov: f32,
+    pub rotation: Quat,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub settings: SimSettings,
+    pub camera: Option<Camera>,
+    /// Every rigid body slot, the first `settings.num_bodies` in use
+    pub bodies: Bodies,
+    /// How far the emitters have got, with the particle count
+    pub flow: FlowState,
+    /// What the adaptive time step measured and chose last
+    pub timestep: TimeStep,
+
+    pub positions: Vec<Vec4>,
+    pub predictions: Vec<Vec4>,
+    pub velocities: Vec<Vec4>,
+    pub densities: Vec<Vec2>,
+    /// Velocities at the start of the last step, for the adaptive time step
+    pub previous: Vec<Vec4>,
+}
+
+impl Checkpoint {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CheckpointError> {
+        let path = path.as_ref();
+        let ctx = || IoSnafu {
+            path: path.display().to_string(),
+        };
+
+        let mut w = BufWriter::new(File::create(path).with_context(|_| ctx())?);
+        self.write_to(&mut w).with_context(|_| ctx())?;
+        w.flush().with_context(|_| ctx())?;
+
+        info!("Saved checkpoint to {}", path.display());
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CheckpointError> {
+        let path = path.as_ref();
+        let ctx = || IoSnafu {
+            path: path.display().to_string(),
+        };
+
+        let file = File::open(path).with_context(|_| ctx())?;
+        let len = file.metadata().with_context(|_| ctx())?.len();
+        let mut r = BufReader::new(file);
+
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic).with_context(|_| ctx())?;
+        ensure!(
+            &magic == MAGIC,
+            BadMagicSnafu {
+                path: path.display().to_string()
+            }
+        );
+
+        let version = read_pod::<u32>(&mut r).with_context(|_| ctx())?;
+        ensure!(version == VERSION, VersionSnafu { found: version });
+
+        let particles = read_pod::<u32>(&mut r).with_context(|_| ctx())?;
+        ensure!(
+            Self::file_size(particles) <= len,
+            TruncatedSnafu {
+                path: path.display().to_string(),
+                found: particles
+            }
+        );
+
+        let checkpoint = Self::read_from(&mut r, particles as usize).with_context(|_| ctx())?;
+        checkpoint.validate()?;
+
+        info!("Loaded checkpoint from {}", path.display());
+        Ok(checkpoint)
+    }
+
+    /// Checks the particle counts in the settings fit the buffers, so a
+    /// restore can't index past them.
+    pub fn validate(&self) -> Result<(), CheckpointError> {
+        let particles = self.positions.len();
+        ensure!(
+            [
+                self.predictions.len(),
+                self.velocities.len(),
+                self.densities.len(),
+                self.previous.len()
+            ]
+            .iter()
+            .all(|&len| len == particles),
+            MismatchedSnafu
+        );
+
+        let settings = &self.settings;
+        let (body, boundary) = (settings.body_particles, settings.boundary_particles);
+        ensure!(
+            body <= boundary && boundary as usize <= particles,
+            ParticlesSnafu {
+                body,
+                boundary,
+                particles
+            }
+        );
+        ensure!(
+            settings.num_bodies as usize <= MAX_BODIES,
+            TooManyBodiesSnafu {
+                found: settings.num_bodies
+            }
+        );
+
+        Ok(())
+    }
+
+    // size of a checkpoint file holding `particles` particles
+    fn file_size(particles: u32) -> u64 {
+        let header = MAGIC.len()
+            + 3 * size_of::<u32>()
+            + size_of::<SimSettings>()
+            + size_of::<Camera>()
+            + size_of::<Bodies>()
+            + size_of::<FlowState>()
+            + size_of::<TimeStep>();
+        let particle = 4 * size_of::<Vec4>() + size_of::<Vec2>();
+
+        header as u64 + u64::from(particles) * particle as u64
+    }
+
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(bytemuck::bytes_of(&VERSION))?;
+        w.write_all(bytemuck::bytes_of(&(self.positions.len() as u32)))?;
+        w.write_all(bytemuck::bytes_of(&self.settings))?;
+        w.write_all(bytemuck::bytes_of(&u32::from(self.camera.is_some())))?;
+        w.write_all(bytemuck::bytes_of(
+            &self.camera.unwrap_or_else(Camera::zeroed),
+        ))?;
+        w.write_all(bytemuck::bytes_of(&self.bodies))?;
+        w.write_all(bytemuck::bytes_of(&self.flow))?;
+        w.write_all(bytemuck::bytes_of(&self.timestep))?;
+        w.write_all(bytemuck::cast_slice(&self.positions))?;
+        w.write_all(bytemuck::cast_slice(&self.predictions))?;
+        w.write_all(bytemuck::cast_slice(&self.velocities))?;
+        w.write_all(bytemuck::cast_slice(&self.densities))?;
+        w.write_all(bytemuck::cast_slice(&self.previous))?;
+
+        Ok(())
+    }
+
+    fn read_from(r: &mut impl Read, particles: usize) -> io::Result<Self> {
+        let settings = read_pod::<SimSettings>(r)?;
+        let has_camera = read_pod::<u32>(r)? != 0;
+        let camera = read_pod::<Camera>(r)?;
+        let bodies = read_pod::<Bodies>(r)?;
+        let flow = read_pod::<FlowState>(r)?;
+        let timestep = read_pod::<TimeStep>(r)?;
+
+        Ok(Self {
+            settings,
+            camera: has_camera.then_some(camera),
+            bodies,
+            flow,
+            timestep,
+            positions: read_vec(r, particles)?,
+            predictions: read_vec(r, particles)?,
+            velocities: read_vec(r, particles)?,
+            densities: read_vec(r, particles)?,
+            previous: read_vec(r, particles)?,
+        })
+    }
+}
+
+fn read_pod<K: Pod>(r: &mut impl Read) -> io::Result<K> {
+    let mut value = K::zeroed();
+    r.read_exact(bytemuck::bytes_of_mut(&mut value))?;
+    Ok(value)
+}
+
+fn read_vec<K: Pod>(r: &mut impl Read, len: usize) -> io::Result<Vec<K>> {
+    let mut values = vec![K::zeroed(); len];
+    r.read_exact(bytemuck::cast_slice_mut(&mut values))?;
+    Ok(values)
+}
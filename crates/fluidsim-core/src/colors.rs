@@ -0,0 +1,331 @@
+//! How fluid particles are colored: which quantity, over what range, and
+//! through which colormap. [`ColorSettings`] is the editable, serializable
+//! form; the `copy_prims` kernel reads it as a [`Coloring`] uniform.
+
+use std::fmt;
+
+use glam::Vec4;
+use gpu_shared::colors::{Coloring, MAX_STOPS};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    #[default]
+    Speed,
+    Density,
+    NearDensity,
+    Pressure,
+    /// Height above the floor of the boundary box
+    Height,
+    /// Index among the fluid particles
+    Id,
+    /// Particles within the smoothing radius
+    Neighbors,
+    /// The color of each particle's material, ignoring the colormap
+    Phase,
+}
+
+impl ColorMode {
+    pub const ALL: [ColorMode; 8] = [
+        ColorMode::Speed,
+        ColorMode::Density,
+        ColorMode::NearDensity,
+        ColorMode::Pressure,
+        ColorMode::Height,
+        ColorMode::Id,
+        ColorMode::Neighbors,
+        ColorMode::Phase,
+    ];
+
+    fn gpu(self) -> u32 {
+        match self {
+            ColorMode::Speed => Coloring::SPEED,
+            ColorMode::Density => Coloring::DENSITY,
+            ColorMode::NearDensity => Coloring::NEAR_DENSITY,
+            ColorMode::Pressure => Coloring::PRESSURE,
+            ColorMode::Height => Coloring::HEIGHT,
+            ColorMode::Id => Coloring::ID,
+            ColorMode::Neighbors => Coloring::NEIGHBORS,
+            ColorMode::Phase => Coloring::PHASE,
+        }
+    }
+
+    /// A range that covers the usual values of this mode under `settings`.
+    #[must_use]
+    pub fn default_range(self, settings: &SimSettings) -> (f32, f32) {
+        let target = settings.target_density;
+
+        match self {
+            ColorMode::Speed => (0.0, 15.0),
+            ColorMode::Density | ColorMode::NearDensity => (0.0, 2.0 * target),
+            ColorMode::Pressure => {
+                let p = 0.5 * target * settings.pressure_multiplier;
+                (-p, p)
+            }
+            ColorMode::Height => (0.0, settings.box_size.y),
+            ColorMode::Id => (
+                0.0,
+                settings
+                    .num_particles
+                    .saturating_sub(settings.boundary_particles) as f32,
+            ),
+            ColorMode::Neighbors => (0.0, 64.0),
+            ColorMode::Phase => (0.0, 1.0),
+        }
+    }
+}
+
+impl fmt::Display for ColorMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColorMode::Speed => "Speed",
+            ColorMode::Density => "Density",
+            ColorMode::NearDensity => "Near Density",
+            ColorMode::Pressure => "Pressure",
+            ColorMode::Height => "Height",
+            ColorMode::Id => "Particle ID",
+            ColorMode::Neighbors => "Neighbor Count",
+            ColorMode::Phase => "Phase",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ColorStop {
+    /// Where this color sits in the range, from 0 to 1
+    pub position: f32,
+    /// Red, green and blue, each from 0 to 1
+    pub color: [f32; 3],
+}
+
+const fn stop(position: f32, [r, g, b]: [u8; 3]) -> ColorStop {
+    ColorStop {
+        position,
+        color: [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0],
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Colormap {
+    /// The original speed gradient
+    #[default]
+    Classic,
+    Viridis,
+    Magma,
+    Coolwarm,
+    /// The stops in [`ColorSettings::custom`]
+    Custom,
+}
+
+impl Colormap {
+    pub const ALL: [Colormap; 5] = [
+        Colormap::Classic,
+        Colormap::Viridis,
+        Colormap::Magma,
+        Colormap::Coolwarm,
+        Colormap::Custom,
+    ];
+
+    /// The stops of a built-in colormap, empty for [`Colormap::Custom`].
+    #[must_use]
+    pub fn stops(self) -> &'static [ColorStop] {
+        const CLASSIC: &[ColorStop] = &[
+            stop(0.10, [60, 10, 99]),
+            stop(0.25, [127, 31, 105]),
+            stop(0.50, [227, 90, 44]),
+            stop(0.75, [250, 151, 8]),
+            stop(1.00, [245, 213, 66]),
+        ];
+        const VIRIDIS: &[ColorStop] = &[
+            stop(0.00, [68, 1, 84]),
+            stop(0.25, [59, 82, 139]),
+            stop(0.50, [33, 145, 140]),
+            stop(0.75, [94, 201, 98]),
+            stop(1.00, [253, 231, 37]),
+        ];
+        const MAGMA: &[ColorStop] = &[
+            stop(0.00, [0, 0, 4]),
+            stop(0.25, [81, 18, 124]),
+            stop(0.50, [183, 55, 121]),
+            stop(0.75, [252, 137, 97]),
+            stop(1.00, [252, 253, 191]),
+        ];
+        const COOLWARM: &[ColorStop] = &[
+            stop(0.00, [59, 76, 192]),
+            stop(0.25, [123, 159, 249]),
+            stop(0.50, [221, 221, 221]),
+            stop(0.75, [244, 154, 123]),
+            stop(1.00, [180, 4, 38]),
+        ];
+
+        match self {
+            Colormap::Classic => CLASSIC,
+            Colormap::Viridis => VIRIDIS,
+            Colormap::Magma => MAGMA,
+            Colormap::Coolwarm => COOLWARM,
+            Colormap::Custom => &[],
+        }
+    }
+}
+
+impl fmt::Display for Colormap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Colormap::Classic => "Classic",
+            Colormap::Viridis => "Viridis",
+            Colormap::Magma => "Magma",
+            Colormap::Coolwarm => "Coolwarm",
+            Colormap::Custom => "Custom",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ColorSettings {
+    pub mode: ColorMode,
+    pub min: f32,
+    pub max: f32,
+    pub map: Colormap,
+    /// Up to [`MAX_STOPS`] stops, used when `map` is [`Colormap::Custom`]
+    pub custom: Vec<ColorStop>,
+}
+
+impl Default for ColorSettings {
+    fn default() -> Self {
+        Self {
+            mode: ColorMode::Speed,
+            min: 0.0,
+            max: 15.0,
+            map: Colormap::Classic,
+            custom: Colormap::Classic.stops().to_vec(),
+        }
+    }
+}
+
+impl ColorSettings {
+    /// Switches to `mode` with its default range.
+    pub fn set_mode(&mut self, mode: ColorMode, settings: &SimSettings) {
+        self.mode = mode;
+        (self.min, self.max) = mode.default_range(settings);
+    }
+
+    #[must_use]
+    pub fn uniform(&self) -> Coloring {
+        let mut stops = match self.map {
+            Colormap::Custom => self.custom.clone(),
+            map => map.stops().to_vec(),
+        };
+
+        if stops.is_empty() {
+            stops = Colormap::Classic.stops().to_vec();
+        }
+        stops.truncate(MAX_STOPS);
+
+        stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+
+        let mut coloring = Coloring {
+            num_stops: stops.len() as u32,
+            mode: self.mode.gpu(),
+            min: self.min,
+            max: self.max,
+            ..Coloring::default()
+        };
+
+        for (out, stop) in coloring.stops.iter_mut().zip(&stops) {
+            let [r, g, b] = stop.color;
+            *out = Vec4::new(r, g, b, stop.position);
+        }
+
+        coloring
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+
+    fn settings(map: Colormap, custom: Vec<ColorStop>) -> ColorSettings {
+        ColorSettings {
+            min: 10.0,
+            max: 20.0,
+            map,
+            custom,
+            ..ColorSettings::default()
+        }
+    }
+
+    fn rgb(stop: &ColorStop) -> Vec3 {
+        Vec3::from_array(stop.color)
+    }
+
+    fn assert_near(sampled: Vec4, expected: Vec3) {
+        assert!(
+            sampled.distance(expected.extend(1.0)) < 1e-5,
+            "{sampled} != {expected}"
+        );
+    }
+
+    #[test]
+    fn samples_hit_the_stops_and_clamp_past_the_ends() {
+        for map in Colormap::ALL.into_iter().filter(|&m| m != Colormap::Custom) {
+            let coloring = settings(map, vec![]).uniform();
+            let stops = map.stops();
+
+            for stop in stops {
+                assert_near(coloring.sample(10.0 + 10.0 * stop.position), rgb(stop));
+            }
+            assert_near(coloring.sample(-100.0), rgb(&stops[0]));
+            assert_near(coloring.sample(100.0), rgb(&stops[stops.len() - 1]));
+        }
+    }
+
+    #[test]
+    fn samples_blend_between_stops() {
+        let coloring = settings(Colormap::Viridis, vec![]).uniform();
+        let stops = Colormap::Viridis.stops();
+
+        let quarter = rgb(&stops[0]).lerp(rgb(&stops[1]), 0.25);
+        assert_near(coloring.sample(10.0 + 10.0 * 0.0625), quarter);
+    }
+
+    #[test]
+    fn custom_stops_are_sorted_and_capped() {
+        let gray = |position| ColorStop {
+            position,
+            color: [position; 3],
+        };
+        let mut custom = vec![gray(1.0), gray(0.0), gray(0.5)];
+        custom.extend((0..MAX_STOPS).map(|_| gray(0.75)));
+
+        let coloring = settings(Colormap::Custom, custom).uniform();
+
+        assert_eq!(coloring.num_stops as usize, MAX_STOPS);
+        assert!(coloring.stops.is_sorted_by(|a, b| a.w <= b.w));
+        assert_near(coloring.sample(12.5), Vec3::splat(0.25));
+        assert_near(coloring.sample(20.0), Vec3::ONE);
+    }
+
+    #[test]
+    fn empty_custom_maps_fall_back_to_classic() {
+        let custom = settings(Colormap::Custom, vec![]).uniform();
+        let classic = settings(Colormap::Classic, vec![]).uniform();
+
+        assert_eq!(custom, classic);
+    }
+
+    #[test]
+    fn empty_ranges_take_the_first_stop() {
+        let mut settings = settings(Colormap::Magma, vec![]);
+        settings.max = settings.min;
+        let coloring = settings.uniform();
+
+        assert_near(coloring.sample(50.0), rgb(&Colormap::Magma.stops()[0]));
+    }
+}
@@ -0,0 +1,214 @@
+use std::{fmt, str::FromStr};
+
+use glam::{BVec3, Quat, UVec3, Vec3, vec3};
+use gpu_shared::{DEFAULT_BOX_SIZE, DEFAULT_PARTICLES};
+use rand::{Rng, RngExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bodies::Body,
+    flow::{Emitter, Sink},
+    materials::MaterialSettings,
+    mesh::MeshBoundary,
+    motion::BoxMotion,
+    obstacles::Obstacle,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// The SPIR-V compute kernels
+    #[default]
+    Gpu,
+    /// The rayon reference implementation in [`crate::cpu`]
+    Cpu,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Gpu => f.write_str("gpu"),
+            Backend::Cpu => f.write_str("cpu"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gpu" => Ok(Backend::Gpu),
+            "cpu" => Ok(Backend::Cpu),
+            _ => Err(format!("unknown backend {s:?}, expected gpu or cpu")),
+        }
+    }
+}
+
+/// A region of fluid particles. Coordinates are relative to the center of the
+/// boundary box, before it is rotated. `phase` picks the fluid, see
+/// [`InitialConditions::materials`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Volume {
+    /// A grid of `particles`, centered on `offset`
+    Block {
+        particles: UVec3,
+        #[serde(default)]
+        offset: Vec3,
+        #[serde(default)]
+        phase: u32,
+    },
+    /// Every grid point within `radius` of `center`
+    Sphere {
+        center: Vec3,
+        radius: f32,
+        #[serde(default)]
+        phase: u32,
+    },
+    /// Particles at exactly these positions
+    Particles {
+        positions: Vec<Vec3>,
+        #[serde(default)]
+        phase: u32,
+    },
+}
+
+impl Volume {
+    /// Appends this volume's particles to `out`. `step` is the distance between
+    /// neighbouring grid points.
+    pub(crate) fn fill(&self, step: f32, gap: f32, rng: &mut impl Rng, out: &mut Vec<Vec3>) {
+        match self {
+            Volume::Block {
+                particles, offset, ..
+            } => {
+                let ibox = particles.as_vec3() * step - gap;
+                let topleft = *offset - ibox / 2.;
+
+                for i in 0..particles.x {
+                    for j in 0..particles.y {
+                        for k in 0..particles.z {
+                            let pos = topleft + vec3(i as f32, j as f32, k as f32) * step;
+                            out.push(pos + jitter(rng));
+                        }
+                    }
+                }
+            }
+            Volume::Sphere { center, radius, .. } => {
+                let n = (radius / step).floor() as i32;
+
+                for i in -n..=n {
+                    for j in -n..=n {
+                        for k in -n..=n {
+                            let rel = vec3(i as f32, j as f32, k as f32) * step;
+                            if rel.length() <= *radius {
+                                out.push(*center + rel + jitter(rng));
+                            }
+                        }
+                    }
+                }
+            }
+            Volume::Particles { positions, .. } => out.extend_from_slice(positions),
+        }
+    }
+
+    #[must_use]
+    pub fn phase(&self) -> u32 {
+        match self {
+            Volume::Block { phase, .. }
+            | Volume::Sphere { phase, .. }
+            | Volume::Particles { phase, .. } => *phase,
+        }
+    }
+}
+
+// add a small random offset to grid positions because this engine is
+// deterministic
+fn jitter(rng: &mut impl Rng) -> Vec3 {
+    let random = vec3(
+        rng.random::<f32>() - 0.5,
+        rng.random::<f32>() - 0.5,
+        rng.random::<f32>() - 0.5,
+    );
+
+    random / 25.
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InitialConditions {
+    pub box_size: Vec3,
+    pub box_quat: Quat,
+    /// Axes of the box that wrap around, so fluid leaving through one face
+    /// comes back in through the other. They need to be at least two
+    /// smoothing radii long, and rigid bodies still bounce off every face
+    pub periodic: BVec3,
+    /// Layers of boundary particles on the walls of the box. One is enough
+    /// when the smoothing radius is about twice the spacing, thicker walls
+    /// keep the fluid in at larger radii
+    pub boundary_layers: u32,
+    /// Moves the box while the simulation runs, see [`BoxMotion`]
+    pub motion: Option<BoxMotion>,
+    pub gap: f32,
+    pub volumes: Vec<Volume>,
+    /// Fluids besides the one the settings describe, at most
+    /// [`gpu_shared::materials::MAX_PHASES`] - 1. A volume of phase `n` is
+    /// made of `materials[n - 1]`
+    pub materials: Vec<MaterialSettings>,
+    /// Rigid bodies, at most [`gpu_shared::bodies::MAX_BODIES`]. Fluid
+    /// inside them is left out
+    pub bodies: Vec<Body>,
+    /// Static obstacles, at most [`gpu_shared::obstacles::MAX_OBSTACLES`].
+    /// Fluid inside them is left out too
+    pub obstacles: Vec<Obstacle>,
+    /// Mesh files covered in boundary particles, like the walls of the box
+    pub meshes: Vec<MeshBoundary>,
+    /// Add fluid as the simulation runs, at most
+    /// [`gpu_shared::flow::MAX_EMITTERS`]
+    pub emitters: Vec<Emitter>,
+    /// Remove the fluid that reaches them, at most
+    /// [`gpu_shared::flow::MAX_SINKS`]
+    pub sinks: Vec<Sink>,
+    /// Most particles at once, boundary included, which the emitters stop
+    /// at. [`crate::flow::DEFAULT_HEADROOM`] more than the scene starts with
+    /// if unset
+    pub max_particles: Option<u32>,
+    /// Seeds the jitter added to grid positions. Random on every reset if
+    /// unset.
+    pub seed: Option<u64>,
+}
+
+impl InitialConditions {
+    /// The particle count of the first block, which is what the panel edits.
+    pub fn block_mut(&mut self) -> Option<&mut UVec3> {
+        self.volumes.iter_mut().find_map(|v| match v {
+            Volume::Block { particles, .. } => Some(particles),
+            _ => None,
+        })
+    }
+}
+
+impl Default for InitialConditions {
+    fn default() -> Self {
+        Self {
+            box_size: DEFAULT_BOX_SIZE,
+            box_quat: Quat::IDENTITY,
+            periodic: BVec3::FALSE,
+            boundary_layers: 1,
+            motion: None,
+            gap: 0.05,
+            volumes: vec![Volume::Block {
+                particles: DEFAULT_PARTICLES,
+                offset: Vec3::ZERO,
+                phase: 0,
+            }],
+            materials: Vec::new(),
+            bodies: Vec::new(),
+            obstacles: Vec::new(),
+            meshes: Vec::new(),
+            emitters: Vec::new(),
+            sinks: Vec::new(),
+            max_particles: None,
+            seed: None,
+        }
+    }
+}
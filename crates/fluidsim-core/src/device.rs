@@ -0,0 +1,111 @@
+use wgpu::PowerPreference;
+
+use crate::prelude::*;
+
+#[derive(Debug, Snafu)]
+pub enum DeviceError {
+    #[snafu(display("At {location}: wgpu: failed to request adapter\n{source}"))]
+    RequestAdapter {
+        source: wgpu::RequestAdapterError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display(
+        "At {location}: no adapter matching {name:?}\nAvailable adapters: {available:?}"
+    ))]
+    NoAdapter {
+        name: String,
+        available: Vec<String>,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: wgpu: failed to request device\n{source}"))]
+    RequestDevice {
+        source: wgpu::RequestDeviceError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+#[must_use]
+pub fn instance() -> wgpu::Instance {
+    wgpu::Instance::new(wgpu::InstanceDescriptor {
+        #[cfg(target_os = "linux")]
+        backends: wgpu::Backends::VULKAN,
+        #[cfg(target_os = "windows")]
+        backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
+        #[cfg(target_os = "macos")]
+        backends: wgpu::Backends::METAL,
+        ..wgpu::InstanceDescriptor::new_without_display_handle_from_env()
+    })
+}
+
+/// Finds the first adapter whose name contains `name`, ignoring case.
+async fn find_adapter(
+    instance: &wgpu::Instance,
+    surface: Option<&wgpu::Surface<'_>>,
+    name: &str,
+) -> Result<wgpu::Adapter, DeviceError> {
+    let adapters = instance.enumerate_adapters(wgpu::Backends::all()).await;
+    let available = adapters
+        .iter()
+        .map(|a| a.get_info().name)
+        .collect::<Vec<_>>();
+    let needle = name.to_lowercase();
+
+    adapters
+        .into_iter()
+        .find(|adapter| {
+            let supported = surface.is_none_or(|s| adapter.is_surface_supported(s));
+            supported && adapter.get_info().name.to_lowercase().contains(&needle)
+        })
+        .context(NoAdapterSnafu { name, available })
+}
+
+/// Requests an adapter and device with the features the simulation needs.
+/// Pass a surface when rendering, or `None` to run headless. If `adapter` is
+/// set, the first adapter whose name contains it is used.
+pub async fn request_device(
+    instance: &wgpu::Instance,
+    surface: Option<&wgpu::Surface<'_>>,
+    adapter: Option<&str>,
+) -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue), DeviceError> {
+    let adapter = match adapter {
+        Some(name) => find_adapter(instance, surface, name).await?,
+        None => instance
+            .request_adapter(&wgpu::RequestAdapterOptionsBase {
+                power_preference: PowerPreference::None,
+                force_fallback_adapter: false,
+                compatible_surface: surface,
+            })
+            .await
+            .context(RequestAdapterSnafu)?,
+    };
+
+    info!("Using adapter {}", adapter.get_info().name);
+
+    // particle buffers are sized to the scene, so allow as large as the adapter can
+    let supported = adapter.limits();
+    let limits = wgpu::Limits {
+        max_buffer_size: supported.max_buffer_size,
+        max_storage_buffer_binding_size: supported.max_storage_buffer_binding_size,
+        ..wgpu::Limits::default()
+    };
+
+    let (device, queue) = adapter
+        .request_device(&wgpu::DeviceDescriptor {
+            label: None,
+            required_features: wgpu::Features::TIMESTAMP_QUERY
+                | wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS,
+            required_limits: limits,
+            memory_hints: wgpu::MemoryHints::default(),
+            experimental_features: wgpu::ExperimentalFeatures::disabled(),
+            trace: wgpu::Trace::Off,
+        })
+        .await
+        .context(RequestDeviceSnafu)?;
+
+    Ok((adapter, device, queue))
+}
@@ -0,0 +1,509 @@
+//! Per-frame particle export to VTK, PLY or CSV.
+//!
+//! [`Exporter`] copies the particle buffers into staging buffers inside the
+//! frame's own encoder and maps them asynchronously, so nothing waits on the
+//! gpu. Finished frames are written to disk on a separate thread.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, OnceLock, mpsc},
+    thread::{self, JoinHandle},
+};
+
+use glam::{Vec2, Vec4};
+use gpu_shared::{flow::FlowState, materials};
+
+use crate::{physics::PhysicsShader, prelude::*};
+
+// frames waiting on the gpu before capturing blocks
+const MAX_IN_FLIGHT: usize = 3;
+
+#[derive(Debug, Snafu)]
+pub enum ExportError {
+    #[snafu(display("At {location}: failed to write {path}\n{source}"))]
+    Write {
+        path: String,
+        source: io::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Legacy binary VTK polydata, for Paraview
+    #[default]
+    Vtk,
+    /// Binary PLY, for Blender
+    Ply,
+    Csv,
+}
+
+impl ExportFormat {
+    #[must_use]
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Vtk => "vtk",
+            ExportFormat::Ply => "ply",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vtk" => Ok(ExportFormat::Vtk),
+            "ply" => Ok(ExportFormat::Ply),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!(
+                "unknown export format {s:?}, expected vtk, ply or csv"
+            )),
+        }
+    }
+}
+
+/// One step's worth of particles. The first `boundary` particles are the
+/// boundary shell, the rest are fluid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub step: u64,
+    pub boundary: u32,
+    pub positions: Vec<Vec4>,
+    pub velocities: Vec<Vec4>,
+    pub densities: Vec<Vec2>,
+}
+
+impl Frame {
+    pub fn save(&self, format: ExportFormat, path: impl AsRef<Path>) -> Result<(), ExportError> {
+        let path = path.as_ref();
+        let ctx = || WriteSnafu {
+            path: path.display().to_string(),
+        };
+
+        let mut w = BufWriter::new(File::create(path).with_context(|_| ctx())?);
+        match format {
+            ExportFormat::Vtk => self.write_vtk(&mut w),
+            ExportFormat::Ply => self.write_ply(&mut w),
+            ExportFormat::Csv => self.write_csv(&mut w),
+        }
+        .and_then(|()| w.flush())
+        .with_context(|_| ctx())
+    }
+
+    fn is_boundary(&self, i: usize) -> bool {
+        i < self.boundary as usize
+    }
+
+    fn phase(&self, i: usize) -> u8 {
+        materials::phase(self.velocities[i]) as u8
+    }
+
+    fn write_vtk(&self, w: &mut impl Write) -> io::Result<()> {
+        // legacy binary vtk is big-endian
+        fn floats(w: &mut impl Write, values: impl IntoIterator<Item = f32>) -> io::Result<()> {
+            for v in values {
+                w.write_all(&v.to_be_bytes())?;
+            }
+            writeln!(w)
+        }
+
+        let n = self.positions.len();
+
+        writeln!(w, "# vtk DataFile Version 3.0")?;
+        writeln!(w, "fluidsim step {}", self.step)?;
+        writeln!(w, "BINARY")?;
+        writeln!(w, "DATASET POLYDATA")?;
+
+        writeln!(w, "POINTS {n} float")?;
+        floats(
+            w,
+            self.positions.iter().flat_map(|p| p.truncate().to_array()),
+        )?;
+
+        writeln!(w, "VERTICES {n} {}", n * 2)?;
+        for i in 0..n as u32 {
+            w.write_all(&1u32.to_be_bytes())?;
+            w.write_all(&i.to_be_bytes())?;
+        }
+        writeln!(w)?;
+
+        writeln!(w, "POINT_DATA {n}")?;
+        writeln!(w, "VECTORS velocity float")?;
+        floats(
+            w,
+            self.velocities.iter().flat_map(|v| v.truncate().to_array()),
+        )?;
+
+        writeln!(w, "SCALARS density float 1")?;
+        writeln!(w, "LOOKUP_TABLE default")?;
+        floats(w, self.densities.iter().map(|d| d.x))?;
+
+        writeln!(w, "SCALARS boundary int 1")?;
+        writeln!(w, "LOOKUP_TABLE default")?;
+        for i in 0..n {
+            w.write_all(&i32::from(self.is_boundary(i)).to_be_bytes())?;
+        }
+        writeln!(w)?;
+
+        writeln!(w, "SCALARS phase int 1")?;
+        writeln!(w, "LOOKUP_TABLE default")?;
+        for i in 0..n {
+            w.write_all(&i32::from(self.phase(i)).to_be_bytes())?;
+        }
+        writeln!(w)
+    }
+
+    fn write_ply(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "ply")?;
+        writeln!(w, "format binary_little_endian 1.0")?;
+        writeln!(w, "comment fluidsim step {}", self.step)?;
+        writeln!(w, "element vertex {}", self.positions.len())?;
+        for name in ["x", "y", "z", "vx", "vy", "vz", "density"] {
+            writeln!(w, "property float {name}")?;
+        }
+        writeln!(w, "property uchar boundary")?;
+        writeln!(w, "property uchar phase")?;
+        writeln!(w, "end_header")?;
+
+        for (i, ((p, v), d)) in self
+            .positions
+            .iter()
+            .zip(&self.velocities)
+            .zip(&self.densities)
+            .enumerate()
+        {
+            for f in [p.x, p.y, p.z, v.x, v.y, v.z, d.x] {
+                w.write_all(&f.to_le_bytes())?;
+            }
+            w.write_all(&[u8::from(self.is_boundary(i)), self.phase(i)])?;
+        }
+
+        Ok(())
+    }
+
+    fn write_csv(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "id,x,y,z,vx,vy,vz,density,near_density,boundary,phase")?;
+
+        for (i, ((p, v), d)) in self
+            .positions
+            .iter()
+            .zip(&self.velocities)
+            .zip(&self.densities)
+            .enumerate()
+        {
+            writeln!(
+                w,
+                "{i},{},{},{},{},{},{},{},{},{},{}",
+                p.x,
+                p.y,
+                p.z,
+                v.x,
+                v.y,
+                v.z,
+                d.x,
+                d.y,
+                u8::from(self.is_boundary(i)),
+                self.phase(i),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Pending {
+    step: u64,
+    boundary: u32,
+    // slots copied, the count in use follows them
+    max_particles: usize,
+    staging: wgpu::Buffer,
+    mapped: Arc<OnceLock<Result<(), wgpu::BufferAsyncError>>>,
+}
+
+/// Writes a [`Frame`] to `dir` every `every` steps without stalling the gpu.
+pub struct Exporter {
+    format: ExportFormat,
+    dir: PathBuf,
+    every: u64,
+
+    // copied this frame, waiting for submit
+    copied: Vec<Pending>,
+    // submitted and mapping
+    mapping: Vec<Pending>,
+
+    tx: Option<mpsc::Sender<(Frame, PathBuf)>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Exporter {
+    #[must_use]
+    pub fn new(format: ExportFormat, dir: impl Into<PathBuf>, every: u64) -> Self {
+        let (tx, rx) = mpsc::channel::<(Frame, PathBuf)>();
+
+        let writer = thread::spawn(move || {
+            for (frame, path) in rx {
+                if let Err(e) = frame.save(format, &path) {
+                    error!("{e}");
+                }
+            }
+        });
+
+        Self {
+            format,
+            dir: dir.into(),
+            every: every.max(1),
+            copied: vec![],
+            mapping: vec![],
+            tx: Some(tx),
+            writer: Some(writer),
+        }
+    }
+
+    /// Whether advancing from step `from` to `to` crosses an export.
+    #[must_use]
+    pub fn due(&self, from: u64, to: u64) -> bool {
+        from / self.every != to / self.every
+    }
+
+    /// Records copies of the particle buffers into `encoder`. Call
+    /// [`Exporter::submitted`] once the encoder has been submitted.
+    pub fn capture(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        physics: &PhysicsShader,
+        step: u64,
+    ) {
+        if self.mapping.len() >= MAX_IN_FLIGHT {
+            warn!("export is falling behind, waiting on the gpu");
+            self.flush(device);
+        }
+
+        // every particle there is room for, and the count of those in use
+        let settings = physics.udata.settings();
+        let particles = settings.max_particles as usize;
+        let vec4 = (particles * mem::size_of::<Vec4>()) as u64;
+        let vec2 = (particles * mem::size_of::<Vec2>()) as u64;
+        let count = mem::size_of::<u32>() as u64;
+
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("export/staging"),
+            size: 2 * vec4 + vec2 + count,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let buffers = &physics.buffers().physics;
+        encoder.copy_buffer_to_buffer(&buffers.positions.buffer, 0, &staging, 0, vec4);
+        encoder.copy_buffer_to_buffer(&buffers.velocities.buffer, 0, &staging, vec4, vec4);
+        encoder.copy_buffer_to_buffer(&buffers.densities.buffer, 0, &staging, 2 * vec4, vec2);
+        encoder.copy_buffer_to_buffer(
+            &buffers.flow_state.buffer,
+            mem::offset_of!(FlowState, count) as u64,
+            &staging,
+            2 * vec4 + vec2,
+            count,
+        );
+
+        self.copied.push(Pending {
+            step,
+            boundary: settings.boundary_particles,
+            max_particles: particles,
+            staging,
+            mapped: Arc::new(OnceLock::new()),
+        });
+    }
+
+    /// Starts mapping the frames captured since the last submit.
+    pub fn submitted(&mut self) {
+        for pending in self.copied.drain(..) {
+            let mapped = Arc::clone(&pending.mapped);
+            pending
+                .staging
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |res| _ = mapped.set(res));
+
+            self.mapping.push(pending);
+        }
+    }
+
+    /// Hands every frame that has finished mapping to the writer thread. The
+    /// device must be polled for this to make progress.
+    pub fn collect(&mut self) {
+        let (done, waiting) = mem::take(&mut self.mapping)
+            .into_iter()
+            .partition::<Vec<_>, _>(|p| p.mapped.get().is_some());
+        self.mapping = waiting;
+
+        for pending in done {
+            if let Some(Err(e)) = pending.mapped.get() {
+                error!("Failed to map export buffer: {e}");
+                continue;
+            }
+
+            let frame = {
+                let data = pending.staging.slice(..).get_mapped_range();
+                let max = pending.max_particles;
+                let (vec4, vec2) = (mem::size_of::<Vec4>(), mem::size_of::<Vec2>());
+
+                let counted = &data[max * (2 * vec4 + vec2)..];
+                let n = (bytemuck::pod_read_unaligned::<u32>(counted) as usize).min(max);
+                let velocities = max * vec4;
+                let densities = 2 * max * vec4;
+
+                Frame {
+                    step: pending.step,
+                    boundary: pending.boundary,
+                    positions: bytemuck::pod_collect_to_vec(&data[..n * vec4]),
+                    velocities: bytemuck::pod_collect_to_vec(
+                        &data[velocities..velocities + n * vec4],
+                    ),
+                    densities: bytemuck::pod_collect_to_vec(&data[densities..densities + n * vec2]),
+                }
+            };
+            pending.staging.unmap();
+
+            let path = self.dir.join(format!(
+                "frame_{:06}.{}",
+                frame.step,
+                self.format.extension()
+            ));
+
+            if let Some(tx) = &self.tx {
+                _ = tx.send((frame, path));
+            }
+        }
+    }
+
+    /// Waits for every submitted frame and hands it to the writer thread.
+    pub fn flush(&mut self, device: &wgpu::Device) {
+        if let Err(e) = device.poll(wgpu::PollType::wait_indefinitely()) {
+            error!("Failed to poll device: {e}");
+        }
+
+        self.collect();
+    }
+
+    /// Flushes, then waits for the writer thread to finish.
+    pub fn finish(&mut self, device: &wgpu::Device) {
+        self.flush(device);
+        self.tx = None;
+
+        if let Some(writer) = self.writer.take() {
+            _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec2, vec4};
+
+    use super::*;
+
+    // a boundary particle, then a fluid one of the second phase
+    fn frame() -> Frame {
+        Frame {
+            step: 7,
+            boundary: 1,
+            positions: vec![vec4(1.0, 2.0, 3.0, 0.0), vec4(-0.5, 0.25, 4.0, 0.0)],
+            velocities: vec![Vec4::ZERO, vec4(1.0, -2.0, 0.5, 1.0)],
+            densities: vec![vec2(1000.0, 20.0), vec2(998.5, 30.0)],
+        }
+    }
+
+    fn written(write: impl FnOnce(&Frame, &mut Vec<u8>) -> io::Result<()>) -> Vec<u8> {
+        let mut out = vec![];
+        write(&frame(), &mut out).unwrap();
+        out
+    }
+
+    // the bytes following the first line that starts with `header`
+    fn after<'a>(bytes: &'a [u8], header: &str) -> &'a [u8] {
+        let start = bytes
+            .windows(header.len())
+            .position(|w| w == header.as_bytes())
+            .unwrap();
+        let line = bytes[start..].iter().position(|&b| b == b'\n').unwrap();
+        &bytes[start + line + 1..]
+    }
+
+    fn encoded(values: &[f32], order: fn(f32) -> [u8; 4]) -> Vec<u8> {
+        values.iter().flat_map(|&v| order(v)).collect()
+    }
+
+    #[test]
+    fn csv_has_a_row_per_particle() {
+        let text = String::from_utf8(written(Frame::write_csv)).unwrap();
+
+        assert_eq!(
+            text,
+            "id,x,y,z,vx,vy,vz,density,near_density,boundary,phase\n\
+             0,1,2,3,0,0,0,1000,20,1,0\n\
+             1,-0.5,0.25,4,1,-2,0.5,998.5,30,0,1\n"
+        );
+    }
+
+    #[test]
+    fn ply_vertices_are_little_endian() {
+        let bytes = written(Frame::write_ply);
+
+        let header = String::from_utf8_lossy(&bytes);
+        assert!(header.starts_with("ply\nformat binary_little_endian 1.0\n"));
+        assert!(header.contains("comment fluidsim step 7\nelement vertex 2\n"));
+
+        // seven floats and two bytes a vertex
+        let body = after(&bytes, "end_header");
+        assert_eq!(body.len(), 2 * 30);
+        let second = &body[30..];
+        let floats = [-0.5, 0.25, 4.0, 1.0, -2.0, 0.5, 998.5];
+        assert_eq!(second[..28], encoded(&floats, f32::to_le_bytes));
+        assert_eq!(&second[28..], [0, 1]);
+        assert_eq!(&body[28..30], [1, 0]);
+    }
+
+    #[test]
+    fn vtk_sections_are_big_endian() {
+        let bytes = written(Frame::write_vtk);
+
+        let header = String::from_utf8_lossy(&bytes);
+        assert!(header.starts_with("# vtk DataFile Version 3.0\nfluidsim step 7\nBINARY\n"));
+
+        let be = |values| encoded(values, f32::to_be_bytes);
+        let points = after(&bytes, "POINTS 2 float");
+        assert!(points.starts_with(&be(&[1.0, 2.0, 3.0, -0.5, 0.25, 4.0])));
+        let velocities = after(&bytes, "VECTORS velocity");
+        assert!(velocities.starts_with(&be(&[0.0, 0.0, 0.0, 1.0, -2.0, 0.5])));
+        let densities = after(after(&bytes, "SCALARS density"), "LOOKUP_TABLE");
+        assert!(densities.starts_with(&be(&[1000.0, 998.5])));
+
+        // one vertex cell per particle, each a count and an index
+        let cells = after(&bytes, "VERTICES 2 4");
+        assert_eq!(
+            &cells[..16],
+            [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1]
+        );
+
+        let ints = |name| {
+            let table = after(after(&bytes, name), "LOOKUP_TABLE");
+            [&table[..4], &table[4..8]].map(|b| i32::from_be_bytes(b.try_into().unwrap()))
+        };
+        assert_eq!(ints("SCALARS boundary"), [1, 0]);
+        assert_eq!(ints("SCALARS phase"), [0, 1]);
+        assert!(bytes.ends_with(b"\n"));
+    }
+}
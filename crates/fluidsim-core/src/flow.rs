@@ -0,0 +1,193 @@
+//! Emitters that add fluid to a scene while it runs and sinks that drain it,
+//! and the table the kernels read them from.
+
+use glam::{Quat, Vec3};
+use gpu_shared::{
+    flow::{self, Flow, FlowState, MAX_EMITTERS, MAX_SINKS},
+    obstacles,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::obstacles::{Shape, analytic};
+
+/// Room left for the emitters when the scene doesn't set
+/// [`crate::InitialConditions::max_particles`]
+pub const DEFAULT_HEADROOM: u32 = 1 << 16;
+
+/// Adds fluid of `phase` from `start` seconds after a reset. Coordinates are
+/// relative to the center of the boundary box, before it is rotated, like a
+/// [`crate::Volume`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Emitter {
+    /// Shoots `rate` particles a second out of a disc of `radius` facing
+    /// `direction`, at `speed`. About `speed * π * radius² / spacing³` keeps
+    /// the jet at the rest spacing of the volumes
+    Nozzle {
+        center: Vec3,
+        direction: Vec3,
+        radius: f32,
+        rate: f32,
+        speed: f32,
+        #[serde(default)]
+        phase: u32,
+        #[serde(default)]
+        start: f32,
+    },
+    /// Fills a box of `size` with a grid of particles moving at `velocity`,
+    /// every `interval` seconds or just once when zero
+    Volume {
+        center: Vec3,
+        size: Vec3,
+        #[serde(default)]
+        velocity: Vec3,
+        #[serde(default)]
+        interval: f32,
+        #[serde(default)]
+        phase: u32,
+        #[serde(default)]
+        start: f32,
+    },
+}
+
+/// A region that removes the fluid inside it, placed like a
+/// [`crate::Obstacle`]. Particles pass through it until they are gone, and
+/// meshes aren't supported.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sink {
+    pub shape: Shape,
+    pub center: Vec3,
+    #[serde(default)]
+    pub rotation: Quat,
+}
+
+/// The emitters and sinks as the kernels see them, and the cpu backend's
+/// copy of what the kernels keep in their [`FlowState`].
+#[derive(Default)]
+pub(crate) struct FlowTable {
+    pub uniform: Flow,
+    pub state: FlowState,
+}
+
+impl FlowTable {
+    /// Lays out `emitters` and `sinks` in a box of `box_size`, adding
+    /// particles `spacing` apart. Phases past `last_phase` fall back to 0.
+    pub fn reset(
+        &mut self,
+        emitters: &[Emitter],
+        sinks: &[Sink],
+        box_size: Vec3,
+        spacing: f32,
+        last_phase: usize,
+    ) {
+        if emitters.len() > MAX_EMITTERS {
+            warn!(
+                "{} emitters, only the first {MAX_EMITTERS} are simulated",
+                emitters.len()
+            );
+        }
+        if sinks.len() > MAX_SINKS {
+            warn!(
+                "{} sinks, only the first {MAX_SINKS} are simulated",
+                sinks.len()
+            );
+        }
+
+        let mut uniform = Flow {
+            spacing,
+            ..Flow::default()
+        };
+
+        for emitter in emitters.iter().take(MAX_EMITTERS) {
+            let mut gpu = match *emitter {
+                Emitter::Nozzle {
+                    center,
+                    direction,
+                    radius,
+                    rate,
+                    speed,
+                    phase,
+                    start,
+                } => {
+                    let direction = direction.try_normalize().unwrap_or(Vec3::Y);
+                    flow::Emitter {
+                        position: center,
+                        kind: flow::NOZZLE,
+                        direction,
+                        radius: radius.max(0.0),
+                        rate: rate.max(0.0),
+                        velocity: direction * speed,
+                        phase,
+                        start,
+                        ..flow::Emitter::default()
+                    }
+                }
+                Emitter::Volume {
+                    center,
+                    size,
+                    velocity,
+                    interval,
+                    phase,
+                    start,
+                } => flow::Emitter {
+                    position: center,
+                    kind: flow::VOLUME,
+                    half: size.max(Vec3::ZERO) / 2.0,
+                    velocity,
+                    phase,
+                    start,
+                    interval: interval.max(0.0),
+                    ..flow::Emitter::default()
+                },
+            };
+
+            if gpu.phase as usize > last_phase {
+                warn!("no material for phase {}, using phase 0", gpu.phase);
+                gpu.phase = 0;
+            }
+
+            gpu.position += box_size / 2.0;
+            uniform.emitters[uniform.num_emitters as usize] = gpu;
+            uniform.num_emitters += 1;
+        }
+
+        for sink in sinks.iter().take(MAX_SINKS) {
+            let Some((shape, size)) = analytic(&sink.shape) else {
+                warn!("mesh sinks aren't supported, leaving it out");
+                continue;
+            };
+
+            uniform.sinks[uniform.num_sinks as usize] = obstacles::Obstacle {
+                position: box_size / 2.0 + sink.center,
+                shape,
+                rotation: sink.rotation,
+                size,
+                grid: 0,
+            };
+            uniform.num_sinks += 1;
+        }
+
+        self.uniform = uniform;
+    }
+
+    /// Whether the particle count changes as the simulation runs.
+    pub fn active(&self) -> bool {
+        self.uniform.num_emitters > 0 || self.uniform.num_sinks > 0
+    }
+
+    /// The most particles the emitters can add over a step of `dtime`.
+    pub fn max_added(&self, dtime: f32) -> u32 {
+        let uniform = &self.uniform;
+        uniform.emitters[..uniform.num_emitters as usize]
+            .iter()
+            .map(|e| {
+                if e.kind == flow::NOZZLE {
+                    (e.rate * dtime).ceil() as u32 + 1
+                } else {
+                    let lattice = e.lattice(uniform.spacing);
+                    lattice.x * lattice.y * lattice.z
+                }
+            })
+            .sum()
+    }
+}
@@ -0,0 +1,109 @@
+//! How many iterations the DFSPH solves took, read back from the gpu a frame or
+//! two late.
+
+use std::sync::{Arc, OnceLock};
+
+use gpu_shared::solver::SolverState;
+
+type Mapped = Arc<OnceLock<Result<(), wgpu::BufferAsyncError>>>;
+
+/// Iterations each DFSPH solve ran in one step.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Iterations {
+    pub density: u32,
+    pub divergence: u32,
+}
+
+impl From<SolverState> for Iterations {
+    fn from(state: SolverState) -> Self {
+        Self {
+            density: state.density.iterations,
+            divergence: state.divergence.iterations,
+        }
+    }
+}
+
+pub struct IterationReadback {
+    readback: wgpu::Buffer,
+
+    // set when the state was copied this frame, waiting for submit
+    copied: bool,
+    // set while `readback` is being mapped
+    mapping: Option<Mapped>,
+
+    latest: Option<Iterations>,
+}
+
+impl IterationReadback {
+    #[must_use]
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            readback: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("iterations/readback"),
+                size: size_of::<SolverState>() as u64,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            copied: false,
+            mapping: None,
+            latest: None,
+        }
+    }
+
+    /// Copies the solver state, just written by a step. Skipped while the
+    /// previous readback is still mapping.
+    pub(crate) fn copy(&mut self, encoder: &mut wgpu::CommandEncoder, state: &wgpu::Buffer) {
+        if self.mapping.is_some() {
+            return;
+        }
+
+        encoder.copy_buffer_to_buffer(state, 0, &self.readback, 0, self.readback.size());
+        self.copied = true;
+    }
+
+    /// Starts mapping the state copied since the last submit.
+    pub fn submitted(&mut self) {
+        if !std::mem::take(&mut self.copied) {
+            return;
+        }
+
+        let mapped = Mapped::default();
+        let inner = Arc::clone(&mapped);
+        self.readback
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |res| _ = inner.set(res));
+
+        self.mapping = Some(mapped);
+    }
+
+    /// Records the iterations once they have been mapped. The device must be
+    /// polled for this to make progress.
+    pub fn collect(&mut self) {
+        let Some(mapped) = &self.mapping else {
+            return;
+        };
+        let Some(res) = mapped.get() else {
+            return;
+        };
+
+        if let Err(e) = res {
+            error!("Failed to map solver state: {e}");
+        } else {
+            let state = {
+                let data = self.readback.slice(..).get_mapped_range();
+                bytemuck::pod_read_unaligned::<SolverState>(&data)
+            };
+            self.readback.unmap();
+
+            self.latest = Some(state.into());
+        }
+
+        self.mapping = None;
+    }
+
+    /// The most recent step that has been read back.
+    #[must_use]
+    pub fn latest(&self) -> Option<Iterations> {
+        self.latest
+    }
+}
@@ -0,0 +1,321 @@
+//! Headless SPH fluid simulation.
+//!
+//! Owns the compute pipelines, the particle buffers and the CPU reference
+//! backend, so the simulation can be stepped and inspected without a window.
+
+#![warn(clippy::pedantic)]
+#![allow(
+    clippy::similar_names,
+    clippy::missing_errors_doc,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::result_large_err,
+    clippy::wildcard_imports,
+    clippy::large_enum_variant,
+    clippy::many_single_char_names
+)]
+
+#[macro_use]
+extern crate tracing;
+
+pub mod bodies;
+mod boundary;
+pub mod buffers;
+pub mod checkpoint;
+pub mod colors;
+pub mod config;
+pub mod cpu;
+pub mod device;
+pub mod export;
+pub mod flow;
+pub mod iterations;
+pub mod materials;
+pub mod mesh;
+pub mod motion;
+pub mod obstacles;
+pub mod physics;
+pub mod pipelines;
+mod prelude;
+pub mod profiler;
+pub mod scene;
+
+use std::sync::OnceLock;
+
+pub use bodies::Body;
+pub use config::{Backend, InitialConditions, Volume};
+pub use flow::{Emitter, Sink};
+use glam::{Vec2, Vec4};
+use gpu_shared::{Globals, bodies::RigidBody};
+pub use motion::BoxMotion;
+pub use obstacles::Obstacle;
+pub use scene::Scene;
+use wgpu::include_spirv;
+
+use crate::{
+    buffers::{BufferBinding, ReadError},
+    checkpoint::{Checkpoint, CheckpointError},
+    device::DeviceError,
+    export::Frame,
+    mesh::MeshError,
+    physics::PhysicsShader,
+    prelude::*,
+};
+
+pub fn shader_module(device: &wgpu::Device) -> &wgpu::ShaderModule {
+    const SHADER: wgpu::ShaderModuleDescriptor<'static> = include_spirv!(env!("physics.spv"));
+    static MODULE: OnceLock<wgpu::ShaderModule> = OnceLock::new();
+
+    MODULE.get_or_init(|| device.create_shader_module(SHADER))
+}
+
+#[derive(Debug, Snafu)]
+pub enum SimulationError {
+    #[snafu(display("At {location}: failed to create device\n{source}"))]
+    Device {
+        source: DeviceError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: failed to load a mesh boundary\n{source}"))]
+    Mesh {
+        source: MeshError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: failed to read back particles\n{source}"))]
+    Read {
+        source: ReadError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: wgpu: failed to poll device\n{source}"))]
+    Poll {
+        source: wgpu::PollError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: failed to restore checkpoint\n{source}"))]
+    Restore {
+        source: CheckpointError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// A simulation that can be driven without a window or a renderer.
+pub struct Simulation {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    physics: PhysicsShader,
+    init: InitialConditions,
+    backend: Backend,
+}
+
+impl Simulation {
+    /// Creates a headless device and lays out the initial particles.
+    pub async fn new(
+        init: InitialConditions,
+        settings: SimSettings,
+        backend: Backend,
+    ) -> Result<Self, SimulationError> {
+        let instance = device::instance();
+        let (_, device, queue) = device::request_device(&instance, None, None)
+            .await
+            .context(DeviceSnafu)?;
+
+        Self::with_device(&device, &queue, init, settings, backend)
+    }
+
+    /// Like [`Simulation::new`], with the initial conditions and settings of
+    /// a scene.
+    pub async fn from_scene(scene: Scene, backend: Backend) -> Result<Self, SimulationError> {
+        Self::new(scene.init, scene.settings, backend).await
+    }
+
+    /// Like [`Simulation::new`], but on an existing device.
+    pub fn with_device(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        init: InitialConditions,
+        settings: SimSettings,
+        backend: Backend,
+    ) -> Result<Self, SimulationError> {
+        let mut physics = PhysicsShader::new(device, queue);
+        *physics.lease_panel() = settings;
+        physics
+            .reset(device, queue, &init, backend)
+            .context(MeshSnafu)?;
+
+        Ok(Self {
+            device: device.clone(),
+            queue: queue.clone(),
+            physics,
+            init,
+            backend,
+        })
+    }
+
+    /// Restores the initial conditions, keeping the current settings.
+    pub fn reset(&mut self) -> Result<(), SimulationError> {
+        self.physics
+            .reset(&self.device, &self.queue, &self.init, self.backend)
+            .context(MeshSnafu)
+    }
+
+    /// Advances the simulation by `dtime` seconds, then picks up any timings
+    /// and solver iterations that have come back from earlier steps.
+    pub fn step(&mut self, dtime: f32) -> Result<(), SimulationError> {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("simulation/step_encoder"),
+            });
+
+        self.physics.update(
+            &self.device,
+            &self.queue,
+            &mut encoder,
+            dtime,
+            &Globals::default(),
+        );
+        if let Some(profiler) = self.physics.profiler_mut() {
+            profiler.resolve(&mut encoder);
+        }
+        self.queue.submit([encoder.finish()]);
+
+        if let Some(profiler) = self.physics.profiler_mut() {
+            profiler.submitted();
+        }
+        self.physics.iterations_mut().submitted();
+
+        self.device.poll(wgpu::PollType::Poll).context(PollSnafu)?;
+
+        if let Some(profiler) = self.physics.profiler_mut() {
+            profiler.collect();
+        }
+        self.physics.iterations_mut().collect();
+
+        Ok(())
+    }
+
+    /// Snapshots the particles and settings. Blocks on the gpu.
+    pub fn checkpoint(&self) -> Result<Checkpoint, SimulationError> {
+        self.physics
+            .checkpoint(&self.device, &self.queue)
+            .context(ReadSnafu)
+    }
+
+    /// Reads back the particles for export, labelled with `step`.
+    pub fn frame(&self, step: u64) -> Result<Frame, SimulationError> {
+        let boundary = self.settings().boundary_particles;
+
+        if let Some(cpu) = self.physics.cpu() {
+            return Ok(Frame {
+                step,
+                boundary,
+                positions: cpu.positions.clone(),
+                velocities: cpu.velocities.clone(),
+                densities: cpu.densities.clone(),
+            });
+        }
+
+        // one count for all three, so they agree on it
+        let len = self.count()? as usize;
+        let physics = &self.physics.buffers().physics;
+        Ok(Frame {
+            step,
+            boundary,
+            positions: self.read(&physics.positions, len)?,
+            velocities: self.read(&physics.velocities, len)?,
+            densities: self.read(&physics.densities, len)?,
+        })
+    }
+
+    /// Replaces the particles and settings with a snapshot.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) -> Result<(), SimulationError> {
+        self.physics
+            .restore(&self.device, &self.queue, checkpoint, self.backend)
+            .context(RestoreSnafu)
+    }
+
+    #[must_use]
+    pub fn settings(&self) -> &SimSettings {
+        self.physics.udata.settings()
+    }
+
+    pub fn settings_mut(&mut self) -> &mut SimSettings {
+        self.physics.lease_panel()
+    }
+
+    #[must_use]
+    pub fn physics(&self) -> &PhysicsShader {
+        &self.physics
+    }
+
+    /// Particles in use, boundary included. Blocks on the gpu while emitters
+    /// or sinks are changing it.
+    pub fn count(&self) -> Result<u32, SimulationError> {
+        self.physics
+            .count(&self.device, &self.queue)
+            .context(ReadSnafu)
+    }
+
+    /// Particle positions, boundary particles first. `w` is padding.
+    pub fn positions(&self) -> Result<Vec<Vec4>, SimulationError> {
+        if let Some(cpu) = self.physics.cpu() {
+            return Ok(cpu.positions.clone());
+        }
+
+        let len = self.count()? as usize;
+        self.read(&self.physics.buffers().physics.positions, len)
+    }
+
+    /// Particle velocities, boundary particles first. `w` is the phase.
+    pub fn velocities(&self) -> Result<Vec<Vec4>, SimulationError> {
+        if let Some(cpu) = self.physics.cpu() {
+            return Ok(cpu.velocities.clone());
+        }
+
+        let len = self.count()? as usize;
+        self.read(&self.physics.buffers().physics.velocities, len)
+    }
+
+    /// The rigid bodies, in the order the scene lists them.
+    pub fn bodies(&self) -> Result<Vec<RigidBody>, SimulationError> {
+        let count = self.settings().num_bodies as usize;
+        let bodies = self
+            .physics
+            .bodies(&self.device, &self.queue)
+            .context(ReadSnafu)?;
+
+        Ok(bodies.bodies[..count].to_vec())
+    }
+
+    /// Particle densities as `[density, near_density]`, `[density, lambda]`
+    /// under PBF or `[density, alpha]` under DFSPH.
+    pub fn densities(&self) -> Result<Vec<Vec2>, SimulationError> {
+        if let Some(cpu) = self.physics.cpu() {
+            return Ok(cpu.densities.clone());
+        }
+
+        let len = self.count()? as usize;
+        self.read(&self.physics.buffers().physics.densities, len)
+    }
+
+    // the first `len` particles of a per-particle buffer
+    fn read<A: ?Sized, K: Pod>(
+        &self,
+        binding: &BufferBinding<A>,
+        len: usize,
+    ) -> Result<Vec<K>, SimulationError> {
+        binding
+            .read(&self.device, &self.queue, len)
+            .context(ReadSnafu)
+    }
+}
@@ -0,0 +1,74 @@
+//! The fluids a simulation is made of. Phase 0 is the fluid the settings
+//! describe, and [`InitialConditions::materials`] adds the rest. The kernels
+//! read them all as a [`Materials`] uniform.
+
+use glam::Vec3;
+use gpu_shared::materials::{MAX_PHASES, Material, Materials};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// Color of phase 0 when coloring by phase.
+pub const BASE_COLOR: [f32; 3] = [0.16, 0.42, 0.86];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialSettings {
+    /// Rest density. Particles of every phase sit at the same spacing, so
+    /// their mass scales with it
+    pub target_density: f32,
+    pub viscosity_strength: f32,
+    pub surface_tension: f32,
+    /// Red, green and blue, each from 0 to 1
+    pub color: [f32; 3],
+}
+
+impl Default for MaterialSettings {
+    fn default() -> Self {
+        Self {
+            target_density: 20.0,
+            viscosity_strength: 0.12,
+            surface_tension: 0.0,
+            color: [0.93, 0.66, 0.18],
+        }
+    }
+}
+
+impl MaterialSettings {
+    /// The fluid `settings` describe, phase 0.
+    #[must_use]
+    pub fn base(settings: &SimSettings) -> Self {
+        Self {
+            target_density: settings.target_density,
+            viscosity_strength: settings.viscosity_strength,
+            surface_tension: settings.surface_tension,
+            color: BASE_COLOR,
+        }
+    }
+
+    fn gpu(&self, settings: &SimSettings) -> Material {
+        Material {
+            color: Vec3::from_array(self.color).extend(1.0),
+            mass: settings.mass * self.target_density / settings.target_density,
+            target_density: self.target_density,
+            viscosity_strength: self.viscosity_strength,
+            surface_tension: self.surface_tension,
+        }
+    }
+}
+
+/// The material table, with the settings' fluid as phase 0 followed by
+/// `materials`. Phases past the end repeat phase 0.
+#[must_use]
+pub fn uniform(settings: &SimSettings, materials: &[MaterialSettings]) -> Materials {
+    let base = MaterialSettings::base(settings).gpu(settings);
+    let mut uniform = Materials {
+        phases: [base; MAX_PHASES],
+    };
+
+    for (out, material) in uniform.phases[1..].iter_mut().zip(materials) {
+        *out = material.gpu(settings);
+    }
+
+    uniform
+}
@@ -0,0 +1,507 @@
+//! Triangle meshes loaded from OBJ and STL files, sampled with boundary
+//! particles so the fluid can be poured into or around real geometry.
+
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use glam::{IVec3, Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+#[derive(Debug, Snafu)]
+pub enum MeshError {
+    #[snafu(display("At {location}: failed to read {path}\n{source}"))]
+    Io {
+        path: String,
+        source: io::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: unknown mesh format for {path}, expected .obj or .stl"))]
+    UnknownFormat {
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: {path}:{line}: {message}"))]
+    Parse {
+        path: String,
+        line: usize,
+        message: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// Which side of a [`MeshBoundary`] the fluid belongs on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MeshKind {
+    /// Fluid may start inside, like a tank or a pipe. The mesh may be open
+    #[default]
+    Container,
+    /// Fluid inside is left out, so the mesh should be closed
+    Obstacle,
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+/// A mesh file whose surface is covered in boundary particles. Coordinates
+/// are relative to the center of the boundary box, before it is rotated,
+/// like a [`crate::Body`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MeshBoundary {
+    /// An `.obj` or `.stl` file, relative to the working directory
+    pub path: PathBuf,
+    #[serde(default)]
+    pub kind: MeshKind,
+    #[serde(default)]
+    pub center: Vec3,
+    #[serde(default)]
+    pub rotation: Quat,
+    /// Applied before the rotation
+    #[serde(default = "one")]
+    pub scale: f32,
+}
+
+impl MeshBoundary {
+    /// Loads the file and moves it into place, relative to the center of the
+    /// box.
+    pub fn load(&self) -> Result<TriangleMesh, MeshError> {
+        let mut mesh = TriangleMesh::load(&self.path)?;
+        for v in &mut mesh.vertices {
+            *v = self.center + self.rotation * (*v * self.scale);
+        }
+
+        Ok(mesh)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TriangleMesh {
+    pub vertices: Vec<Vec3>,
+    pub faces: Vec<[u32; 3]>,
+}
+
+impl TriangleMesh {
+    /// Reads an OBJ or STL file, picked by the extension. STL may be ASCII or
+    /// binary.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MeshError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let bytes = fs::read(path).context(IoSnafu { path: &name })?;
+
+        let extension = path.extension().and_then(|e| e.to_str());
+        let mesh = match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("obj") => Self::parse_obj(&String::from_utf8_lossy(&bytes), &name)?,
+            Some("stl") => Self::parse_stl(&bytes, &name)?,
+            _ => return UnknownFormatSnafu { path: name }.fail(),
+        };
+
+        info!(
+            "Loaded {} triangles from {}",
+            mesh.faces.len(),
+            path.display()
+        );
+        Ok(mesh)
+    }
+
+    /// Vertices and faces of a Wavefront OBJ file, with polygons split into
+    /// fans. Everything else is ignored.
+    fn parse_obj(text: &str, path: &str) -> Result<Self, MeshError> {
+        let mut mesh = Self::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let fail = |message: &str| {
+                ParseSnafu {
+                    path,
+                    line: i + 1,
+                    message,
+                }
+                .fail()
+            };
+
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("v") => {
+                    let coords = words
+                        .take(3)
+                        .map(str::parse::<f32>)
+                        .collect::<Result<Vec<_>, _>>();
+                    match coords.as_deref() {
+                        Ok(&[x, y, z]) => mesh.vertices.push(Vec3::new(x, y, z)),
+                        _ => return fail("expected three coordinates"),
+                    }
+                }
+                Some("f") => {
+                    let count = mesh.vertices.len() as i64;
+                    let mut corners = vec![];
+
+                    // `v`, `v/vt`, `v//vn` or `v/vt/vn`, counted from 1 or
+                    // back from the end when negative
+                    for word in words {
+                        let index = word.split('/').next().and_then(|v| v.parse::<i64>().ok());
+                        let index = match index {
+                            Some(v) if v > 0 && v <= count => v - 1,
+                            Some(v) if v < 0 && -v <= count => count + v,
+                            _ => return fail("bad vertex index"),
+                        };
+                        corners.push(index as u32);
+                    }
+
+                    if corners.len() < 3 {
+                        return fail("face with fewer than three vertices");
+                    }
+                    for k in 1..corners.len() - 1 {
+                        mesh.faces.push([corners[0], corners[k], corners[k + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(mesh)
+    }
+
+    /// Triangles of a binary or ASCII STL file. Binary files are recognized
+    /// by their size, since some start with `solid` too.
+    fn parse_stl(bytes: &[u8], path: &str) -> Result<Self, MeshError> {
+        let mut mesh = Self::default();
+
+        let count = bytes
+            .get(80..84)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+        if let Some(count) = count
+            && bytes.len() == 84 + count * 50
+        {
+            for triangle in bytes[84..].chunks_exact(50) {
+                // skip the normal, then three vertices
+                let float = |at: usize| {
+                    let b = &triangle[at..at + 4];
+                    f32::from_le_bytes([b[0], b[1], b[2], b[3]])
+                };
+                let start = mesh.vertices.len() as u32;
+                for v in 0..3 {
+                    let at = 12 + v * 12;
+                    mesh.vertices
+                        .push(Vec3::new(float(at), float(at + 4), float(at + 8)));
+                }
+                mesh.faces.push([start, start + 1, start + 2]);
+            }
+
+            return Ok(mesh);
+        }
+
+        let text = String::from_utf8_lossy(bytes);
+        for (i, line) in text.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("vertex") {
+                continue;
+            }
+
+            let coords = words
+                .take(3)
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<_>, _>>();
+            let Ok(&[x, y, z]) = coords.as_deref() else {
+                return ParseSnafu {
+                    path,
+                    line: i + 1,
+                    message: "expected three coordinates",
+                }
+                .fail();
+            };
+            mesh.vertices.push(Vec3::new(x, y, z));
+
+            let n = mesh.vertices.len() as u32;
+            if n.is_multiple_of(3) {
+                mesh.faces.push([n - 3, n - 2, n - 1]);
+            }
+        }
+
+        Ok(mesh)
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = [Vec3; 3]> + '_ {
+        self.faces
+            .iter()
+            .map(|face| face.map(|i| self.vertices[i as usize]))
+    }
+
+    /// Covers the surface with points about `spacing` apart, and at least
+    /// half of it apart from each other, into `grid`.
+    fn sample(&self, grid: &mut PointGrid) {
+        let spacing = grid.spacing;
+
+        for [a, b, c] in self.triangles() {
+            let longest = (b - a).length().max((c - b).length()).max((a - c).length());
+            let n = (longest / spacing).ceil().max(1.0) as u32;
+
+            for i in 0..=n {
+                for j in 0..=n - i {
+                    let p = a + (b - a) * (i as f32 / n as f32) + (c - a) * (j as f32 / n as f32);
+
+                    // shared edges and thin triangles sample the same spots
+                    if !grid.near(p, spacing / 2.0) {
+                        grid.insert(p);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether `p` is inside, by the generalized winding number. Only
+    /// meaningful for closed meshes.
+    #[must_use]
+    pub fn contains(&self, p: Vec3) -> bool {
+        let winding = self.triangles().map(|t| solid_angle(p, t)).sum::<f32>();
+        (winding / (4.0 * PI)).abs() > 0.5
+    }
+
+    fn bounds(&self) -> (Vec3, Vec3) {
+        self.vertices.iter().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(lo, hi), &v| (lo.min(v), hi.max(v)),
+        )
+    }
+}
+
+/// Points bucketed by cells `spacing` wide, to find the ones nearby.
+struct PointGrid {
+    spacing: f32,
+    cells: HashMap<IVec3, Vec<Vec3>>,
+    points: Vec<Vec3>,
+}
+
+impl PointGrid {
+    fn cell(&self, p: Vec3) -> IVec3 {
+        (p / self.spacing).floor().as_ivec3()
+    }
+
+    fn insert(&mut self, p: Vec3) {
+        self.cells.entry(self.cell(p)).or_default().push(p);
+        self.points.push(p);
+    }
+
+    fn near(&self, p: Vec3, dist: f32) -> bool {
+        let reach = (dist / self.spacing).ceil() as i32;
+        let home = self.cell(p);
+
+        (-reach..=reach).any(|x| {
+            (-reach..=reach).any(|y| {
+                (-reach..=reach).any(|z| {
+                    self.cells
+                        .get(&(home + IVec3::new(x, y, z)))
+                        .is_some_and(|near| near.iter().any(|q| q.distance(p) < dist))
+                })
+            })
+        })
+    }
+}
+
+/// The boundary particles of every mesh in a scene, and the meshes fluid
+/// can't start inside.
+pub(crate) struct MeshSurfaces {
+    grid: PointGrid,
+    solids: Vec<(Vec3, Vec3, TriangleMesh)>,
+}
+
+impl MeshSurfaces {
+    /// Loads and samples `meshes` with particles `spacing` apart.
+    pub fn sample(meshes: &[MeshBoundary], spacing: f32) -> Result<Self, MeshError> {
+        let mut surfaces = Self {
+            grid: PointGrid {
+                spacing,
+                cells: HashMap::new(),
+                points: Vec::new(),
+            },
+            solids: Vec::new(),
+        };
+
+        for boundary in meshes {
+            let mesh = boundary.load()?;
+            mesh.sample(&mut surfaces.grid);
+
+            if boundary.kind == MeshKind::Obstacle {
+                let (lo, hi) = mesh.bounds();
+                surfaces.solids.push((lo, hi, mesh));
+            }
+        }
+
+        Ok(surfaces)
+    }
+
+    /// Boundary particles, relative to the center of the box.
+    pub fn points(&self) -> &[Vec3] {
+        &self.grid.points
+    }
+
+    /// Whether `p` is within `margin` of a mesh, or inside an obstacle.
+    pub fn contains(&self, p: Vec3, margin: f32) -> bool {
+        self.grid.near(p, margin)
+            || self
+                .solids
+                .iter()
+                .any(|(lo, hi, mesh)| p.cmpge(*lo).all() && p.cmple(*hi).all() && mesh.contains(p))
+    }
+}
+
+/// Closest point to `p` on the triangle `[a, b, c]`.
+pub(crate) fn closest(p: Vec3, [a, b, c]: [Vec3; 3]) -> Vec3 {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+/// Solid angle the triangle `[a, b, c]` covers seen from `p`, signed by its
+/// winding.
+pub(crate) fn solid_angle(p: Vec3, [a, b, c]: [Vec3; 3]) -> f32 {
+    let (a, b, c) = (a - p, b - p, c - p);
+    let (la, lb, lc) = (a.length(), b.length(), c.length());
+
+    let det = a.dot(b.cross(c));
+    let div = la * lb * lc + a.dot(b) * lc + a.dot(c) * lb + b.dot(c) * la;
+
+    2.0 * det.atan2(div)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn obj_faces_take_every_index_form() {
+        let text = "\
+# a unit square, then a triangle on its corner
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vn 0 0 1
+f 1 2/1 3//1 4/1/1
+f -4 -3 -1
+";
+        let mesh = TriangleMesh::parse_obj(text, "square.obj").unwrap();
+
+        assert_eq!(mesh.vertices.len(), 4);
+        // the quad splits into a fan from its first corner
+        assert_eq!(mesh.faces, [[0, 1, 2], [0, 2, 3], [0, 1, 3]]);
+    }
+
+    #[test]
+    fn obj_rejects_bad_faces() {
+        let two = "v 0 0 0\nv 1 0 0\nf 1 2\n";
+        let past = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n";
+        let zero = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n";
+
+        for text in [two, past, zero] {
+            let err = TriangleMesh::parse_obj(text, "bad.obj").unwrap_err();
+            let last = text.lines().count();
+            assert!(
+                matches!(err, MeshError::Parse { line, .. } if line == last),
+                "{err}"
+            );
+        }
+    }
+
+    fn binary_stl(header: &[u8], triangles: &[[Vec3; 3]]) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        bytes.resize(80, b' ');
+        bytes.extend((triangles.len() as u32).to_le_bytes());
+
+        for triangle in triangles {
+            bytes.extend([0u8; 12]);
+            for v in triangle {
+                for c in v.to_array() {
+                    bytes.extend(c.to_le_bytes());
+                }
+            }
+            bytes.extend([0u8; 2]);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn binary_stl_starting_with_solid_is_still_binary() {
+        let triangle = [Vec3::ZERO, Vec3::X, Vec3::Y];
+        let bytes = binary_stl(
+            b"solid exported",
+            &[triangle, triangle.map(|v| v + Vec3::Z)],
+        );
+
+        let mesh = TriangleMesh::parse_stl(&bytes, "binary.stl").unwrap();
+        assert_eq!(mesh.faces, [[0, 1, 2], [3, 4, 5]]);
+        assert_eq!(mesh.vertices[4], Vec3::new(1.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn ascii_stl() {
+        let text = b"\
+solid tri
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 0 1 0
+    endloop
+  endfacet
+endsolid tri
+";
+        let mesh = TriangleMesh::parse_stl(text, "ascii.stl").unwrap();
+        assert_eq!(mesh.vertices, [Vec3::ZERO, Vec3::X, Vec3::Y]);
+        assert_eq!(mesh.faces, [[0, 1, 2]]);
+
+        let bad = b"solid tri\nvertex 0 0\nendsolid tri\n";
+        let err = TriangleMesh::parse_stl(bad, "bad.stl").unwrap_err();
+        assert!(matches!(err, MeshError::Parse { line: 2, .. }), "{err}");
+    }
+}
@@ -0,0 +1,193 @@
+//! Moving the boundary box while the fluid runs, on a script or by hand.
+
+use std::f32::consts::TAU;
+
+use glam::{Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// How the box moves after a reset. Offsets are of its center and rotations
+/// are about it, on top of [`crate::InitialConditions::box_quat`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BoxMotion {
+    /// Shakes the box `frequency` times a second, up to `amplitude` away and
+    /// `angle` radians about `axis`, like a tank on a shaker table
+    Slosh {
+        #[serde(default)]
+        amplitude: Vec3,
+        #[serde(default = "forward")]
+        axis: Vec3,
+        #[serde(default)]
+        angle: f32,
+        frequency: f32,
+    },
+    /// Eases between poses in order of time, holding the last one or
+    /// starting over when `looped`
+    Keyframes {
+        frames: Vec<Keyframe>,
+        #[serde(default)]
+        looped: bool,
+    },
+}
+
+fn forward() -> Vec3 {
+    Vec3::Z
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// Seconds after the reset
+    pub time: f32,
+    #[serde(default)]
+    pub offset: Vec3,
+    #[serde(default)]
+    pub rotation: Quat,
+}
+
+impl BoxMotion {
+    /// The offset and rotation `t` seconds after a reset.
+    #[must_use]
+    pub fn pose(&self, t: f32) -> (Vec3, Quat) {
+        match self {
+            BoxMotion::Slosh {
+                amplitude,
+                axis,
+                angle,
+                frequency,
+            } => {
+                let wave = (TAU * frequency * t).sin();
+                let axis = axis.try_normalize().unwrap_or(Vec3::Z);
+                (*amplitude * wave, Quat::from_axis_angle(axis, angle * wave))
+            }
+            BoxMotion::Keyframes { frames, looped } => {
+                let (Some(first), Some(last)) = (frames.first(), frames.last()) else {
+                    return (Vec3::ZERO, Quat::IDENTITY);
+                };
+
+                let t = if *looped && last.time > 0.0 {
+                    t.rem_euclid(last.time)
+                } else {
+                    t
+                };
+
+                let next = frames.iter().position(|f| f.time > t);
+                let (a, b) = match next {
+                    None => (last, last),
+                    Some(0) => (first, first),
+                    Some(i) => (&frames[i - 1], &frames[i]),
+                };
+
+                let span = b.time - a.time;
+                let s = if span > 0.0 { (t - a.time) / span } else { 0.0 };
+                (
+                    a.offset.lerp(b.offset, s),
+                    a.rotation.normalize().slerp(b.rotation.normalize(), s),
+                )
+            }
+        }
+    }
+}
+
+/// Puts the box where its motion and the mouse say, and works out how fast
+/// its walls move for the kernels.
+#[derive(Default)]
+pub(crate) struct BoxMover {
+    motion: Option<BoxMotion>,
+    rest: Quat,
+    // the center of the box in its own frame
+    half: Vec3,
+    // what the mouse added to the offset
+    drag: Vec3,
+    // whether the walls were moving over the last step
+    moving: bool,
+}
+
+impl BoxMover {
+    /// Starts `motion` over and puts the box where it is at the start.
+    pub fn reset(&mut self, motion: Option<BoxMotion>, settings: &mut SimSettings) {
+        *self = Self {
+            motion,
+            rest: settings.box_quat,
+            half: settings.box_size / 2.0,
+            ..Self::default()
+        };
+
+        let (position, rotation) = self.pose(0.0);
+        settings.box_position = position;
+        settings.box_quat = rotation;
+        settings.box_time = 0.0;
+        settings.box_velocity = Vec3::ZERO;
+        settings.box_spin = Vec3::ZERO;
+    }
+
+    /// Picks up where a checkpoint left the box, keeping the motion.
+    pub fn restore(&mut self, settings: &SimSettings) {
+        let t = settings.box_time;
+        let (_, turn) = self.scripted(t);
+        self.rest = (turn.conjugate() * settings.box_quat).normalize();
+        self.half = settings.box_size / 2.0;
+        self.drag = Vec3::ZERO;
+
+        let (scripted, _) = self.pose(t);
+        self.drag = settings.box_position - scripted;
+        self.moving = settings.box_velocity != Vec3::ZERO || settings.box_spin != Vec3::ZERO;
+    }
+
+    /// Moves the box by `offset` on top of its motion.
+    pub fn drag(&mut self, offset: Vec3) {
+        self.drag += offset;
+    }
+
+    /// Moves the box on by `dtime` and sets its wall velocity. Whether the
+    /// boundary particles need to follow, which they also do once after the
+    /// box stops so their velocity goes back to zero.
+    pub fn advance(&mut self, settings: &mut SimSettings, dtime: f32) -> bool {
+        let time = settings.box_time + dtime;
+        let (position, rotation) = self.pose(time);
+        settings.box_time = time;
+
+        let moved = position != settings.box_position || rotation != settings.box_quat;
+        if !moved && !self.moving {
+            return false;
+        }
+
+        let (velocity, spin) = if moved && dtime > 0.0 {
+            let mut turn = rotation * settings.box_quat.conjugate();
+            if turn.w < 0.0 {
+                turn = -turn;
+            }
+            let (axis, angle) = turn.to_axis_angle();
+            (
+                (position - settings.box_position) / dtime,
+                axis * angle / dtime,
+            )
+        } else {
+            (Vec3::ZERO, Vec3::ZERO)
+        };
+
+        settings.box_position = position;
+        settings.box_quat = rotation;
+        settings.box_velocity = velocity;
+        settings.box_spin = spin;
+        self.moving = moved;
+        true
+    }
+
+    // the motion's offset and rotation, `t` seconds in
+    fn scripted(&self, t: f32) -> (Vec3, Quat) {
+        self.motion
+            .as_ref()
+            .map_or((Vec3::ZERO, Quat::IDENTITY), |motion| motion.pose(t))
+    }
+
+    // where the corner of the box is and how it's turned, `t` seconds in
+    fn pose(&self, t: f32) -> (Vec3, Quat) {
+        let (offset, turn) = self.scripted(t);
+
+        let rotation = (turn * self.rest).normalize();
+        let center = self.rest * self.half + offset + self.drag;
+        (center - rotation * self.half, rotation)
+    }
+}
@@ -0,0 +1,258 @@
+//! Static obstacles in a scene, and the table the kernels collide particles
+//! against. Meshes are baked into signed distance grids.
+
+use std::f32::consts::PI;
+
+use glam::{Quat, Vec3};
+use gpu_shared::obstacles::{
+    self, MAX_OBSTACLES, MAX_SDF_GRIDS, Obstacles, SDF_RESOLUTION, SdfGrid, SdfGrids,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::mesh::{closest, solid_angle};
+
+/// The outline of an obstacle, around its own origin.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Shape {
+    Sphere {
+        radius: f32,
+    },
+    /// Along `y`, with `length` between the centers of the caps
+    Capsule {
+        radius: f32,
+        length: f32,
+    },
+    Box {
+        size: Vec3,
+    },
+    /// Along `y`
+    Cylinder {
+        radius: f32,
+        height: f32,
+    },
+    /// A closed triangle mesh, baked into a grid of
+    /// [`gpu_shared::obstacles::SDF_RESOLUTION`] samples a side
+    Mesh {
+        vertices: Vec<Vec3>,
+        faces: Vec<[u32; 3]>,
+    },
+}
+
+/// An obstacle that particles bounce off. Coordinates are relative to the
+/// center of the boundary box, before it is rotated, like a [`crate::Body`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Obstacle {
+    pub shape: Shape,
+    pub center: Vec3,
+    #[serde(default)]
+    pub rotation: Quat,
+}
+
+// a mesh and where its grid went
+struct Baked {
+    shape: Shape,
+    middle: Vec3,
+    half: Vec3,
+}
+
+/// The obstacles as the kernels see them. Keeps the meshes it has baked, so
+/// only new ones are baked again.
+pub(crate) struct ObstacleTable {
+    pub uniform: Obstacles,
+    pub grids: Box<SdfGrids>,
+    baked: Vec<Baked>,
+    /// Set when `grids` has changed since it was last uploaded
+    pub dirty: bool,
+}
+
+impl Default for ObstacleTable {
+    fn default() -> Self {
+        Self {
+            uniform: Obstacles::default(),
+            grids: bytemuck::zeroed_box(),
+            baked: Vec::new(),
+            dirty: true,
+        }
+    }
+}
+
+impl ObstacleTable {
+    /// Like [`ObstacleTable::update`], but says so when there are too many.
+    pub fn reset(&mut self, obstacles: &[Obstacle], box_size: Vec3) {
+        if obstacles.len() > MAX_OBSTACLES {
+            warn!(
+                "{} obstacles, only the first {MAX_OBSTACLES} are simulated",
+                obstacles.len()
+            );
+        }
+
+        self.update(obstacles, box_size);
+    }
+
+    /// Lays out `obstacles` in a box of `box_size`, baking any mesh that
+    /// isn't already. Obstacles past [`MAX_OBSTACLES`] are left out.
+    pub fn update(&mut self, obstacles: &[Obstacle], box_size: Vec3) {
+        let meshes = obstacles
+            .iter()
+            .take(MAX_OBSTACLES)
+            .map(|o| &o.shape)
+            .filter(|shape| matches!(shape, Shape::Mesh { .. }))
+            .collect::<Vec<_>>();
+
+        let baked = self.baked.iter().map(|b| &b.shape);
+        if meshes.len() != self.baked.len() || meshes.iter().copied().ne(baked) {
+            self.rebake(&meshes);
+        }
+
+        let mut uniform = Obstacles::default();
+        let mut grids = 0;
+
+        for obstacle in obstacles.iter().take(MAX_OBSTACLES) {
+            let mut gpu = obstacles::Obstacle {
+                position: box_size / 2.0 + obstacle.center,
+                rotation: obstacle.rotation,
+                ..obstacles::Obstacle::default()
+            };
+
+            if let Some((shape, size)) = analytic(&obstacle.shape) {
+                gpu.shape = shape;
+                gpu.size = size;
+            } else {
+                let grid = grids;
+                grids += 1;
+
+                // past the last grid, or nothing to bake
+                let Some(baked) = self
+                    .baked
+                    .get(grid)
+                    .filter(|b| grid < MAX_SDF_GRIDS && b.half.cmpgt(Vec3::ZERO).all())
+                else {
+                    continue;
+                };
+
+                gpu.shape = obstacles::MESH;
+                gpu.position += obstacle.rotation * baked.middle;
+                gpu.size = baked.half;
+                gpu.grid = grid as u32;
+            }
+
+            uniform.obstacles[uniform.count as usize] = gpu;
+            uniform.count += 1;
+        }
+
+        self.uniform = uniform;
+    }
+
+    fn rebake(&mut self, meshes: &[&Shape]) {
+        if meshes.len() > MAX_SDF_GRIDS {
+            warn!(
+                "{} mesh obstacles, only the first {MAX_SDF_GRIDS} are simulated",
+                meshes.len()
+            );
+        }
+
+        let old = std::mem::take(&mut self.baked);
+
+        for (i, &shape) in meshes.iter().enumerate() {
+            // keep the grid if it already holds this mesh
+            if let Some(baked) = old.get(i).filter(|b| b.shape == *shape) {
+                self.baked.push(Baked {
+                    shape: shape.clone(),
+                    middle: baked.middle,
+                    half: baked.half,
+                });
+                continue;
+            }
+
+            let (middle, half) = match shape {
+                Shape::Mesh { vertices, faces } if i < MAX_SDF_GRIDS => {
+                    bake(vertices, faces, &mut self.grids.grids[i])
+                }
+                _ => (Vec3::ZERO, Vec3::ZERO),
+            };
+
+            self.baked.push(Baked {
+                shape: shape.clone(),
+                middle,
+                half,
+            });
+            self.dirty = true;
+        }
+    }
+
+    /// Whether `p`, relative to the center of a box of `box_size`, is within
+    /// `margin` of an obstacle.
+    pub fn contains(&self, p: Vec3, box_size: Vec3, margin: f32) -> bool {
+        let p = box_size / 2.0 + p;
+        self.uniform.obstacles[..self.uniform.count as usize]
+            .iter()
+            .any(|o| o.distance(&self.grids, p) < margin)
+    }
+}
+
+/// The kernels' code and size for `shape`, unless it is a mesh.
+pub(crate) fn analytic(shape: &Shape) -> Option<(u32, Vec3)> {
+    match *shape {
+        Shape::Sphere { radius } => Some((obstacles::SPHERE, Vec3::splat(radius))),
+        Shape::Capsule { radius, length } => {
+            Some((obstacles::CAPSULE, Vec3::new(radius, length / 2.0, radius)))
+        }
+        Shape::Box { size } => Some((obstacles::BOX, size / 2.0)),
+        Shape::Cylinder { radius, height } => {
+            Some((obstacles::CYLINDER, Vec3::new(radius, height / 2.0, radius)))
+        }
+        Shape::Mesh { .. } => None,
+    }
+}
+
+/// Bakes the signed distance to a mesh into `grid`, and returns the middle
+/// of the grid and how far it spans either side. The sign comes from the
+/// winding number, so the mesh may be wound either way.
+fn bake(vertices: &[Vec3], faces: &[[u32; 3]], grid: &mut SdfGrid) -> (Vec3, Vec3) {
+    let (lo, hi) = vertices.iter().fold(
+        (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+        |(lo, hi), &v| (lo.min(v), hi.max(v)),
+    );
+    if faces.is_empty() || lo.cmpgt(hi).any() {
+        warn!("empty obstacle mesh");
+        return (Vec3::ZERO, Vec3::ZERO);
+    }
+
+    // room around the mesh for the particles' radius
+    let middle = (lo + hi) / 2.0;
+    let half = (hi - lo) / 2.0 + (hi - lo).max_element() * 0.1;
+
+    let triangles = faces
+        .iter()
+        .map(|face| face.map(|i| vertices[i as usize] - middle))
+        .collect::<Vec<_>>();
+
+    let n = SDF_RESOLUTION;
+    grid.values
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(z, slice)| {
+            for y in 0..n {
+                for x in 0..n {
+                    let p = SdfGrid::point([x, y, z], half);
+                    let dist = triangles
+                        .iter()
+                        .map(|&t| (closest(p, t) - p).length())
+                        .fold(f32::INFINITY, f32::min);
+                    let winding = triangles.iter().map(|&t| solid_angle(p, t)).sum::<f32>();
+                    let inside = (winding / (4.0 * PI)).abs() > 0.5;
+
+                    slice[y * n + x] = if inside { -dist } else { dist };
+                }
+            }
+        });
+
+    debug!(
+        "baked a {}-triangle obstacle into a {n}^3 grid",
+        triangles.len()
+    );
+
+    (middle, half)
+}
@@ -0,0 +1,989 @@
+use std::mem;
+
+use glam::{BVec3, Vec2, Vec3, Vec4, vec3};
+use gpu_shared::{
+    Globals,
+    bodies::Bodies,
+    colors::Coloring,
+    flow::{COMPACT_BLOCK, FlowState},
+    materials::{MAX_PHASES, Materials},
+    solver,
+    timestep::TimeStep,
+};
+use rand::{RngExt, SeedableRng, rngs::StdRng};
+use wgpu_sort::Sorter;
+
+use crate::{
+    bodies,
+    boundary::BoundaryVolumes,
+    buffers::{Buffers, ReadError},
+    checkpoint::{Checkpoint, CheckpointError},
+    colors::ColorSettings,
+    cpu::CpuSolver,
+    flow::{DEFAULT_HEADROOM, FlowTable},
+    iterations::{IterationReadback, Iterations},
+    materials::{self, MaterialSettings},
+    mesh::{MeshError, MeshSurfaces},
+    motion::BoxMover,
+    obstacles::{Obstacle, ObstacleTable},
+    pipelines::{Kernel, Pipelines},
+    prelude::*,
+    profiler::Profiler,
+};
+
+#[derive(Default)]
+pub struct PhysicsUniformData {
+    settings: SimSettings,
+    mouse: MouseState,
+    colors: Coloring,
+    // every phase but 0, which follows the settings
+    materials: Vec<MaterialSettings>,
+    obstacles: ObstacleTable,
+    flow: FlowTable,
+    mover: BoxMover,
+    boundary: BoundaryVolumes,
+}
+
+impl PhysicsUniformData {
+    #[must_use]
+    pub fn settings(&self) -> &SimSettings {
+        &self.settings
+    }
+
+    /// Particles in use as of the last reset, or the last step on the cpu
+    /// backend. Emitters and sinks change it on the gpu, see
+    /// [`PhysicsShader::count`].
+    #[must_use]
+    pub fn num_particles(&self) -> u32 {
+        self.settings.num_particles
+    }
+
+    /// Particles there is room for, which the kernels are dispatched for.
+    #[must_use]
+    pub fn max_particles(&self) -> u32 {
+        self.settings.max_particles
+    }
+
+    #[must_use]
+    pub fn boundary_particles(&self) -> u32 {
+        self.settings.boundary_particles
+    }
+}
+
+pub struct PhysicsShader {
+    // uniform data
+    pub udata: PhysicsUniformData,
+
+    // all storage and uniform buffers, sized for `capacity` particles
+    buffers: Buffers,
+    capacity: u32,
+
+    // state for updating the scene
+    pub pipelines: Pipelines,
+    pass_desc: wgpu::ComputePassDescriptor<'static>,
+
+    // set when the simulation runs on the cpu backend instead
+    cpu: Option<CpuSolver>,
+
+    // set while profiling the gpu kernels
+    profiler: Option<Profiler>,
+
+    // dfsph iteration counts on their way back from the gpu
+    iterations: IterationReadback,
+
+    // one slot of settings per step, copied into the uniform in the encoder
+    settings_ring: wgpu::Buffer,
+    ring_slot: u64,
+}
+
+/// Steps that can share a submit before their settings slots get reused.
+const SETTINGS_RING: u64 = 64;
+
+/// Adaptive substeps one update may take before it lets the rest of the
+/// requested time go.
+const MAX_SUBSTEPS: u32 = 256;
+
+impl PhysicsShader {
+    /// Creates the pipelines with room for a single particle. The buffers grow
+    /// to fit on [`PhysicsShader::reset`] and [`PhysicsShader::restore`].
+    #[must_use]
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let shader = crate::shader_module(device);
+        let sorter = Sorter::new(device, 1);
+
+        let usr = PhysicsUniformData {
+            colors: ColorSettings::default().uniform(),
+            ..PhysicsUniformData::default()
+        };
+        let buffers = Buffers::new(device, &sorter, 1);
+        let pipelines = Pipelines::new(device, &buffers, shader, sorter);
+
+        let pass_descriptor = wgpu::ComputePassDescriptor {
+            label: Some("physics/compute_pass"),
+            timestamp_writes: None,
+        };
+
+        let this = Self {
+            udata: usr,
+            buffers,
+            capacity: 1,
+            pipelines,
+            pass_desc: pass_descriptor,
+            cpu: None,
+            profiler: None,
+            iterations: IterationReadback::new(device),
+            settings_ring: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("physics/settings_ring"),
+                size: SETTINGS_RING * size_of::<SimSettings>() as u64,
+                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            ring_slot: 0,
+        };
+
+        // initialize some nonzero buffers
+        this.clear_hash(queue);
+        this
+    }
+
+    /// Regrows the buffers, sorter and bind groups to fit `particles`, or
+    /// shrinks them when they are more than twice as large as needed. The
+    /// particle buffers lose their contents.
+    fn reserve(&mut self, device: &wgpu::Device, particles: u32) {
+        let particles = particles.max(1);
+        if particles <= self.capacity && particles > self.capacity / 2 {
+            return;
+        }
+
+        debug!(
+            "resizing particle buffers from {} to {particles}",
+            self.capacity
+        );
+
+        let shader = crate::shader_module(device);
+        let sorter = Sorter::new(device, particles);
+        let mut buffers = Buffers::new(device, &sorter, particles);
+
+        // the renderer shares the uniform buffers, so keep them
+        mem::swap(&mut buffers.uniform, &mut self.buffers.uniform);
+
+        self.pipelines = Pipelines::new(device, &buffers, shader, sorter);
+        self.buffers = buffers;
+        self.capacity = particles;
+        self.udata.obstacles.dirty = true;
+    }
+
+    /// Fills the spatial hash and sort buffers with `u32::MAX`.
+    fn clear_hash(&self, queue: &wgpu::Queue) {
+        let max = vec![u32::MAX; self.capacity as usize];
+        self.buffers.spatial_hash.indices.write(queue, &max);
+        self.buffers.sort.lookup.write(queue, &max);
+        self.buffers.sort.keys.write(queue, &max);
+    }
+
+    /// How many particles fit in the buffers before they have to regrow.
+    #[must_use]
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Lays out the boundary shell, meshes and bodies and the fluid volumes
+    /// described by `init` and uploads them, discarding the previous state.
+    /// Leaves the state alone if a mesh fails to load.
+    #[allow(clippy::too_many_lines)]
+    pub fn reset(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        init: &InitialConditions,
+        backend: Backend,
+    ) -> Result<(), MeshError> {
+        let settings = &self.udata.settings;
+        let sprest = (settings.mass / settings.target_density).cbrt();
+        let surfaces = MeshSurfaces::sample(&init.meshes, sprest)?;
+
+        let settings = &mut self.udata.settings;
+        settings.box_size = init.box_size;
+        settings.box_quat = init.box_quat;
+        settings.periodic = init.periodic.bitmask();
+        self.udata.mover.reset(init.motion.clone(), settings);
+
+        let short = init
+            .box_size
+            .cmplt(Vec3::splat(2.0 * settings.smoothing_radius));
+        if (init.periodic & short).any() {
+            warn!("periodic axes shorter than two smoothing radii don't wrap neighbors");
+        }
+
+        let size = settings.particle_radius * 2.0;
+        let box_size = settings.box_size;
+        let half = box_size / 2.;
+
+        let mut walls = shell(
+            box_size,
+            init.periodic,
+            init.boundary_layers,
+            settings.particle_radius,
+            sprest,
+        );
+
+        // then the meshes and the bodies, which count as boundary too
+        walls.extend(surfaces.points().iter().map(|&p| (half + p).extend(0.)));
+
+        // the walls are laid out in the frame of the box, which moves them
+        let mut positions = walls
+            .iter()
+            .map(|w| settings.from_box(w.truncate()).extend(0.).to_array())
+            .collect::<Vec<_>>();
+
+        let sampled = bodies::sample(&init.bodies, sprest, settings);
+        positions.extend(sampled.positions.iter().map(Vec4::to_array));
+        settings.num_bodies = sampled.count;
+        settings.body_particles = sampled.anchors.len() as u32;
+        settings.boundary_particles = positions.len() as u32;
+
+        self.udata.boundary = BoundaryVolumes::new(
+            walls.iter().map(|w| w.truncate()).collect(),
+            sampled.anchors.clone(),
+            box_size,
+            init.periodic,
+        );
+
+        let mut rng = match init.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
+
+        self.udata.obstacles.reset(&init.obstacles, box_size);
+
+        let (fluid, phases) = fill(init, &self.udata.obstacles, &surfaces, size, &mut rng);
+        self.udata.materials.clone_from(&init.materials);
+
+        let last_phase = init.materials.len().min(MAX_PHASES - 1);
+        let flow = &mut self.udata.flow;
+        flow.reset(
+            &init.emitters,
+            &init.sinks,
+            box_size,
+            size + init.gap,
+            last_phase,
+        );
+
+        // the kernels' jitter follows the seed too, so seeded runs repeat
+        settings.jitter_seed = rng.random::<u32>() >> 16;
+
+        for pos in fluid {
+            let pos = settings.from_box(half + pos);
+
+            positions.push([pos.x, pos.y, pos.z, 0.]); // padding for alignment
+        }
+
+        let n = positions.len();
+        settings.num_particles = n as u32;
+        settings.max_particles = if init.emitters.is_empty() {
+            n as u32
+        } else {
+            let max = init.max_particles.unwrap_or(n as u32 + DEFAULT_HEADROOM);
+            max.max(n as u32)
+        };
+        flow.state = FlowState {
+            count: n as u32,
+            ..FlowState::default()
+        };
+        let settings = *settings;
+
+        // boundary particles are phase 0, the phase of the rest rides in w
+        let mut velocities = vec![Vec4::ZERO; settings.boundary_particles as usize];
+        velocities.extend(phases.into_iter().map(|p| Vec4::W * p as f32));
+
+        // body particles keep their body in w of the prediction, like the anchor
+        let shell = walls.len();
+        let mut anchors = walls.iter().map(Vec4::to_array).collect::<Vec<_>>();
+        anchors.extend(sampled.anchors.iter().map(Vec4::to_array));
+        let mut predictions = positions.clone();
+        predictions[shell..anchors.len()].copy_from_slice(&anchors[shell..]);
+
+        self.reserve(device, settings.max_particles);
+
+        let physics = &self.buffers.physics;
+        self.buffers.uniform.settings.reset(queue, &[settings]);
+        physics.flow_state.reset(queue, &[self.udata.flow.state]);
+        physics.positions.write(queue, &positions);
+        physics.predictions.write(queue, &predictions);
+        physics.velocities.write(queue, &velocities);
+        physics.anchors.write(queue, &anchors);
+        physics.bodies.reset(queue, &[sampled.bodies]);
+        physics.densities.write(queue, &vec![[0f32; 2]; n]);
+        physics.deltas.write(queue, &vec![[0f32; 4]; n]);
+        physics.stiffness.write(queue, &vec![0f32; n]);
+        physics.timestep.reset(queue, &[TimeStep::default()]);
+        self.clear_hash(queue);
+
+        self.cpu = match backend {
+            Backend::Gpu => None,
+            Backend::Cpu => {
+                let positions = positions
+                    .into_iter()
+                    .map(Vec4::from_array)
+                    .collect::<Vec<_>>();
+
+                let mut cpu = CpuSolver::new(&positions);
+                cpu.predictions = predictions.into_iter().map(Vec4::from_array).collect();
+                cpu.velocities = velocities;
+                cpu.set_bodies(
+                    sampled.bodies,
+                    anchors.into_iter().map(Vec4::from_array).collect(),
+                );
+                Some(cpu)
+            }
+        };
+
+        self.update_volumes(queue);
+
+        debug!(
+            "reset simulation with {} particles ({} boundary, {} bodies)",
+            settings.num_particles, settings.boundary_particles, settings.num_bodies
+        );
+        Ok(())
+    }
+
+    /// Advances the simulation by `dtime`. Under adaptive time steps that
+    /// takes as many substeps as the particles need, each picked on the gpu
+    /// and read back before it runs, so the box and the flow keep up with the
+    /// fluid. Resolve the profiler's timings before submitting `encoder`.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        dtime: f32,
+        globals: &Globals,
+    ) {
+        if self.udata.settings.adaptive_timestep == 0 || dtime <= 0.0 {
+            self.substep(queue, encoder, dtime, globals);
+            return;
+        }
+
+        let mut left = dtime;
+        for _ in 0..MAX_SUBSTEPS {
+            let step = self.choose_step(device, queue, encoder, left);
+            self.substep(queue, encoder, step, globals);
+
+            // a sliver left over from rounding isn't worth a step
+            left -= step;
+            if left <= dtime * 1e-4 {
+                return;
+            }
+        }
+        warn!("adaptive time steps fell {left}s behind after {MAX_SUBSTEPS} substeps");
+    }
+
+    /// Moves the box and runs the flow and the solver over one step of
+    /// `dtime`.
+    fn substep(
+        &mut self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        dtime: f32,
+        globals: &Globals,
+    ) {
+        self.udata.settings.dtime = dtime;
+        let moved = self.udata.mover.advance(&mut self.udata.settings, dtime);
+
+        self.update_volumes(queue);
+
+        let flow = &mut self.udata.flow;
+        flow.uniform.max_added = flow.max_added(dtime);
+
+        // before the settings go up, so they hold the new count
+        if let Some(cpu) = &mut self.cpu
+            && flow.active()
+        {
+            cpu.flow(&self.udata.settings, &flow.uniform, &mut flow.state);
+            self.udata.settings.num_particles = flow.state.count;
+        }
+
+        let materials = self.upload_uniforms(queue, encoder);
+
+        if let Some(cpu) = &mut self.cpu {
+            if moved {
+                cpu.move_box(&self.udata.settings);
+            }
+            cpu.step(
+                &self.udata.settings,
+                &materials,
+                &self.udata.obstacles.uniform,
+                &self.udata.obstacles.grids,
+                &self.udata.mouse,
+                globals,
+            );
+            return;
+        }
+
+        if moved {
+            self.pipelines.dispatch_all(
+                encoder,
+                queue,
+                &self.pass_desc,
+                self.udata.settings.max_particles,
+                &[Kernel::MoveBox],
+                self.profiler.as_mut().map(|p| p.begin(&[Kernel::MoveBox])),
+            );
+        }
+        if self.udata.flow.active() {
+            self.flow(queue, encoder);
+        }
+
+        let kernels = Pipelines::schedule(&self.udata.settings, &materials);
+        let dfsph = self.udata.settings.solver == solver::DFSPH;
+
+        // zeroed in the encoder, so every step of a frame starts over
+        if dfsph {
+            encoder.clear_buffer(&self.buffers.physics.state.buffer, 0, None);
+        }
+
+        self.pipelines.dispatch_all(
+            encoder,
+            queue,
+            &self.pass_desc,
+            self.udata.settings.max_particles,
+            &kernels,
+            self.profiler.as_mut().map(|p| p.begin(&kernels)),
+        );
+
+        if dfsph {
+            self.iterations
+                .copy(encoder, &self.buffers.physics.state.buffer);
+        }
+    }
+
+    /// Uploads the settings and the rest of the uniforms for the next
+    /// dispatches, and returns the materials uniform.
+    fn upload_uniforms(
+        &mut self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Materials {
+        if self.cpu.is_some() {
+            self.buffers
+                .uniform
+                .settings
+                .reset(queue, &[self.udata.settings]);
+        } else {
+            self.stage_settings(queue, encoder);
+        }
+        self.buffers.uniform.mouse.reset(queue, &[self.udata.mouse]);
+        self.buffers
+            .uniform
+            .colors
+            .reset(queue, &[self.udata.colors]);
+
+        let materials = materials::uniform(&self.udata.settings, &self.udata.materials);
+        self.buffers.uniform.materials.reset(queue, &[materials]);
+
+        let obstacles = &mut self.udata.obstacles;
+        self.buffers
+            .uniform
+            .obstacles
+            .reset(queue, &[obstacles.uniform]);
+        if mem::take(&mut obstacles.dirty) {
+            let grids = &*obstacles.grids;
+            self.buffers
+                .physics
+                .sdfs
+                .write(queue, std::slice::from_ref(grids));
+        }
+        self.buffers
+            .uniform
+            .flow
+            .reset(queue, &[self.udata.flow.uniform]);
+
+        materials
+    }
+
+    /// Works the volumes of the boundary particles out again when the
+    /// smoothing radius or kernel changed, and uploads them.
+    fn update_volumes(&mut self, queue: &wgpu::Queue) {
+        let settings = &self.udata.settings;
+        let (kernel, radius) = (settings.kernel, settings.smoothing_radius);
+        let Some(volumes) = self.udata.boundary.update(kernel, radius) else {
+            return;
+        };
+
+        let densities = volumes.iter().map(|&v| [v, 0.0]).collect::<Vec<_>>();
+        self.buffers.physics.densities.write(queue, &densities);
+
+        if let Some(cpu) = &mut self.cpu {
+            for (density, volume) in cpu.densities.iter_mut().zip(volumes) {
+                density.x = volume;
+            }
+        }
+    }
+
+    /// Uploads the settings into the next slot of the ring and copies them
+    /// into the uniform in the encoder. Every `write_buffer` lands before the
+    /// submit, so writing the uniform directly would leave each step of a
+    /// frame with the last step's settings.
+    fn stage_settings(&mut self, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder) {
+        let size = size_of::<SimSettings>() as u64;
+        let offset = self.ring_slot * size;
+        self.ring_slot = (self.ring_slot + 1) % SETTINGS_RING;
+
+        queue.write_buffer(
+            &self.settings_ring,
+            offset,
+            bytemuck::bytes_of(&self.udata.settings),
+        );
+        encoder.copy_buffer_to_buffer(
+            &self.settings_ring,
+            offset,
+            &self.buffers.uniform.settings.buffer,
+            0,
+            size,
+        );
+    }
+
+    /// Removes the fluid in the sinks and adds what the emitters owe, then
+    /// copies the new count over `num_particles` in the settings uniform so
+    /// the step sees it.
+    fn flow(&mut self, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder) {
+        const KERNELS: [Kernel; 6] = [
+            Kernel::Drain,
+            Kernel::CountHoles,
+            Kernel::ScanHoles,
+            Kernel::FindHoles,
+            Kernel::FillHoles,
+            Kernel::PlanFlow,
+        ];
+        let settings = &self.udata.settings;
+
+        // the counts of each block of slots start over every step
+        let blocks = settings.max_particles.div_ceil(COMPACT_BLOCK);
+        let size = u64::from(blocks) * size_of::<[u32; 2]>() as u64;
+        encoder.clear_buffer(&self.buffers.physics.blocks.buffer, 0, Some(size));
+
+        self.pipelines.dispatch_all(
+            encoder,
+            queue,
+            &self.pass_desc,
+            settings.max_particles,
+            &KERNELS,
+            self.profiler.as_mut().map(|p| p.begin(&KERNELS)),
+        );
+        // one thread for each particle the emitters could add
+        self.pipelines.dispatch_all(
+            encoder,
+            queue,
+            &self.pass_desc,
+            self.udata.flow.uniform.max_added,
+            &[Kernel::Emit],
+            self.profiler.as_mut().map(|p| p.begin(&[Kernel::Emit])),
+        );
+
+        self.copy_count(encoder);
+    }
+
+    /// Copies the particle count the flow left over `num_particles` in the
+    /// settings uniform.
+    fn copy_count(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.copy_buffer_to_buffer(
+            &self.buffers.physics.flow_state.buffer,
+            mem::offset_of!(FlowState, count) as u64,
+            &self.buffers.uniform.settings.buffer,
+            mem::offset_of!(SimSettings, num_particles) as u64,
+            size_of::<u32>() as u64,
+        );
+    }
+
+    /// Picks the length of the next substep, at most `left`. On the gpu that
+    /// submits what the encoder holds so far and waits for the answer.
+    fn choose_step(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        left: f32,
+    ) -> f32 {
+        self.udata.settings.dtime = left;
+
+        if let Some(cpu) = &mut self.cpu {
+            let materials = materials::uniform(&self.udata.settings, &self.udata.materials);
+            return cpu.choose_step(&self.udata.settings, &materials);
+        }
+
+        self.upload_uniforms(queue, encoder);
+        if self.udata.flow.active() {
+            self.copy_count(encoder);
+        }
+
+        let physics = &self.buffers.physics;
+        let max_particles = self.udata.settings.max_particles;
+
+        // `requested` leads the struct, the rest belongs to the gpu
+        physics.timestep.write(queue, &[left]);
+
+        self.pipelines.dispatch_all(
+            encoder,
+            queue,
+            &self.pass_desc,
+            max_particles,
+            &[Kernel::MeasureStep, Kernel::ChooseStep],
+            self.profiler
+                .as_mut()
+                .map(|p| p.begin(&[Kernel::MeasureStep, Kernel::ChooseStep])),
+        );
+
+        let velocities = u64::from(max_particles) * size_of::<[f32; 4]>() as u64;
+        encoder.copy_buffer_to_buffer(
+            &physics.velocities.buffer,
+            0,
+            &physics.previous.buffer,
+            0,
+            velocities,
+        );
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.resolve(encoder);
+        }
+        let done = mem::replace(
+            encoder,
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("physics/substep_encoder"),
+            }),
+        );
+        queue.submit([done.finish()]);
+
+        match self
+            .buffers
+            .physics
+            .timestep
+            .read::<TimeStep>(device, queue, 1)
+        {
+            Ok(timestep) => timestep[0].dtime,
+            Err(err) => {
+                error!("failed to read back the time step: {err}");
+                left
+            }
+        }
+    }
+
+    /// Starts or stops timing each kernel. Splits the step into one pass per
+    /// kernel while enabled.
+    pub fn set_profiling(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, enabled: bool) {
+        match (enabled, &self.profiler) {
+            (true, None) => self.profiler = Profiler::new(device, queue),
+            (false, Some(_)) => self.profiler = None,
+            _ => {}
+        }
+    }
+
+    #[must_use]
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
+    }
+
+    /// Iterations the DFSPH solves took in a recent step, or `None` under the
+    /// other solvers. Lags a frame or two behind on the gpu backend.
+    #[must_use]
+    pub fn iterations(&self) -> Option<Iterations> {
+        if self.udata.settings.solver != solver::DFSPH {
+            return None;
+        }
+
+        match &self.cpu {
+            Some(cpu) => Some(cpu.solver_state().into()),
+            None => self.iterations.latest(),
+        }
+    }
+
+    pub fn iterations_mut(&mut self) -> &mut IterationReadback {
+        &mut self.iterations
+    }
+
+    /// Snapshots the particles and settings, without a camera. Blocks until the
+    /// gpu is idle.
+    pub fn checkpoint(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<Checkpoint, ReadError> {
+        let mut settings = self.udata.settings;
+
+        if let Some(cpu) = &self.cpu {
+            return Ok(cpu.checkpoint(&settings, self.udata.flow.state));
+        }
+
+        let flow = self.flow_state(device, queue)?;
+        settings.num_particles = flow.count;
+        let n = flow.count as usize;
+        let physics = &self.buffers.physics;
+
+        Ok(Checkpoint {
+            settings,
+            camera: None,
+            bodies: self.bodies(device, queue)?,
+            flow,
+            timestep: physics.timestep.read(device, queue, 1)?[0],
+            positions: physics.positions.read(device, queue, n)?,
+            predictions: physics.predictions.read(device, queue, n)?,
+            velocities: physics.velocities.read(device, queue, n)?,
+            densities: physics.densities.read(device, queue, n)?,
+            previous: physics.previous.read(device, queue, n)?,
+        })
+    }
+
+    /// The rigid bodies as they are now. Blocks until the gpu is idle.
+    pub fn bodies(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Bodies, ReadError> {
+        if let Some(cpu) = &self.cpu {
+            return Ok(cpu.bodies);
+        }
+
+        let bodies = self.buffers.physics.bodies.read(device, queue, 1)?;
+        Ok(bodies[0])
+    }
+
+    /// Particles in use, boundary included. Blocks until the gpu is idle
+    /// while emitters or sinks change the count there.
+    pub fn count(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<u32, ReadError> {
+        Ok(self.flow_state(device, queue)?.count)
+    }
+
+    // what the emitters and sinks have done so far
+    fn flow_state(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<FlowState, ReadError> {
+        if self.cpu.is_some() || !self.udata.flow.active() {
+            return Ok(self.udata.flow.state);
+        }
+
+        let state = self.buffers.physics.flow_state.read(device, queue, 1)?;
+        Ok(state[0])
+    }
+
+    /// Replaces the particles and settings with a snapshot, as if the
+    /// simulation had been running all along. Leaves the simulation alone if
+    /// the snapshot's counts don't fit its buffers.
+    pub fn restore(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        checkpoint: &Checkpoint,
+        backend: Backend,
+    ) -> Result<(), CheckpointError> {
+        checkpoint.validate()?;
+
+        let settings = &mut self.udata.settings;
+        *settings = checkpoint.settings;
+        settings.num_particles = checkpoint.positions.len() as u32;
+        settings.max_particles = settings.max_particles.max(settings.num_particles);
+        self.udata.mover.restore(settings);
+        let settings = *settings;
+
+        self.udata.flow.state = FlowState {
+            count: settings.num_particles,
+            ..checkpoint.flow
+        };
+
+        self.reserve(device, settings.max_particles);
+
+        let shell = (settings.boundary_particles - settings.body_particles) as usize;
+        let boundary = settings.boundary_particles as usize;
+        let mut anchors = checkpoint.positions[..shell]
+            .iter()
+            .map(|p| settings.to_box(p.truncate()).extend(0.))
+            .collect::<Vec<_>>();
+        anchors.extend(bodies::anchors(
+            &checkpoint.bodies,
+            &checkpoint.positions[shell..boundary],
+            &checkpoint.predictions[shell..boundary],
+        ));
+        self.udata.boundary = BoundaryVolumes::new(
+            anchors[..shell].iter().map(|a| a.truncate()).collect(),
+            anchors[shell..].to_vec(),
+            settings.box_size,
+            settings.periodic_axes(),
+        );
+
+        let physics = &self.buffers.physics;
+        self.buffers.uniform.settings.reset(queue, &[settings]);
+        physics.flow_state.reset(queue, &[self.udata.flow.state]);
+        physics.positions.write(queue, &checkpoint.positions);
+        physics.predictions.write(queue, &checkpoint.predictions);
+        physics.velocities.write(queue, &checkpoint.velocities);
+        physics.densities.write(queue, &checkpoint.densities);
+        physics.previous.write(queue, &checkpoint.previous);
+        physics.anchors.write(queue, &anchors);
+        physics.bodies.reset(queue, &[checkpoint.bodies]);
+        physics.timestep.reset(queue, &[checkpoint.timestep]);
+        self.clear_hash(queue);
+
+        self.cpu = match backend {
+            Backend::Gpu => None,
+            Backend::Cpu => Some(CpuSolver::restore(checkpoint, anchors)),
+        };
+
+        self.update_volumes(queue);
+
+        debug!(
+            "restored simulation with {} particles ({} boundary)",
+            settings.num_particles, settings.boundary_particles
+        );
+        Ok(())
+    }
+
+    /// Returns the cpu solver when running on [`Backend::Cpu`].
+    #[must_use]
+    pub fn cpu(&self) -> Option<&CpuSolver> {
+        self.cpu.as_ref()
+    }
+
+    /// Uploads the cpu backend's particles so they can be drawn. The gpu
+    /// backend already does this as part of [`PhysicsShader::update`].
+    pub fn sync(&self, queue: &wgpu::Queue, encoder: &mut wgpu::CommandEncoder) {
+        let Some(cpu) = &self.cpu else {
+            return;
+        };
+
+        let physics = &self.buffers.physics;
+        physics.positions.write(queue, &cpu.positions);
+        physics.predictions.write(queue, &cpu.predictions);
+        physics.velocities.write(queue, &cpu.velocities);
+        physics.densities.write(queue, &cpu.densities);
+        physics.flow_state.reset(queue, &[self.udata.flow.state]);
+
+        let mut pass = encoder.begin_compute_pass(&self.pass_desc);
+        self.pipelines
+            .copy_prims
+            .dispatch(&mut pass, self.udata.settings.num_particles);
+    }
+
+    pub fn lease_panel(&mut self) -> &mut SimSettings {
+        &mut self.udata.settings
+    }
+
+    pub fn set_mouse(&mut self, pos: Vec2, lmb: bool, rmb: bool) {
+        self.udata.mouse = MouseState::new(pos, lmb, rmb);
+    }
+
+    /// Moves the box by `offset`, on top of any motion the scene gives it.
+    /// The walls push the fluid along over the next step.
+    pub fn drag_box(&mut self, offset: Vec3) {
+        self.udata.mover.drag(offset);
+    }
+
+    pub fn set_colors(&mut self, colors: &ColorSettings) {
+        self.udata.colors = colors.uniform();
+    }
+
+    /// Replaces every phase but 0, without moving any particles between
+    /// phases.
+    pub fn set_materials(&mut self, materials: &[MaterialSettings]) {
+        self.udata.materials.clear();
+        self.udata.materials.extend_from_slice(materials);
+    }
+
+    /// Replaces the obstacles, baking any new meshes. The particles already
+    /// inside one are pushed out on the next step.
+    pub fn set_obstacles(&mut self, obstacles: &[Obstacle]) {
+        let box_size = self.udata.settings.box_size;
+        self.udata.obstacles.update(obstacles, box_size);
+    }
+
+    #[must_use]
+    pub fn buffers(&self) -> &Buffers {
+        &self.buffers
+    }
+}
+
+/// `layers` of boundary particles on the walls of a box of `box_size`,
+/// `sprest` apart, in the frame of the box. There are no walls across the
+/// `periodic` axes, where the shell tiles the box exactly so it meets itself
+/// across the seam.
+pub(crate) fn shell(
+    box_size: Vec3,
+    periodic: BVec3,
+    layers: u32,
+    radius: f32,
+    sprest: f32,
+) -> Vec<Vec4> {
+    // calculate boundary conditions, 3d (6 faces + 12 edges + 8 corners per shell)
+    let r1_count = ((box_size + 2. * radius) / sprest).ceil();
+    let r1_size_vec = r1_count * sprest;
+    let r1_diff = (r1_size_vec - box_size) / 2.;
+    let r1_tl = -r1_diff;
+
+    let tiles = (box_size / sprest).round().max(Vec3::ONE);
+    let step = Vec3::select(periodic, box_size / tiles, Vec3::splat(sprest));
+
+    let mut positions = vec![];
+
+    for rn in 0..layers.max(1) {
+        let rn = rn as f32;
+        let tl = Vec3::select(periodic, step / 2., r1_tl - rn * sprest);
+        // `tiles` points across a periodic axis, so the shell repeats with the box
+        let count = Vec3::select(periodic, tiles - 1., r1_count + 2. * rn).as_uvec3();
+
+        let cx = count.x;
+        let cy = count.y;
+        let cz = count.z;
+        let wall = |axis: usize, i: u32, last: u32| !periodic.test(axis) && (i == 0 || i == last);
+
+        // walk the (cx+1) × (cy+1) × (cz+1) grid; keep only positions on the hull
+        for i in 0..=cx {
+            for j in 0..=cy {
+                for k in 0..=cz {
+                    let on_hull = wall(0, i, cx) || wall(1, j, cy) || wall(2, k, cz);
+                    if !on_hull {
+                        continue;
+                    }
+
+                    let p = tl + step * vec3(i as f32, j as f32, k as f32);
+                    positions.push(p.extend(0.)); // padding for alignment
+                }
+            }
+        }
+    }
+
+    positions
+}
+
+/// Fills the volumes of `init` with particles `size` across, leaving out
+/// the fluid inside the bodies, obstacles and meshes. Returns them with their
+/// phase.
+fn fill(
+    init: &InitialConditions,
+    obstacles: &ObstacleTable,
+    surfaces: &MeshSurfaces,
+    size: f32,
+    rng: &mut StdRng,
+) -> (Vec<Vec3>, Vec<u32>) {
+    let mut fluid = vec![];
+    let mut phases = vec![];
+    let last_phase = init.materials.len().min(MAX_PHASES - 1);
+
+    for volume in &init.volumes {
+        let mut filled = vec![];
+        volume.fill(size + init.gap, init.gap, rng, &mut filled);
+        filled.retain(|&pos| {
+            !init.bodies.iter().any(|b| b.contains(pos, size))
+                && !obstacles.contains(pos, init.box_size, size)
+                && !surfaces.contains(pos, size)
+        });
+        fluid.append(&mut filled);
+
+        let mut phase = volume.phase();
+        if phase as usize > last_phase {
+            warn!("no material for phase {phase}, using phase 0");
+            phase = 0;
+        }
+        phases.resize(fluid.len(), phase);
+    }
+
+    (fluid, phases)
+}
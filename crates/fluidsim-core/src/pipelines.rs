@@ -0,0 +1,652 @@
+use gpu_shared::{materials::Materials, rheology, solver};
+
+use crate::{buffers::Buffers, prelude::*};
+
+/// Most dispatches timed in one frame, over all of its steps. Two timestamps
+/// each fill the largest query set wgpu allows
+pub const MAX_DISPATCHES: usize = 2048;
+
+/// Two timestamps per dispatch, one at each end of its pass
+pub const TIMESTAMPS: u32 = 2 * MAX_DISPATCHES as u32;
+
+macro_rules! count {
+    () => (0);
+    ($el:ident $(, $rem:ident)*) => (1 + count!($($rem),*))
+}
+
+macro_rules! pipelines {
+    (@dispatch particles, $p:ident) => ($p.div_ceil(::gpu_shared::WORKGROUP_SIZE));
+    (@dispatch $n:expr, $p:ident) => ($n);
+
+    (@x $p:ident) => (pipelines!(@dispatch particles, $p));
+    (@y $p:ident) => (1);
+    (@z $p:ident) => (1);
+
+    (@x $p:ident $x:tt) => (pipelines!(@dispatch $x, $p));
+    (@y $p:ident $y:tt) => (pipelines!(@dispatch $y, $p));
+    (@z $p:ident $z:tt) => (pipelines!(@dispatch $z, $p));
+
+    ($(
+        compute $entry:ident$([$x:tt; $y:tt; $z:tt])? as $cty:ident {
+            $(from $group:ident use $($buffer:ident),+);+;
+        }
+    )+) => {
+        pub const PIPELINES: usize = count!($($entry),+) + 1;
+
+        /// Every kernel a step can run, including the radix sort
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Kernel {
+            $($cty,)+
+            Sort,
+        }
+
+        impl Kernel {
+            pub const ALL: [Kernel; PIPELINES] = [$(Kernel::$cty,)+ Kernel::Sort];
+
+            #[must_use]
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Kernel::$cty => stringify!($entry),)+
+                    Kernel::Sort => "sort",
+                }
+            }
+        }
+
+        /// Time spent in each kernel over a frame, in milliseconds. Kernels
+        /// that run more than once, in a step or across its steps, are summed.
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub struct Timings {
+            kernels: [f32; PIPELINES],
+            ran: [bool; PIPELINES],
+            pub total: f32,
+        }
+
+        // too many kernels for the arrays to derive it
+        impl Default for Timings {
+            fn default() -> Self {
+                Self {
+                    kernels: [0.0; PIPELINES],
+                    ran: [false; PIPELINES],
+                    total: 0.0,
+                }
+            }
+        }
+
+        impl Timings {
+            /// `ts` holds a pair of timestamps for each of the first
+            /// [`MAX_DISPATCHES`] entries in `kernels`, which ran as `steps`
+            /// steps of that many kernels each. The total leaves out the time
+            /// between steps.
+            pub(crate) fn from_timestamps(
+                ts: &[u64],
+                kernels: &[Kernel],
+                steps: &[usize],
+                period: f32,
+            ) -> Self {
+                let mut perf = Self::default();
+                let timed = kernels.len().min(MAX_DISPATCHES);
+
+                let ms = |start: u64, end: u64| end.saturating_sub(start) as f32 * period * 1e-6;
+
+                for (i, &kernel) in kernels[..timed].iter().enumerate() {
+                    perf.kernels[kernel as usize] += ms(ts[2 * i], ts[2 * i + 1]);
+                    perf.ran[kernel as usize] = true;
+                }
+
+                let mut first = 0;
+                for &len in steps {
+                    let last = (first + len).min(timed);
+                    if last > first {
+                        perf.total += ms(ts[2 * first], ts[2 * last - 1]);
+                    }
+                    first += len;
+                }
+
+                perf
+            }
+
+            #[must_use]
+            pub fn get(&self, kernel: Kernel) -> f32 {
+                self.kernels[kernel as usize]
+            }
+
+            /// Every kernel that ran, excluding the total.
+            #[must_use]
+            pub fn entries(&self) -> Vec<(&'static str, f32)> {
+                Kernel::ALL
+                    .into_iter()
+                    .filter(|&k| self.ran[k as usize])
+                    .map(|k| (k.name(), self.get(k)))
+                    .collect()
+            }
+
+            pub(crate) fn accumulate(&mut self, other: &Self, scale: f32) {
+                for i in 0..PIPELINES {
+                    self.kernels[i] += other.kernels[i] * scale;
+                    self.ran[i] |= other.ran[i];
+                }
+                self.total += other.total * scale;
+            }
+        }
+
+        $(
+            pub struct $cty {
+                bind_groups: [wgpu::BindGroup; Self::GROUPS],
+                pipeline: wgpu::ComputePipeline,
+            }
+
+            impl $cty {
+                pub const GROUPS: usize = count!($($group),+);
+
+                pub fn new(device: &wgpu::Device, buffers: &Buffers, shader: &wgpu::ShaderModule) -> Self {
+                    let bind_layouts = Self::bind_layouts(device, buffers);
+                    let bind_groups = Self::bind_groups(device, &bind_layouts, buffers);
+                    let pipeline_layout = Self::pipeline_layout(device, &bind_layouts);
+                    let pipeline = Self::pipeline(device, &pipeline_layout, shader);
+
+                    Self {
+                        bind_groups,
+                        pipeline,
+                    }
+                }
+
+                fn bind_layouts(device: &wgpu::Device, buffers: &Buffers) -> [wgpu::BindGroupLayout; Self::GROUPS] {
+                    let mut idx;
+
+                    [$({
+                        idx = 0;
+
+                        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                            label: Some(concat!("physics/pipeline_layout:", stringify!($entry), "/bindgroup_layout:", stringify!($group))),
+                            entries: &[$({
+                                idx += 1;
+
+                                wgpu::BindGroupLayoutEntry {
+                                    binding: idx - 1,
+                                    visibility: wgpu::ShaderStages::COMPUTE,
+                                    ty: buffers.$group.$buffer.binding,
+                                    count: None
+                                }
+                            }),+]
+                        })
+                    }),+]
+                }
+
+
+                fn bind_groups(
+                    device: &wgpu::Device,
+                    layouts: &[wgpu::BindGroupLayout],
+                    buffers: &Buffers
+                ) -> [wgpu::BindGroup; Self::GROUPS] {
+                    let mut i = 0;
+
+                    [$({
+                        let mut j = 0;
+
+                        let entries = [$({
+                            j += 1;
+
+                            wgpu::BindGroupEntry {
+                                binding: j - 1,
+                                resource: wgpu::BindingResource::Buffer(
+                                    buffers.$group.$buffer.buffer.as_entire_buffer_binding()
+                                )
+                            }
+                        }),+];
+
+                        i += 1;
+
+                        device.create_bind_group(&wgpu::BindGroupDescriptor {
+                            label: Some(concat!("physics/pipeline:", stringify!($entry), "/bindgroup:", stringify!($group))),
+                            layout: &layouts[i - 1],
+                            entries: &entries
+                        })
+                    }),+]
+                }
+
+                fn pipeline_layout(device: &wgpu::Device, layouts: &[wgpu::BindGroupLayout]) -> wgpu::PipelineLayout {
+                    const _GROUPS: usize = $cty::GROUPS;
+
+                    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: Some(concat!("physics/pipeline_layout:", stringify!($entry))),
+                        bind_group_layouts: &::std::array::from_fn::<_, _GROUPS, _>(|i| Some(&layouts[i])),
+                        immediate_size: 0
+                    })
+                }
+
+                fn pipeline(
+                    device: &wgpu::Device,
+                    layout: &wgpu::PipelineLayout,
+                    shader: &wgpu::ShaderModule
+                ) -> wgpu::ComputePipeline {
+                    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                        label: Some(concat!("physics/pipeline:", stringify!($entry))),
+                        layout: Some(layout),
+                        module: shader,
+                        entry_point: Some(stringify!($entry)),
+                        compilation_options: wgpu::PipelineCompilationOptions::default(),
+                        cache: None,
+                    })
+                }
+
+                #[allow(unused_variables)]
+                pub fn dispatch(
+                    &self,
+                    pass: &mut wgpu::ComputePass,
+                    num_particles: u32,
+                ) {
+                    pass.set_pipeline(&self.pipeline);
+                    for (i, bg) in self.bind_groups.iter().enumerate() {
+                        pass.set_bind_group(i as u32, bg, &[]);
+                    }
+                    pass.dispatch_workgroups(
+                        pipelines!(@x num_particles $($x)?),
+                        pipelines!(@y num_particles $($y)?),
+                        pipelines!(@z num_particles $($z)?)
+                    );
+                }
+            }
+        )+
+
+        pub struct Pipelines {
+            $(pub $entry: $cty,)+
+            pub sorter: ::wgpu_sort::Sorter,
+        }
+
+        impl Pipelines {
+            pub fn new(
+                device: &wgpu::Device,
+                buffers: &Buffers,
+                shader: &wgpu::ShaderModule,
+                sorter: ::wgpu_sort::Sorter,
+            ) -> Self {
+                Self {
+                    $($entry: $cty::new(device, buffers, shader),)+
+                    sorter,
+                }
+            }
+
+            /// Records `kernel` into `pass`.
+            pub fn dispatch(
+                &self,
+                kernel: Kernel,
+                queue: &wgpu::Queue,
+                pass: &mut wgpu::ComputePass,
+                num_particles: u32,
+            ) {
+                match kernel {
+                    $(Kernel::$cty => self.$entry.dispatch(pass, num_particles),)+
+                    Kernel::Sort => self.sorter.sort_with_pass(pass, queue, num_particles),
+                }
+            }
+
+            /// Runs `kernels` in one pass, or one pass each when writing
+            /// `timestamps`, from the query index with them. Queries past
+            /// [`TIMESTAMPS`] are left out.
+            pub fn dispatch_all(
+                &self,
+                encoder: &mut wgpu::CommandEncoder,
+                queue: &wgpu::Queue,
+                descriptor: &wgpu::ComputePassDescriptor<'_>,
+                num_particles: u32,
+                kernels: &[Kernel],
+                timestamps: Option<(&wgpu::QuerySet, u32)>,
+            ) {
+                let Some((query_set, first)) = timestamps else {
+                    let mut pass = encoder.begin_compute_pass(descriptor);
+
+                    for &kernel in kernels {
+                        self.dispatch(kernel, queue, &mut pass, num_particles);
+                    }
+
+                    return;
+                };
+
+                for (i, &kernel) in kernels.iter().enumerate() {
+                    let i = first + i as u32 * 2;
+                    let timestamp_writes = (i < TIMESTAMPS).then_some(wgpu::ComputePassTimestampWrites {
+                        query_set,
+                        beginning_of_pass_write_index: Some(i),
+                        end_of_pass_write_index: Some(i + 1),
+                    });
+
+                    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                        label: descriptor.label,
+                        timestamp_writes,
+                    });
+
+                    self.dispatch(kernel, queue, &mut pass, num_particles);
+                }
+            }
+        }
+    };
+}
+
+pipelines!(
+    compute external_forces as ExternalForces {
+        from uniform use settings, mouse, globals;
+        from physics use positions, predictions, velocities;
+    }
+
+    compute measure_step as MeasureStep {
+        from uniform use settings;
+        from physics use velocities, previous, timestep;
+    }
+
+    compute choose_step[1; 1; 1] as ChooseStep {
+        from uniform use settings, materials;
+        from physics use timestep;
+    }
+
+    compute pre_sort as PreSort {
+        from uniform use settings;
+        from physics use predictions;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute post_sort as PostSort {
+        from uniform use settings;
+        from spatial_hash use indices;
+        from sort use keys;
+    }
+
+    compute update_densities as UpdateDensities {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, deltas;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute apply_jitter as ApplyJitter {
+        from uniform use settings;
+        from physics use predictions, deltas;
+    }
+
+    compute pressure_force as PressureForce {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute pbf_predict as PbfPredict {
+        from uniform use settings, mouse, globals;
+        from physics use positions, predictions, velocities;
+    }
+
+    compute pbf_lambda as PbfLambda {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, deltas;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute pbf_delta as PbfDelta {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, deltas;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute pbf_apply as PbfApply {
+        from uniform use settings, obstacles;
+        from physics use predictions, deltas, sdfs;
+    }
+
+    compute pbf_velocity as PbfVelocity {
+        from uniform use settings;
+        from physics use positions, predictions, velocities;
+    }
+
+    compute dfsph_prepare as DfsphPrepare {
+        from uniform use settings;
+        from physics use positions, predictions;
+    }
+
+    compute dfsph_factors as DfsphFactors {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute dfsph_divergence_source as DfsphDivergenceSource {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, stiffness, state;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute dfsph_divergence_check[1; 1; 1] as DfsphDivergenceCheck {
+        from uniform use settings;
+        from physics use state;
+    }
+
+    compute dfsph_divergence_apply as DfsphDivergenceApply {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, stiffness, state;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute dfsph_forces as DfsphForces {
+        from uniform use settings, mouse, globals;
+        from physics use positions, velocities;
+    }
+
+    compute dfsph_density_source as DfsphDensitySource {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, stiffness, state;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute dfsph_density_check[1; 1; 1] as DfsphDensityCheck {
+        from uniform use settings;
+        from physics use state;
+    }
+
+    compute dfsph_density_apply as DfsphDensityApply {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, stiffness, state;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute surface_normals as SurfaceNormals {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, normals;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute surface_tension as SurfaceTension {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, normals, deltas;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute shear_rates as ShearRates {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, shear;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute viscosity as Viscosity {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, shear, deltas;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute apply_forces as ApplyForces {
+        from uniform use settings;
+        from physics use velocities, deltas;
+    }
+
+    compute body_forces as BodyForces {
+        from uniform use settings, materials;
+        from physics use predictions, velocities, densities, stiffness, bodies;
+        from spatial_hash use indices;
+        from sort use lookup, keys;
+    }
+
+    compute integrate_bodies[1; 1; 1] as IntegrateBodies {
+        from uniform use settings;
+        from physics use bodies;
+    }
+
+    compute move_bodies as MoveBodies {
+        from uniform use settings;
+        from physics use positions, predictions, velocities, anchors, bodies;
+    }
+
+    compute move_box as MoveBox {
+        from uniform use settings;
+        from physics use positions, predictions, velocities, anchors;
+    }
+
+    compute update_positions as UpdatePositions {
+        from uniform use settings;
+        from physics use positions, velocities;
+    }
+
+    compute collide as Collide {
+        from uniform use settings, obstacles;
+        from physics use positions, velocities, sdfs;
+    }
+
+    compute drain as Drain {
+        from uniform use settings, flow;
+        from physics use positions, flow_state;
+    }
+
+    compute count_holes as CountHoles {
+        from uniform use settings;
+        from physics use positions, flow_state, blocks;
+    }
+
+    compute scan_holes[1; 1; 1] as ScanHoles {
+        from physics use flow_state, blocks;
+    }
+
+    compute find_holes as FindHoles {
+        from uniform use settings;
+        from physics use positions, flow_state, blocks, holes;
+    }
+
+    compute fill_holes as FillHoles {
+        from physics use positions, predictions, velocities, densities, stiffness, previous, flow_state, holes;
+    }
+
+    compute plan_flow[1; 1; 1] as PlanFlow {
+        from uniform use settings, flow;
+        from physics use flow_state;
+    }
+
+    compute emit as Emit {
+        from uniform use settings, flow;
+        from physics use positions, predictions, velocities, densities, stiffness, previous, flow_state;
+    }
+
+    compute copy_prims as CopyPrims {
+        from uniform use settings, colors, materials;
+        from physics use positions, predictions, velocities, densities;
+        from drawing use primitives;
+    }
+);
+
+impl Pipelines {
+    /// The kernels one step runs under `settings.solver`, in order. The
+    /// bodies feel every pressure iteration the fluid applies. Kernels that
+    /// move particles their neighbors are reading leave the change in the
+    /// deltas for an apply pass, so runs repeat on the gpu.
+    #[must_use]
+    pub fn schedule(settings: &SimSettings, materials: &Materials) -> Vec<Kernel> {
+        let sort = [Kernel::PreSort, Kernel::Sort, Kernel::PostSort];
+        let bodies = settings.num_bodies > 0;
+        let mut kernels = Vec::new();
+
+        match settings.solver {
+            solver::PBF => {
+                kernels.push(Kernel::PbfPredict);
+                kernels.extend(sort);
+                for _ in 0..settings.solver_iterations.max(1) {
+                    kernels.extend([Kernel::PbfLambda, Kernel::ApplyJitter]);
+                    if bodies {
+                        kernels.push(Kernel::BodyForces);
+                    }
+                    kernels.extend([Kernel::PbfDelta, Kernel::PbfApply]);
+                }
+                kernels.push(Kernel::PbfVelocity);
+                Self::schedule_forces(settings, materials, &mut kernels);
+            }
+            solver::DFSPH => {
+                kernels.push(Kernel::DfsphPrepare);
+                kernels.extend(sort);
+                kernels.push(Kernel::DfsphFactors);
+                // the checks stop the solves early, later iterations are no-ops
+                for _ in 0..settings.max_divergence_iterations.max(1) {
+                    kernels.extend([
+                        Kernel::DfsphDivergenceSource,
+                        Kernel::DfsphDivergenceCheck,
+                        Kernel::DfsphDivergenceApply,
+                    ]);
+                    if bodies {
+                        kernels.push(Kernel::BodyForces);
+                    }
+                }
+                kernels.push(Kernel::DfsphForces);
+                Self::schedule_forces(settings, materials, &mut kernels);
+                for _ in 0..settings.max_density_iterations.max(1) {
+                    kernels.extend([
+                        Kernel::DfsphDensitySource,
+                        Kernel::DfsphDensityCheck,
+                        Kernel::DfsphDensityApply,
+                    ]);
+                    if bodies {
+                        kernels.push(Kernel::BodyForces);
+                    }
+                }
+            }
+            _ => {
+                kernels.push(Kernel::ExternalForces);
+                kernels.extend(sort);
+                kernels.extend([
+                    Kernel::UpdateDensities,
+                    Kernel::ApplyJitter,
+                    Kernel::PressureForce,
+                ]);
+                if bodies {
+                    kernels.push(Kernel::BodyForces);
+                }
+                Self::schedule_forces(settings, materials, &mut kernels);
+            }
+        }
+
+        if bodies {
+            kernels.extend([Kernel::IntegrateBodies, Kernel::MoveBodies]);
+        }
+
+        kernels.extend([Kernel::UpdatePositions, Kernel::Collide, Kernel::CopyPrims]);
+        kernels
+    }
+
+    /// The forces every solver applies once the densities are known.
+    fn schedule_forces(settings: &SimSettings, materials: &Materials, kernels: &mut Vec<Kernel>) {
+        if materials.max_surface_tension() > 0.0 {
+            kernels.extend([
+                Kernel::SurfaceNormals,
+                Kernel::SurfaceTension,
+                Kernel::ApplyForces,
+            ]);
+        }
+        if settings.viscosity_model != rheology::NEWTONIAN {
+            kernels.push(Kernel::ShearRates);
+        }
+        kernels.extend([Kernel::Viscosity, Kernel::ApplyForces]);
+    }
+}
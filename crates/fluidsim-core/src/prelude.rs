@@ -0,0 +1,7 @@
+pub use std::sync::Arc;
+
+pub use bytemuck::{Pod, Zeroable};
+pub use gpu_shared::{MouseState, Settings as SimSettings};
+pub use snafu::{Location, prelude::*};
+
+pub(crate) use crate::config::*;
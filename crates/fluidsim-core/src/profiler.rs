@@ -0,0 +1,179 @@
+//! Per-kernel gpu timings, from a timestamp query around each compute pass.
+//! Every step of a frame gets its own range of queries, and the frame's
+//! timings are their sum. Each range is resolved before the submit that runs
+//! it, so a frame can be split over several submits.
+
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{Arc, OnceLock},
+};
+
+use crate::pipelines::{Kernel, TIMESTAMPS, Timings};
+
+// frames in the rolling average
+const HISTORY: usize = 60;
+
+type Mapped = Arc<OnceLock<Result<(), wgpu::BufferAsyncError>>>;
+
+#[derive(Default)]
+struct Frame {
+    kernels: Vec<Kernel>,
+    steps: Vec<usize>,
+}
+
+pub struct Profiler {
+    query_set: wgpu::QuerySet,
+    resolve: wgpu::Buffer,
+    readback: wgpu::Buffer,
+    period: f32,
+
+    // the kernels timed this frame, in query order, and how long each run
+    // of them was
+    frame: Frame,
+    // queries of this frame already copied into `readback`
+    resolved: u32,
+    // set while `readback` is being mapped
+    mapping: Option<(Mapped, Frame)>,
+
+    history: VecDeque<Timings>,
+}
+
+impl Profiler {
+    /// Returns `None` if the device can't write timestamps.
+    #[must_use]
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            warn!("timestamp queries are unsupported, not profiling");
+            return None;
+        }
+
+        let size = u64::from(TIMESTAMPS) * u64::from(wgpu::QUERY_SIZE);
+
+        Some(Self {
+            query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some("profiler/query_set"),
+                ty: wgpu::QueryType::Timestamp,
+                count: TIMESTAMPS,
+            }),
+            resolve: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("profiler/resolve"),
+                size,
+                usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("profiler/readback"),
+                size,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            period: queue.get_timestamp_period(),
+            frame: Frame::default(),
+            resolved: 0,
+            mapping: None,
+            history: VecDeque::with_capacity(HISTORY),
+        })
+    }
+
+    /// Claims queries for a run of `kernels`, after the runs already in this
+    /// frame. Returns the query set and the first index.
+    pub(crate) fn begin(&mut self, kernels: &[Kernel]) -> (&wgpu::QuerySet, u32) {
+        let first = 2 * self.frame.kernels.len() as u32;
+        self.frame.kernels.extend_from_slice(kernels);
+        self.frame.steps.push(kernels.len());
+
+        (&self.query_set, first)
+    }
+
+    /// Resolves the queries claimed since the last call into their place in
+    /// the readback. Call it once before every submit. Skipped while the
+    /// previous readback is still mapping.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if self.mapping.is_some() {
+            return;
+        }
+
+        let used = (2 * self.frame.kernels.len() as u32).min(TIMESTAMPS);
+        if used <= self.resolved {
+            return;
+        }
+        let bytes = |queries: u32| u64::from(queries) * u64::from(wgpu::QUERY_SIZE);
+
+        // resolves have to start at an aligned offset, copies don't
+        encoder.resolve_query_set(&self.query_set, self.resolved..used, &self.resolve, 0);
+        encoder.copy_buffer_to_buffer(
+            &self.resolve,
+            0,
+            &self.readback,
+            bytes(self.resolved),
+            bytes(used - self.resolved),
+        );
+        self.resolved = used;
+    }
+
+    /// Starts mapping the timestamps resolved this frame, and starts the next
+    /// frame's queries over from the first.
+    pub fn submitted(&mut self) {
+        let mut frame = mem::take(&mut self.frame);
+        let resolved = mem::take(&mut self.resolved);
+        if resolved == 0 {
+            return;
+        }
+        // anything claimed after the last resolve never reached the readback
+        frame.kernels.truncate(resolved as usize / 2);
+
+        let mapped = Mapped::default();
+        let inner = Arc::clone(&mapped);
+        self.readback
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |res| _ = inner.set(res));
+
+        self.mapping = Some((mapped, frame));
+    }
+
+    /// Records the timings once they have been mapped. The device must be
+    /// polled for this to make progress.
+    pub fn collect(&mut self) {
+        let Some((mapped, frame)) = &self.mapping else {
+            return;
+        };
+        let Some(res) = mapped.get() else {
+            return;
+        };
+
+        if let Err(e) = res {
+            error!("Failed to map timestamps: {e}");
+        } else {
+            let timings = {
+                let data = self.readback.slice(..).get_mapped_range();
+                let ts = bytemuck::pod_collect_to_vec::<u8, u64>(&data);
+                Timings::from_timestamps(&ts, &frame.kernels, &frame.steps, self.period)
+            };
+            self.readback.unmap();
+
+            if self.history.len() == HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back(timings);
+        }
+
+        self.mapping = None;
+    }
+
+    /// The average over the last few frames, if any have been collected.
+    #[must_use]
+    pub fn average(&self) -> Option<Timings> {
+        if self.history.is_empty() {
+            return None;
+        }
+
+        let scale = 1.0 / self.history.len() as f32;
+        let mut avg = Timings::default();
+        for timings in &self.history {
+            avg.accumulate(timings, scale);
+        }
+
+        Some(avg)
+    }
+}
@@ -0,0 +1,269 @@
+//! Scene files, which describe the initial conditions and settings of a
+//! simulation. The format is picked from the extension, either `.ron` or
+//! `.toml`.
+
+use std::{fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{colors::ColorSettings, prelude::*};
+
+#[derive(Debug, Snafu)]
+pub enum SceneError {
+    #[snafu(display("At {location}: failed to access {path}\n{source}"))]
+    Io {
+        path: String,
+        source: io::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: unknown scene format for {path}, expected .ron or .toml"))]
+    UnknownFormat {
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display(
+        "At {location}: fixed_dtime must be a positive number of seconds, got {value}"
+    ))]
+    FixedDtime {
+        value: f32,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: {name} must be a positive number, got {value}"))]
+    Setting {
+        name: &'static str,
+        value: f32,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: ron: failed to parse scene\n{source}"))]
+    RonParse {
+        source: ron::error::SpannedError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: ron: failed to write scene\n{source}"))]
+    RonWrite {
+        source: ron::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: toml: failed to parse scene\n{source}"))]
+    TomlParse {
+        source: toml::de::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: toml: failed to write scene\n{source}"))]
+    TomlWrite {
+        source: toml::ser::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Ron,
+    Toml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Self, SceneError> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("ron") => Ok(Format::Ron),
+            Some("toml") => Ok(Format::Toml),
+            _ => UnknownFormatSnafu {
+                path: path.display().to_string(),
+            }
+            .fail(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scene {
+    /// Length of every step in seconds, ignoring the frame time, so that runs
+    /// of the scene repeat exactly. Steps follow the frame time if unset.
+    pub fixed_dtime: Option<f32>,
+    pub settings: SimSettings,
+    pub init: InitialConditions,
+    pub colors: ColorSettings,
+}
+
+impl Scene {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SceneError> {
+        let path = path.as_ref();
+        let format = Format::of(path)?;
+        let text = fs::read_to_string(path).context(IoSnafu {
+            path: path.display().to_string(),
+        })?;
+        let scene = Self::parse(&text, format)?;
+
+        info!("Loaded scene from {}", path.display());
+        Ok(scene)
+    }
+
+    fn parse(text: &str, format: Format) -> Result<Self, SceneError> {
+        let scene: Self = match format {
+            Format::Ron => ron::from_str(text).context(RonParseSnafu)?,
+            Format::Toml => toml::from_str(text).context(TomlParseSnafu)?,
+        };
+
+        if let Some(value) = scene.fixed_dtime {
+            ensure!(value.is_finite() && value > 0.0, FixedDtimeSnafu { value });
+        }
+
+        // zero or NaN in any of these leaves the particle lattice and the
+        // kernels without a scale, so filling the box hangs or divides by zero
+        let settings = &scene.settings;
+        let size = scene.init.box_size;
+        for (name, value) in [
+            ("mass", settings.mass),
+            ("target_density", settings.target_density),
+            ("smoothing_radius", settings.smoothing_radius),
+            ("particle_radius", settings.particle_radius),
+            ("cfl_number", settings.cfl_number),
+            ("min_dtime", settings.min_dtime),
+            ("box_size.x", size.x),
+            ("box_size.y", size.y),
+            ("box_size.z", size.z),
+        ] {
+            ensure!(
+                value.is_finite() && value > 0.0,
+                SettingSnafu { name, value }
+            );
+        }
+
+        Ok(scene)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SceneError> {
+        let path = path.as_ref();
+        let text = self.write(Format::of(path)?)?;
+
+        fs::write(path, text).context(IoSnafu {
+            path: path.display().to_string(),
+        })?;
+
+        info!("Saved scene to {}", path.display());
+        Ok(())
+    }
+
+    fn write(&self, format: Format) -> Result<String, SceneError> {
+        match format {
+            Format::Ron => ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+                .context(RonWriteSnafu),
+            Format::Toml => toml::to_string_pretty(self).context(TomlWriteSnafu),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+
+    #[test]
+    fn rejects_settings_without_a_scale() {
+        for text in [
+            "[settings]\nmass = 0.0",
+            "[settings]\ntarget_density = -40.0",
+            "[settings]\nsmoothing_radius = nan",
+            "[init]\nbox_size = [8.0, 0.0, 8.0]",
+        ] {
+            let err = Scene::parse(text, Format::Toml).unwrap_err();
+            assert!(matches!(err, SceneError::Setting { .. }), "{text}: {err}");
+        }
+    }
+
+    #[test]
+    fn formats_follow_the_extension() {
+        assert_eq!(Format::of(Path::new("a/drop.ron")).unwrap(), Format::Ron);
+        assert_eq!(Format::of(Path::new("drop.toml")).unwrap(), Format::Toml);
+        for path in ["drop.json", "drop", "ron"] {
+            let err = Format::of(Path::new(path)).unwrap_err();
+            assert!(matches!(err, SceneError::UnknownFormat { .. }), "{path}");
+        }
+    }
+
+    #[test]
+    fn ron_and_toml_read_the_same_scene() {
+        let ron = r#"(
+            fixed_dtime: Some(0.004),
+            settings: (viscosity_strength: 0.12),
+            init: (
+                box_size: (10.0, 8.0, 6.0),
+                volumes: [
+                    (shape: "sphere", center: (0.0, 2.0, 0.0), radius: 1.2),
+                ],
+            ),
+        )"#;
+        let toml = r#"
+            fixed_dtime = 0.004
+            [settings]
+            viscosity_strength = 0.12
+            [init]
+            box_size = [10.0, 8.0, 6.0]
+            [[init.volumes]]
+            shape = "sphere"
+            center = [0.0, 2.0, 0.0]
+            radius = 1.2
+        "#;
+
+        let scene = Scene::parse(ron, Format::Ron).unwrap();
+        assert_eq!(scene, Scene::parse(toml, Format::Toml).unwrap());
+
+        // everything left out keeps its default
+        let mut expected = Scene {
+            fixed_dtime: Some(0.004),
+            ..Scene::default()
+        };
+        expected.settings.viscosity_strength = 0.12;
+        expected.init.box_size = vec3(10.0, 8.0, 6.0);
+        expected.init.volumes = vec![Volume::Sphere {
+            center: vec3(0.0, 2.0, 0.0),
+            radius: 1.2,
+            phase: 0,
+        }];
+        assert_eq!(scene, expected);
+    }
+
+    #[test]
+    fn saved_scenes_read_back_the_same() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../assets/scenes");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let mut scene = Scene::load(&path).unwrap();
+            scene.fixed_dtime = Some(1.0 / 240.0);
+
+            for format in [Format::Ron, Format::Toml] {
+                let text = scene.write(format).unwrap();
+                let read = Scene::parse(&text, format).unwrap();
+                assert_eq!(read, scene, "{} as {format:?}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn bundled_scenes_load() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../assets/scenes");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if let Err(err) = Scene::load(&path) {
+                panic!("{}: {err}", path.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use fluidsim_core::export::ExportFormat;
+
+use crate::prelude::*;
+
+#[derive(Clone, Debug, Parser)]
+#[command(version, about = "Realtime 3D SPH fluid simulation")]
+pub(crate) struct Args {
+    /// Scene file to start with (.ron or .toml)
+    pub scene: Option<PathBuf>,
+
+    /// Open a window instead of going borderless fullscreen
+    #[arg(long)]
+    pub windowed: bool,
+
+    /// Window size when windowed, e.g. 1280x720
+    #[arg(long, value_parser = parse_size, default_value = "1280x720")]
+    pub size: UVec2,
+
+    /// Simulation backend, gpu or cpu
+    #[arg(long, default_value_t)]
+    pub backend: Backend,
+
+    /// Use the first adapter whose name contains this
+    #[arg(long)]
+    pub adapter: Option<String>,
+
+    /// Seed for the initial particle jitter, overriding the scene's
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Run every step this many seconds long, ignoring the frame time,
+    /// overriding the scene's. With --seed, runs repeat exactly
+    #[arg(long, value_parser = parse_dtime)]
+    pub fixed_dtime: Option<f32>,
+
+    /// Start running instead of paused
+    #[arg(long)]
+    pub run: bool,
+
+    /// Exit after this many simulation steps. Implies --run
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub steps: Option<u64>,
+
+    /// Checkpoint to resume from, instead of the scene's initial conditions
+    #[arg(long)]
+    pub restore: Option<PathBuf>,
+
+    /// Write a checkpoint to this file on exit, relative to --output
+    #[arg(long)]
+    pub save_checkpoint: Option<PathBuf>,
+
+    /// Export particles in this format (vtk, ply or csv) into --output
+    #[arg(long)]
+    pub export: Option<ExportFormat>,
+
+    /// Export every this many steps
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub export_every: u64,
+
+    /// Directory to write scenes and other output to
+    #[arg(long, default_value = ".")]
+    pub output: PathBuf,
+}
+
+fn parse_size(s: &str) -> Result<UVec2, String> {
+    let (w, h) = s
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {s:?}"))?;
+
+    let w = w.parse::<u32>().map_err(|e| e.to_string())?;
+    let h = h.parse::<u32>().map_err(|e| e.to_string())?;
+
+    if w == 0 || h == 0 {
+        return Err(format!("expected a nonzero size, got {s:?}"));
+    }
+
+    Ok(UVec2::new(w, h))
+}
+
+fn parse_dtime(s: &str) -> Result<f32, String> {
+    let dtime = s.parse::<f32>().map_err(|e| e.to_string())?;
+
+    if !dtime.is_finite() || dtime <= 0.0 {
+        return Err(format!("expected a positive number of seconds, got {s:?}"));
+    }
+
+    Ok(dtime)
+}
@@ -19,23 +19,88 @@
 #[macro_use]
 extern crate tracing;
 
+mod cli;
 mod config;
 mod logger;
 mod prelude;
 mod renderer;
 
+use std::{fs, io};
+
+use clap::Parser;
+use cli::Args;
+use fluidsim_core::{
+    Scene,
+    checkpoint::{Checkpoint, CheckpointError},
+    scene::SceneError,
+};
 use renderer::Renderer;
+use snafu::{Location, prelude::*};
 use winit::{error::EventLoopError, event_loop::EventLoop};
 
-fn main() -> Result<(), EventLoopError> {
+#[derive(Debug, Snafu)]
+enum MainError {
+    #[snafu(display("At {location}: failed to load scene\n{source}"))]
+    LoadScene {
+        source: SceneError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: failed to load checkpoint\n{source}"))]
+    LoadCheckpoint {
+        source: CheckpointError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: failed to create output directory\n{source}"))]
+    CreateOutput {
+        source: io::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+
+    #[snafu(display("At {location}: winit: event loop error\n{source}"))]
+    EventLoop {
+        source: EventLoopError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+fn main() -> Result<(), MainError> {
+    let args = Args::parse();
     logger::init();
 
     info!("Starting up");
-    let event_loop = EventLoop::builder().build()?;
-    let app = Box::leak(Box::new(Renderer::new()));
+
+    let mut scene = match &args.scene {
+        Some(path) => Scene::load(path).context(LoadSceneSnafu)?,
+        None => Scene::default(),
+    };
+
+    if args.seed.is_some() {
+        scene.init.seed = args.seed;
+    }
+    if args.fixed_dtime.is_some() {
+        scene.fixed_dtime = args.fixed_dtime;
+    }
+
+    let checkpoint = args
+        .restore
+        .as_ref()
+        .map(Checkpoint::load)
+        .transpose()
+        .context(LoadCheckpointSnafu)?;
+
+    fs::create_dir_all(&args.output).context(CreateOutputSnafu)?;
+
+    let event_loop = EventLoop::builder().build().context(EventLoopSnafu)?;
+    let app = Box::leak(Box::new(Renderer::new(args, scene, checkpoint)));
 
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
-    event_loop.run_app(app)?;
+    event_loop.run_app(app).context(EventLoopSnafu)?;
 
     Ok(())
 }
@@ -2,8 +2,9 @@ pub use core::f32;
 pub use std::{sync::Arc, time::Instant};
 
 pub use bytemuck::{Pod, Zeroable};
+pub use fluidsim_core::{Backend, InitialConditions};
 pub use glam::UVec2;
-pub use gpu_shared::{ARRAY_LEN, MouseState, Settings as SimSettings};
+pub use gpu_shared::Settings as SimSettings;
 pub use snafu::{Location, prelude::*};
 use winit::dpi::PhysicalSize;
 
@@ -1,4 +1,4 @@
-use wgpu::PowerPreference;
+use fluidsim_core::device::{self, DeviceError};
 use winit::{dpi::PhysicalSize, window::Window};
 
 use crate::prelude::*;
@@ -12,16 +12,9 @@ pub enum GraphicsInitError {
         location: Location,
     },
 
-    #[snafu(display("At {location}: wgpu: failed to request adapter\n{source}"))]
-    RequestAdapter {
-        source: wgpu::RequestAdapterError,
-        #[snafu(implicit)]
-        location: Location,
-    },
-
-    #[snafu(display("At {location}: wgpu: failed to request device\n{source}"))]
-    RequestDevice {
-        source: wgpu::RequestDeviceError,
+    #[snafu(display("At {location}: failed to create device\n{source}"))]
+    Device {
+        source: DeviceError,
         #[snafu(implicit)]
         location: Location,
     },
@@ -45,18 +38,14 @@ pub struct GraphicsContext {
 }
 
 impl GraphicsContext {
-    pub async fn new(window: Window, screen: UVec2) -> Result<Self, GraphicsInitError> {
+    pub async fn new(
+        window: Window,
+        screen: UVec2,
+        adapter: Option<&str>,
+    ) -> Result<Self, GraphicsInitError> {
         info!("Initializing renderer");
 
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            #[cfg(target_os = "linux")]
-            backends: wgpu::Backends::VULKAN,
-            #[cfg(target_os = "windows")]
-            backends: wgpu::Backends::DX12 | wgpu::Backends::VULKAN,
-            #[cfg(target_os = "macos")]
-            backends: wgpu::Backends::METAL,
-            ..wgpu::InstanceDescriptor::new_without_display_handle_from_env()
-        });
+        let instance = device::instance();
 
         let window = Arc::new(window);
 
@@ -67,27 +56,9 @@ impl GraphicsContext {
             .create_surface(Arc::clone(&window))
             .context(CreateSurfaceSnafu)?;
 
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptionsBase {
-                power_preference: PowerPreference::None,
-                force_fallback_adapter: false,
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .context(RequestAdapterSnafu)?;
-
-        let (device, queue) = adapter
-            .request_device(&wgpu::DeviceDescriptor {
-                label: None,
-                required_features: wgpu::Features::TIMESTAMP_QUERY
-                    | wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS,
-                required_limits: wgpu::Limits::default(),
-                memory_hints: wgpu::MemoryHints::default(),
-                experimental_features: wgpu::ExperimentalFeatures::disabled(),
-                trace: wgpu::Trace::Off,
-            })
+        let (adapter, device, queue) = device::request_device(&instance, Some(&surface), adapter)
             .await
-            .context(RequestDeviceSnafu)?;
+            .context(DeviceSnafu)?;
 
         let caps = surface.get_capabilities(&adapter);
         let selected_fmt = [wgpu::TextureFormat::Bgra8Unorm];
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, iter, time::Instant};
+use std::{collections::HashMap, iter, mem, time::Instant};
 
 use glam::{Vec2, vec2};
 use winit::{
@@ -13,6 +13,7 @@ pub(crate) struct InputProcessor {
     mouse_pos: Vec2,
     lmb: bool,
     rmb: bool,
+    mmb: bool,
 }
 
 pub(crate) enum HumanInput {
@@ -25,6 +26,8 @@ pub(crate) enum HumanInput {
         position: Vec2,
         lmb: bool,
         rmb: bool,
+        /// How far the cursor moved with the middle button held
+        drag: Vec2,
     },
 }
 
@@ -60,12 +63,17 @@ impl InputProcessor {
                 device_id: _,
                 position: pos,
             } => {
-                self.mouse_pos = vec2(pos.x as f32, pos.y as f32);
+                let last = mem::replace(&mut self.mouse_pos, vec2(pos.x as f32, pos.y as f32));
 
                 HumanInput::Mouse {
                     position: self.mouse_pos,
                     lmb: self.lmb,
                     rmb: self.rmb,
+                    drag: if self.mmb {
+                        self.mouse_pos - last
+                    } else {
+                        Vec2::ZERO
+                    },
                 }
             }
             WindowEvent::MouseInput {
@@ -76,6 +84,7 @@ impl InputProcessor {
                 match button {
                     MouseButton::Left => self.lmb = state.is_pressed(),
                     MouseButton::Right => self.rmb = state.is_pressed(),
+                    MouseButton::Middle => self.mmb = state.is_pressed(),
                     _ => return HumanInput::None,
                 }
 
@@ -83,6 +92,7 @@ impl InputProcessor {
                     position: self.mouse_pos,
                     lmb: self.lmb,
                     rmb: self.rmb,
+                    drag: Vec2::ZERO,
                 }
             }
             _ if self.keys.is_empty() => HumanInput::None,
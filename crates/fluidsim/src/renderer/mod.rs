@@ -1,4 +1,3 @@
-mod buffers;
 mod egui;
 mod graphics;
 mod input;
@@ -7,10 +6,17 @@ mod shader;
 mod state;
 mod text;
 
-use glam::{Quat, Vec3};
+use std::path::Path;
+
+use fluidsim_core::{
+    Scene, checkpoint::Checkpoint, export::Exporter, mesh::MeshError, physics::PhysicsShader,
+    profiler::Profiler,
+};
+use glam::{Quat, Vec2, Vec3, vec3};
 use wgpu::CurrentSurfaceTexture;
 use winit::{
     application::ApplicationHandler,
+    dpi::PhysicalSize,
     event::WindowEvent,
     event_loop::ActiveEventLoop,
     keyboard::KeyCode,
@@ -18,13 +24,14 @@ use winit::{
 };
 
 use crate::{
+    cli::Args,
     prelude::*,
     renderer::{
         egui::UiRenderer,
         graphics::{GraphicsContext, GraphicsInitError},
         input::{HumanInput, InputProcessor},
         panel::Panel,
-        shader::{circles::CircleShader, lines::LineShader, physics::PhysicsShader},
+        shader::{circles::CircleShader, lines::LineShader},
         state::SimulationState,
         text::PerformanceDisplay,
     },
@@ -61,6 +68,13 @@ pub(crate) enum RendererInitError {
         #[snafu(implicit)]
         location: Location,
     },
+
+    #[snafu(display("At {location}: failed to lay out the scene\n{source}"))]
+    Reset {
+        source: MeshError,
+        #[snafu(implicit)]
+        location: Location,
+    },
 }
 
 #[derive(Debug, Snafu)]
@@ -92,6 +106,7 @@ pub(crate) struct RendererInit {
     perf: PerformanceDisplay,
     input: InputProcessor,
     state: SimulationState,
+    exporter: Option<Exporter>,
 }
 
 impl RendererInit {
@@ -138,26 +153,61 @@ impl RendererInit {
         });
 
         let framesteps = self.state.gfx.steps_per_frame;
-        let dtime = self.state.dtime() / framesteps as f32;
+        let dtime = self.state.dtime();
+        let screen = self.ctx.window.inner_size().to_uvec2();
+        let globals = self.circle.update_globals(queue, &self.state, screen);
+
+        // paused frames still upload settings, but don't count as steps
+        let from = self.state.step;
+        let steps = if dtime > 0.0 {
+            let steps = self.state.take_steps(framesteps);
+            self.state.step += u64::from(steps);
+            steps
+        } else {
+            framesteps
+        };
+
+        self.physics.set_colors(&self.state.colors);
+        self.physics.set_materials(&self.state.init.materials);
+        self.physics.set_obstacles(&self.state.init.obstacles);
 
-        for _ in 0..framesteps {
-            self.physics.update(queue, &mut encoder, dtime);
+        for _ in 0..steps {
+            self.physics
+                .update(device, queue, &mut encoder, dtime, globals);
+        }
+
+        self.physics.sync(queue, &mut encoder);
+        self.lines.follow(
+            device,
+            self.physics.udata.settings(),
+            &self.state.init.obstacles,
+        );
+
+        if let Some(exporter) = &mut self.exporter
+            && exporter.due(from, self.state.step)
+        {
+            exporter.capture(device, &mut encoder, &self.physics, self.state.step);
         }
 
         // draw particles
+        self.circle.rebind(device, self.physics.buffers());
         self.circle.draw(
-            &self.ctx,
             &mut encoder,
             &surface_view,
-            &self.state,
             &self.physics.udata,
             &self.lines,
-            self.ctx.window.inner_size().to_uvec2(),
         );
 
         // draw fps counter
+        let timings = self.physics.profiler().and_then(Profiler::average);
         self.perf
-            .render(&self.ctx, &mut encoder, &surface_view, &self.state)
+            .render(
+                &self.ctx,
+                &mut encoder,
+                &surface_view,
+                &self.state,
+                timings.as_ref(),
+            )
             .context(PerformanceDisplaySnafu)?;
 
         // draw egui
@@ -173,30 +223,85 @@ impl RendererInit {
             ),
         );
 
+        if let Some(profiler) = self.physics.profiler_mut() {
+            profiler.resolve(&mut encoder);
+        }
         queue.submit(Some(encoder.finish()));
         surface_tex.present();
 
+        if let Some(exporter) = &mut self.exporter {
+            exporter.submitted();
+        }
+        if let Some(profiler) = self.physics.profiler_mut() {
+            profiler.submitted();
+        }
+        self.physics.iterations_mut().submitted();
+
         device.poll(wgpu::PollType::Poll).context(PollSnafu)?;
 
+        if let Some(exporter) = &mut self.exporter {
+            exporter.collect();
+        }
+        if let Some(profiler) = self.physics.profiler_mut() {
+            profiler.collect();
+        }
+        self.physics.iterations_mut().collect();
+
         Ok(())
     }
 }
 
+/// Snapshots the simulation along with the camera, logging any failure.
+pub(crate) fn save_checkpoint(
+    ctx: &GraphicsContext,
+    physics: &PhysicsShader,
+    state: &SimulationState,
+    path: &Path,
+) {
+    let mut checkpoint = match physics.checkpoint(&ctx.device, &ctx.queue) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            error!("Failed to read back particles: {e}");
+            return;
+        }
+    };
+
+    checkpoint.camera = Some(state.player.into());
+    if let Err(e) = checkpoint.save(path) {
+        error!("Failed to save checkpoint: {e}");
+    }
+}
+
 pub(crate) enum Renderer {
-    Uninit,
+    /// Holds the startup options until the window exists
+    Uninit {
+        args: Args,
+        scene: Scene,
+        checkpoint: Option<Checkpoint>,
+    },
     Init(RendererInit),
 }
 
 impl Renderer {
-    pub(crate) fn new() -> Self {
-        Self::Uninit
+    pub(crate) fn new(args: Args, scene: Scene, checkpoint: Option<Checkpoint>) -> Self {
+        Self::Uninit {
+            args,
+            scene,
+            checkpoint,
+        }
     }
 
-    async fn init(&mut self, window: Window) -> Result<(), RendererInitError> {
+    async fn init(
+        &mut self,
+        window: Window,
+        args: &Args,
+        scene: Scene,
+        checkpoint: Option<Checkpoint>,
+    ) -> Result<(), RendererInitError> {
         let size = window.inner_size().to_uvec2();
 
         let panel = Panel::default();
-        let ctx = GraphicsContext::new(window, size)
+        let ctx = GraphicsContext::new(window, size, args.adapter.as_deref())
             .await
             .context(GraphicsInitSnafu)?;
 
@@ -204,9 +309,27 @@ impl Renderer {
         let vs = CircleShader::new(&ctx, phyiscs.buffers(), size);
         let ui = UiRenderer::new(&ctx);
         let perf = PerformanceDisplay::new(&ctx);
-        let mut state = SimulationState::new();
+        let mut state = SimulationState::new(args, scene.init, scene.colors, scene.fixed_dtime);
+
+        *phyiscs.lease_panel() = scene.settings;
+        phyiscs
+            .reset(&ctx.device, &ctx.queue, &state.init, state.gfx.backend)
+            .context(ResetSnafu)?;
+
+        if let Some(checkpoint) = checkpoint
+            && phyiscs
+                .restore(&ctx.device, &ctx.queue, &checkpoint, state.gfx.backend)
+                .inspect_err(|e| error!("{e}"))
+                .is_ok()
+        {
+            if let Some(camera) = checkpoint.camera {
+                state.player = camera.into();
+            }
 
-        phyiscs.reset(&ctx, &mut state);
+            state.init.box_size = checkpoint.settings.box_size;
+            state.init.box_quat = checkpoint.settings.box_quat;
+            state.init.periodic = checkpoint.settings.periodic_axes();
+        }
 
         *self = Self::Init(RendererInit {
             physics: phyiscs,
@@ -216,6 +339,7 @@ impl Renderer {
                 vs.globals_buf(),
                 state.init.box_size,
                 state.init.box_quat,
+                &state.init.obstacles,
             ),
             circle: vs,
             ctx,
@@ -224,6 +348,9 @@ impl Renderer {
             panel,
             input: InputProcessor::default(),
             state,
+            exporter: args
+                .export
+                .map(|format| Exporter::new(format, &args.output, args.export_every)),
         });
 
         Ok(())
@@ -232,8 +359,10 @@ impl Renderer {
 
 const TRANSLATE_STRENGTH: f32 = 0.175;
 const ROTATE_STRENGTH: f32 = 0.025;
+const DRAG_STRENGTH: f32 = 0.01;
 
 impl ApplicationHandler for Renderer {
+    #[allow(clippy::too_many_lines)]
     fn window_event(&mut self, event_loop: &ActiveEventLoop, id: WindowId, event: WindowEvent) {
         let Self::Init(this) = self else {
             return;
@@ -257,10 +386,28 @@ impl ApplicationHandler for Renderer {
                         }
                         KeyCode::Space => this.state.time.toggle(),
                         KeyCode::ArrowRight => this.state.time.step(),
-                        KeyCode::KeyR => this.physics.reset(&this.ctx, &mut this.state),
+                        KeyCode::KeyR => {
+                            let state = &mut this.state;
+                            if let Err(e) = this.physics.reset(
+                                &this.ctx.device,
+                                &this.ctx.queue,
+                                &state.init,
+                                state.gfx.backend,
+                            ) {
+                                error!("{e}");
+                            }
+                            state.time.pause();
+                        }
                         KeyCode::KeyC => this.panel.toggle_self(),
                         KeyCode::KeyH => this.panel.toggle_help(),
-                        KeyCode::KeyP => this.perf.toggle(),
+                        KeyCode::KeyP => {
+                            this.perf.toggle();
+                            this.physics.set_profiling(
+                                &this.ctx.device,
+                                &this.ctx.queue,
+                                this.perf.show,
+                            );
+                        }
                         _ => {}
                     }
                 }
@@ -309,8 +456,19 @@ impl ApplicationHandler for Renderer {
                     }
                 }
             }
-            HumanInput::Mouse { position, lmb, rmb } => {
+            HumanInput::Mouse {
+                position,
+                lmb,
+                rmb,
+                drag,
+            } => {
                 this.physics.set_mouse(position, lmb, rmb);
+
+                // across the screen, as the camera sees it
+                if drag != Vec2::ZERO {
+                    let offset = this.state.player.q * vec3(drag.x, -drag.y, 0.0);
+                    this.physics.drag_box(offset * DRAG_STRENGTH);
+                }
             }
             HumanInput::None => {}
         }
@@ -325,6 +483,13 @@ impl ApplicationHandler for Renderer {
                     error!("Error during draw: {e}");
                     return;
                 }
+
+                if this.state.finished() {
+                    info!("Ran all requested steps, quitting!");
+                    event_loop.exit();
+                    return;
+                }
+
                 this.ctx.window.request_redraw();
                 this.perf.update();
             }
@@ -338,16 +503,49 @@ impl ApplicationHandler for Renderer {
     }
 
     fn resumed(&mut self, ev: &ActiveEventLoop) {
+        let Self::Uninit {
+            args,
+            scene,
+            checkpoint,
+        } = self
+        else {
+            return;
+        };
+
+        let (args, scene, checkpoint) = (args.clone(), scene.clone(), checkpoint.take());
+
         // rust iife lol
         (|| {
-            let win = ev
-                .create_window(Window::default_attributes().with_title("fluidsim"))
-                .context(WindowCreateSnafu)?;
-            win.set_fullscreen(Some(Fullscreen::Borderless(None)));
-            pollster::block_on(self.init(win)).context(RendererInitSnafu)?;
+            let mut attrs = Window::default_attributes().with_title("fluidsim");
+            if args.windowed {
+                attrs = attrs.with_inner_size(PhysicalSize::new(args.size.x, args.size.y));
+            }
+
+            let win = ev.create_window(attrs).context(WindowCreateSnafu)?;
+            if !args.windowed {
+                win.set_fullscreen(Some(Fullscreen::Borderless(None)));
+            }
+
+            pollster::block_on(self.init(win, &args, scene, checkpoint))
+                .context(RendererInitSnafu)?;
 
             Ok::<_, ResumeError>(())
         })()
         .unwrap();
     }
+
+    fn exiting(&mut self, _: &ActiveEventLoop) {
+        let Self::Init(this) = self else {
+            return;
+        };
+
+        if let Some(exporter) = &mut this.exporter {
+            exporter.finish(&this.ctx.device);
+        }
+
+        if let Some(path) = &this.state.save_checkpoint {
+            let path = this.state.output.join(path);
+            save_checkpoint(&this.ctx, &this.physics, &this.state, &path);
+        }
+    }
 }
@@ -1,13 +1,20 @@
-use egui::{Button, RichText, Slider};
-use glam::Quat;
+use egui::{Button, ComboBox, DragValue, RichText, Slider};
+use fluidsim_core::{
+    Obstacle, Scene,
+    checkpoint::Checkpoint,
+    colors::{ColorMode, ColorSettings, ColorStop, Colormap},
+    materials::MaterialSettings,
+    obstacles::Shape,
+    physics::PhysicsShader,
+};
+use glam::{Quat, Vec3};
+use gpu_shared::{
+    colors::MAX_STOPS, kernel, materials::MAX_PHASES, obstacles::MAX_OBSTACLES, rheology, solver,
+};
 
 use crate::{
     prelude::*,
-    renderer::{
-        graphics::GraphicsContext,
-        shader::{lines::LineShader, physics::PhysicsShader},
-        state::SimulationState,
-    },
+    renderer::{graphics::GraphicsContext, shader::lines::LineShader, state::SimulationState},
 };
 
 const TEXT_SIZE: f32 = 16.0;
@@ -15,6 +22,8 @@ const TEXT_SIZE: f32 = 16.0;
 pub struct Panel {
     show: bool,
     show_help: bool,
+    scene_path: String,
+    checkpoint_path: String,
 }
 
 impl Default for Panel {
@@ -22,6 +31,8 @@ impl Default for Panel {
         Self {
             show: true,
             show_help: true,
+            scene_path: String::from("scene.ron"),
+            checkpoint_path: String::from("checkpoint.bin"),
         }
     }
 }
@@ -36,20 +47,44 @@ fn degrees(rad: &mut f32) -> impl FnMut(Option<f64>) -> f64 + '_ {
     }
 }
 
+fn millis(secs: &mut f32) -> impl FnMut(Option<f64>) -> f64 + '_ {
+    move |v| {
+        if let Some(v) = v {
+            *secs = (v / 1000.0) as f32;
+        }
+
+        f64::from(*secs * 1000.0)
+    }
+}
+
+fn percent(fraction: &mut f32) -> impl FnMut(Option<f64>) -> f64 + '_ {
+    move |v| {
+        if let Some(v) = v {
+            *fraction = (v / 100.0) as f32;
+        }
+
+        f64::from(*fraction * 100.0)
+    }
+}
+
 impl Panel {
     #[allow(clippy::too_many_lines)]
     pub fn update<'a>(
-        &'a self,
+        &'a mut self,
         ctx: &'a GraphicsContext,
         state: &'a mut SimulationState,
         physics: &'a mut PhysicsShader,
         lines: &'a mut LineShader,
     ) -> impl FnMut(&mut egui::Ui) + 'a {
         |ui: &mut egui::Ui| {
+            let iterations = physics.iterations();
             let settings = physics.lease_panel();
 
             let mut reset = false;
             let mut reline = false;
+            let mut redraw = false;
+            let mut save = None;
+            let mut restore = None;
 
             if !self.show {
                 return;
@@ -67,11 +102,44 @@ impl Panel {
                 ui.add(Slider::new(&mut state.gfx.steps_per_frame, 1..=5).text("Steps per Frame"))
                     .changed();
 
+                let mut fixed = state.fixed_dtime.is_some();
+                if ui.checkbox(&mut fixed, "Fixed Time Step").changed() {
+                    state.fixed_dtime = fixed.then_some(state.gfx.step_time / 1000.0);
+                }
+
+                if let Some(fixed) = &mut state.fixed_dtime {
+                    ui.add(Slider::from_get_set(0.1..=20.0, millis(fixed)).text("Fixed Step (ms)"));
+                }
+
+                let mut adaptive = settings.adaptive_timestep != 0;
+                if ui.checkbox(&mut adaptive, "Adaptive Time Step").changed() {
+                    settings.adaptive_timestep = u32::from(adaptive);
+                }
+
+                if adaptive {
+                    ui.add(Slider::new(&mut settings.cfl_number, 0.05..=1.0).text("CFL Number"));
+                }
+
                 ui.add(
                     Slider::from_get_set(20.0..=120.0, degrees(&mut state.player.fov)).text("FoV"),
                 )
                 .changed();
 
+                ui.horizontal(|ui| {
+                    ui.label("Backend");
+                    reset |= ui
+                        .radio_value(&mut state.gfx.backend, Backend::Gpu, "GPU")
+                        .changed();
+                    reset |= ui
+                        .radio_value(&mut state.gfx.backend, Backend::Cpu, "CPU")
+                        .changed();
+                });
+
+                ui.add_space(25.0);
+                ui.label(RichText::new("Colors").size(TEXT_SIZE).strong());
+
+                Self::colors(ui, &mut state.colors, settings);
+
                 ui.add_space(25.0);
                 ui.label(RichText::new("Physics Settings").size(TEXT_SIZE).strong());
 
@@ -94,7 +162,22 @@ impl Panel {
                 );
 
                 ui.add_space(25.0);
-                ui.label(RichText::new("SPH Settings").size(TEXT_SIZE).strong());
+                ui.label(RichText::new("Fluid Settings").size(TEXT_SIZE).strong());
+
+                ui.horizontal(|ui| {
+                    ui.label("Solver");
+                    ui.radio_value(&mut settings.solver, solver::SPH, "SPH");
+                    ui.radio_value(&mut settings.solver, solver::PBF, "PBF");
+                    ui.radio_value(&mut settings.solver, solver::DFSPH, "DFSPH");
+                });
+
+                Self::pick(
+                    ui,
+                    "Kernel",
+                    &mut settings.kernel,
+                    &kernel::ALL,
+                    kernel::name,
+                );
 
                 ui.add(
                     Slider::new(&mut settings.smoothing_radius, 0.01..=4.0)
@@ -108,21 +191,123 @@ impl Panel {
                     )
                     .changed();
 
+                if settings.solver == solver::PBF {
+                    ui.add(
+                        Slider::new(&mut settings.solver_iterations, 1..=16)
+                            .text("Solver Iterations"),
+                    );
+
+                    ui.add(
+                        Slider::new(&mut settings.relaxation, 1.0..=1000.0)
+                            .logarithmic(true)
+                            .text("Relaxation"),
+                    );
+
+                    ui.add(
+                        Slider::new(&mut settings.tensile_strength, 0.0..=0.1)
+                            .text("Tensile Strength"),
+                    );
+                } else if settings.solver == solver::DFSPH {
+                    ui.add(
+                        Slider::from_get_set(0.01..=5.0, percent(&mut settings.density_tolerance))
+                            .logarithmic(true)
+                            .text("Density Tolerance (%)"),
+                    );
+
+                    ui.add(
+                        Slider::from_get_set(
+                            0.01..=5.0,
+                            percent(&mut settings.divergence_tolerance),
+                        )
+                        .logarithmic(true)
+                        .text("Divergence Tolerance (%)"),
+                    );
+
+                    ui.add(
+                        Slider::new(&mut settings.max_density_iterations, 1..=64)
+                            .text("Max Density Iterations"),
+                    );
+
+                    ui.add(
+                        Slider::new(&mut settings.max_divergence_iterations, 1..=64)
+                            .text("Max Divergence Iterations"),
+                    );
+
+                    if let Some(iterations) = iterations {
+                        ui.label(format!(
+                            "Iterations: density {}, divergence {}",
+                            iterations.density, iterations.divergence
+                        ));
+                    }
+                } else {
+                    ui.add(
+                        Slider::new(&mut settings.pressure_multiplier, 1.0..=700.0)
+                            .text("Pressure Multiplier"),
+                    );
+
+                    ui.add(
+                        Slider::new(&mut settings.near_pressure_multiplier, 0.0..=50.0)
+                            .text("Near Pressure Multiplier"),
+                    );
+                }
+
                 ui.add(
-                    Slider::new(&mut settings.pressure_multiplier, 1.0..=700.0)
-                        .text("Pressure Multiplier"),
+                    Slider::new(&mut settings.viscosity_strength, 0.0..=1.0)
+                        .text("Viscosity Strength"),
                 );
 
-                ui.add(
-                    Slider::new(&mut settings.near_pressure_multiplier, 0.0..=50.0)
-                        .text("Near Pressure Multiplier"),
+                Self::pick(
+                    ui,
+                    "Viscosity Kernel",
+                    &mut settings.viscosity_kernel,
+                    &kernel::ALL,
+                    kernel::name,
                 );
 
+                Self::pick(
+                    ui,
+                    "Viscosity Model",
+                    &mut settings.viscosity_model,
+                    &rheology::ALL,
+                    rheology::name,
+                );
+
+                let model = settings.viscosity_model;
+                if model == rheology::POWER_LAW || model == rheology::CARREAU {
+                    ui.add(Slider::new(&mut settings.flow_index, 0.1..=2.0).text("Flow Index"));
+                }
+
+                if model == rheology::CARREAU {
+                    ui.add(
+                        Slider::new(&mut settings.shear_time, 0.01..=10.0)
+                            .logarithmic(true)
+                            .text("Shear Time (s)"),
+                    );
+
+                    ui.add(
+                        Slider::new(&mut settings.infinite_viscosity, 0.0..=1.0)
+                            .text("Infinite Shear Viscosity"),
+                    );
+                } else if model == rheology::BINGHAM {
+                    ui.add(
+                        Slider::new(&mut settings.yield_stress, 0.0..=10.0).text("Yield Stress"),
+                    );
+                }
+
+                if model != rheology::NEWTONIAN {
+                    ui.add(
+                        Slider::new(&mut settings.max_viscosity, 0.1..=10.0)
+                            .logarithmic(true)
+                            .text("Max Viscosity"),
+                    );
+                }
+
                 ui.add(
-                    Slider::new(&mut settings.viscosity_strength, 0.0..=1.0)
-                        .text("Viscosity Strength"),
+                    Slider::new(&mut settings.surface_tension, 0.0..=10.0).text("Surface Tension"),
                 );
 
+                Self::materials(ui, &mut state.init.materials);
+
                 ui.add_space(25.0);
                 ui.label(RichText::new("Mouse Settings").size(TEXT_SIZE).strong());
 
@@ -139,21 +324,23 @@ impl Panel {
                 ui.add_space(25.0);
                 ui.label(RichText::new("Initial Conditions").size(TEXT_SIZE).strong());
 
-                ui.collapsing("Particle Count", |ui| {
-                    reset |= ui
-                        .add(Slider::new(&mut state.init.particles.x, 1..=32).text("Particles X"))
-                        .changed();
+                if let Some(particles) = state.init.block_mut() {
+                    ui.collapsing("Particle Count", |ui| {
+                        reset |= ui
+                            .add(Slider::new(&mut particles.x, 1..=32).text("Particles X"))
+                            .changed();
 
-                    reset |= ui
-                        .add(Slider::new(&mut state.init.particles.y, 1..=32).text("Particles Y"))
-                        .changed();
+                        reset |= ui
+                            .add(Slider::new(&mut particles.y, 1..=32).text("Particles Y"))
+                            .changed();
 
-                    reset |= ui
-                        .add(Slider::new(&mut state.init.particles.z, 1..=32).text("Particles Z"))
-                        .changed();
+                        reset |= ui
+                            .add(Slider::new(&mut particles.z, 1..=32).text("Particles Z"))
+                            .changed();
 
-                    ui.add_space(5.0);
-                });
+                        ui.add_space(5.0);
+                    });
+                }
 
                 ui.collapsing("Boundary Size", |ui| {
                     reline |= ui
@@ -168,6 +355,19 @@ impl Panel {
                         .add(Slider::new(&mut state.init.box_size.z, 0.0..=16.0).text("Boundary Z"))
                         .changed();
 
+                    ui.horizontal(|ui| {
+                        let periodic = &mut state.init.periodic;
+                        reset |= ui.checkbox(&mut periodic.x, "Wrap X").changed();
+                        reset |= ui.checkbox(&mut periodic.y, "Wrap Y").changed();
+                        reset |= ui.checkbox(&mut periodic.z, "Wrap Z").changed();
+                    });
+
+                    reset |= ui
+                        .add(
+                            Slider::new(&mut state.init.boundary_layers, 1..=4).text("Wall Layers"),
+                        )
+                        .changed();
+
                     ui.add_space(5.0);
                 });
 
@@ -200,6 +400,8 @@ impl Panel {
                     ui.add_space(5.0);
                 });
 
+                redraw |= Self::obstacles(ui, &mut state.init.obstacles);
+
                 reset |= ui
                     .add(Slider::new(&mut state.init.gap, 0.0..=3.0).text("Initial Gap"))
                     .changed();
@@ -221,12 +423,65 @@ impl Panel {
                     }
                 }
 
+                ui.add_space(25.0);
+                ui.label(RichText::new("Scene").size(TEXT_SIZE).strong());
+
+                ui.text_edit_singleline(&mut self.scene_path);
+                ui.horizontal(|ui| {
+                    if ui.button("Load").clicked() {
+                        match Scene::load(state.output.join(&self.scene_path)) {
+                            Ok(scene) => {
+                                *settings = scene.settings;
+                                state.init = scene.init;
+                                state.colors = scene.colors;
+                                state.fixed_dtime = scene.fixed_dtime;
+                                reline = true;
+                            }
+                            Err(e) => error!("{e}"),
+                        }
+                    }
+
+                    if ui.button("Save").clicked() {
+                        let scene = Scene {
+                            fixed_dtime: state.fixed_dtime,
+                            settings: *settings,
+                            init: state.init.clone(),
+                            colors: state.colors.clone(),
+                        };
+
+                        if let Err(e) = scene.save(state.output.join(&self.scene_path)) {
+                            error!("{e}");
+                        }
+                    }
+                });
+
+                ui.add_space(25.0);
+                ui.label(RichText::new("Checkpoint").size(TEXT_SIZE).strong());
+
+                ui.text_edit_singleline(&mut self.checkpoint_path);
+                ui.horizontal(|ui| {
+                    let path = state.output.join(&self.checkpoint_path);
+
+                    if ui.button("Load").clicked() {
+                        match Checkpoint::load(&path) {
+                            Ok(checkpoint) => restore = Some(checkpoint),
+                            Err(e) => error!("{e}"),
+                        }
+                    }
+
+                    if ui.button("Save").clicked() {
+                        save = Some(path);
+                    }
+                });
+
                 if self.show_help {
                     ui.add_space(10.0);
                     ui.label("Press space to pause/play the simulation");
                     ui.label("Press the right arrow to step the simulation");
                     ui.label("Use WASD and the 2/8/4/6 numpad keys to move and rotate the camera");
                     ui.label("The red line is the X axis, green=Y, and blue=Z");
+                    ui.label("Left click to pull particles, right click to push them");
+                    ui.label("Drag with the middle button to move the box");
                     ui.label("Press 'R' to restart");
                     ui.label("Press 'C' to toggle this panel");
                     ui.label("Press 'H' to toggle this help text");
@@ -238,15 +493,314 @@ impl Panel {
             }
 
             if reset || reline {
-                physics.reset(ctx, state);
+                if let Err(e) =
+                    physics.reset(&ctx.device, &ctx.queue, &state.init, state.gfx.backend)
+                {
+                    error!("{e}");
+                }
+                state.time.pause();
+            }
+
+            // after any reset, so the restored particles aren't replaced
+            if let Some(checkpoint) = restore
+                && physics
+                    .restore(&ctx.device, &ctx.queue, &checkpoint, state.gfx.backend)
+                    .inspect_err(|e| error!("{e}"))
+                    .is_ok()
+            {
+                if let Some(camera) = checkpoint.camera {
+                    state.player = camera.into();
+                }
+
+                state.init.box_size = checkpoint.settings.box_size;
+                state.init.box_quat = checkpoint.settings.box_quat;
+                state.init.periodic = checkpoint.settings.periodic_axes();
+                state.time.pause();
+                reline = true;
+            }
+
+            if let Some(path) = save {
+                super::save_checkpoint(ctx, physics, state, &path);
             }
 
-            if reline {
-                lines.rebuild(&ctx.device, state.init.box_size, state.init.box_quat);
+            // where the reset or restore left the box, which may be moving
+            if reline || redraw {
+                let settings = physics.udata.settings();
+                lines.rebuild(
+                    &ctx.device,
+                    settings.box_size,
+                    settings.box_position,
+                    settings.box_quat,
+                    &state.init.obstacles,
+                );
             }
         }
     }
 
+    fn colors(ui: &mut egui::Ui, colors: &mut ColorSettings, settings: &SimSettings) {
+        let mut mode = colors.mode;
+        ComboBox::from_label("Color By")
+            .selected_text(mode.to_string())
+            .show_ui(ui, |ui| {
+                for m in ColorMode::ALL {
+                    ui.selectable_value(&mut mode, m, m.to_string());
+                }
+            });
+
+        if mode != colors.mode {
+            colors.set_mode(mode, settings);
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Range");
+            ui.add(DragValue::new(&mut colors.min).speed(0.1));
+            ui.add(DragValue::new(&mut colors.max).speed(0.1));
+
+            if ui.button("Reset").clicked() {
+                colors.set_mode(colors.mode, settings);
+            }
+        });
+
+        ComboBox::from_label("Colormap")
+            .selected_text(colors.map.to_string())
+            .show_ui(ui, |ui| {
+                for map in Colormap::ALL {
+                    ui.selectable_value(&mut colors.map, map, map.to_string());
+                }
+            });
+
+        if colors.map != Colormap::Custom {
+            return;
+        }
+
+        ui.collapsing("Color Stops", |ui| {
+            let mut remove = None;
+
+            for (i, stop) in colors.custom.iter_mut().enumerate() {
+                ui.push_id(i, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.color_edit_button_rgb(&mut stop.color);
+                        ui.add(
+                            DragValue::new(&mut stop.position)
+                                .speed(0.01)
+                                .range(0.0..=1.0),
+                        );
+
+                        if ui.small_button("Remove").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+                });
+            }
+
+            if let Some(i) = remove
+                && colors.custom.len() > 1
+            {
+                colors.custom.remove(i);
+            }
+
+            if colors.custom.len() < MAX_STOPS && ui.button("Add Stop").clicked() {
+                let last = colors.custom.last().copied();
+                colors.custom.push(ColorStop {
+                    position: 1.0,
+                    color: last.map_or([1.0; 3], |s| s.color),
+                });
+            }
+
+            ui.add_space(5.0);
+        });
+    }
+
+    /// Edits the phases after 0, which the sliders above describe.
+    // a combo box over the `u32` constants in `all`
+    fn pick(
+        ui: &mut egui::Ui,
+        label: &str,
+        selected: &mut u32,
+        all: &[u32],
+        name: fn(u32) -> &'static str,
+    ) {
+        ComboBox::from_label(label)
+            .selected_text(name(*selected))
+            .show_ui(ui, |ui| {
+                for &value in all {
+                    ui.selectable_value(selected, value, name(value));
+                }
+            });
+    }
+
+    fn materials(ui: &mut egui::Ui, materials: &mut Vec<MaterialSettings>) {
+        ui.collapsing("Materials", |ui| {
+            let mut remove = None;
+
+            for (i, material) in materials.iter_mut().enumerate() {
+                ui.push_id(i, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Phase {}", i + 1));
+                        ui.color_edit_button_rgb(&mut material.color);
+
+                        if ui.small_button("Remove").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+
+                    ui.add(
+                        Slider::new(&mut material.target_density, 1.0..=200.0)
+                            .text("Target Density"),
+                    );
+                    ui.add(
+                        Slider::new(&mut material.viscosity_strength, 0.0..=1.0)
+                            .text("Viscosity Strength"),
+                    );
+                    ui.add(
+                        Slider::new(&mut material.surface_tension, 0.0..=10.0)
+                            .text("Surface Tension"),
+                    );
+                });
+            }
+
+            if let Some(i) = remove {
+                materials.remove(i);
+            }
+
+            if materials.len() < MAX_PHASES - 1 && ui.button("Add Material").clicked() {
+                materials.push(MaterialSettings::default());
+            }
+
+            ui.add_space(5.0);
+        });
+    }
+
+    /// Edits the obstacles, which take effect without a reset. Returns
+    /// whether any changed. Meshes can be moved but not reshaped.
+    fn obstacles(ui: &mut egui::Ui, obstacles: &mut Vec<Obstacle>) -> bool {
+        const SHAPES: [&str; 4] = ["Sphere", "Capsule", "Box", "Cylinder"];
+
+        let name = |shape: &Shape| match shape {
+            Shape::Sphere { .. } => "Sphere",
+            Shape::Capsule { .. } => "Capsule",
+            Shape::Box { .. } => "Box",
+            Shape::Cylinder { .. } => "Cylinder",
+            Shape::Mesh { .. } => "Mesh",
+        };
+
+        let mut changed = false;
+
+        ui.collapsing("Obstacles", |ui| {
+            let mut remove = None;
+
+            for (i, obstacle) in obstacles.iter_mut().enumerate() {
+                ui.push_id(i, |ui| {
+                    ui.horizontal(|ui| {
+                        let mut shape = name(&obstacle.shape);
+                        if shape == "Mesh" {
+                            ui.label(format!("Obstacle {} (Mesh)", i + 1));
+                        } else {
+                            ComboBox::from_label(format!("Obstacle {}", i + 1))
+                                .selected_text(shape)
+                                .show_ui(ui, |ui| {
+                                    for s in SHAPES {
+                                        ui.selectable_value(&mut shape, s, s);
+                                    }
+                                });
+                        }
+
+                        if shape != name(&obstacle.shape) {
+                            obstacle.shape = match shape {
+                                "Capsule" => Shape::Capsule {
+                                    radius: 0.3,
+                                    length: 1.0,
+                                },
+                                "Box" => Shape::Box { size: Vec3::ONE },
+                                "Cylinder" => Shape::Cylinder {
+                                    radius: 0.5,
+                                    height: 1.0,
+                                },
+                                _ => Shape::Sphere { radius: 0.5 },
+                            };
+                            changed = true;
+                        }
+
+                        if ui.small_button("Remove").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.label("Center");
+                        for axis in 0..3 {
+                            changed |= ui
+                                .add(DragValue::new(&mut obstacle.center[axis]).speed(0.05))
+                                .changed();
+                        }
+                    });
+
+                    let (mut y, mut x, mut z) = obstacle.rotation.to_euler(glam::EulerRot::YXZEx);
+                    for (angle, text) in [
+                        (&mut x, "About X"),
+                        (&mut y, "About Y"),
+                        (&mut z, "About Z"),
+                    ] {
+                        changed |= ui
+                            .add(Slider::from_get_set(-180.0..=180.0, degrees(angle)).text(text))
+                            .changed();
+                    }
+                    obstacle.rotation = Quat::from_euler(glam::EulerRot::YXZEx, y, x, z);
+
+                    changed |= Self::shape(ui, &mut obstacle.shape);
+                });
+            }
+
+            if let Some(i) = remove {
+                obstacles.remove(i);
+                changed = true;
+            }
+
+            if obstacles.len() < MAX_OBSTACLES && ui.button("Add Obstacle").clicked() {
+                obstacles.push(Obstacle {
+                    shape: Shape::Sphere { radius: 0.5 },
+                    center: Vec3::ZERO,
+                    rotation: Quat::IDENTITY,
+                });
+                changed = true;
+            }
+
+            ui.add_space(5.0);
+        });
+
+        changed
+    }
+
+    /// Edits the dimensions of an obstacle. Returns whether any changed.
+    fn shape(ui: &mut egui::Ui, shape: &mut Shape) -> bool {
+        let mut changed = false;
+        let mut slider = |ui: &mut egui::Ui, value: &mut f32, text: &str| {
+            changed |= ui.add(Slider::new(value, 0.05..=8.0).text(text)).changed();
+        };
+
+        match shape {
+            Shape::Sphere { radius } => slider(ui, radius, "Radius"),
+            Shape::Capsule { radius, length } => {
+                slider(ui, radius, "Radius");
+                slider(ui, length, "Length");
+            }
+            Shape::Box { size } => {
+                slider(ui, &mut size.x, "Size X");
+                slider(ui, &mut size.y, "Size Y");
+                slider(ui, &mut size.z, "Size Z");
+            }
+            Shape::Cylinder { radius, height } => {
+                slider(ui, radius, "Radius");
+                slider(ui, height, "Height");
+            }
+            Shape::Mesh { faces, .. } => {
+                ui.label(format!("{} triangles", faces.len()));
+            }
+        }
+
+        changed
+    }
+
     pub fn toggle_help(&mut self) {
         self.show_help = !self.show_help;
     }
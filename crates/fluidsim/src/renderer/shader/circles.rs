@@ -1,17 +1,12 @@
+use fluidsim_core::{buffers::Buffers, physics::PhysicsUniformData};
 use wgpu::{BindGroupLayoutDescriptor, util::DeviceExt};
 
 use crate::{
     prelude::*,
-    renderer::{
-        buffers::Buffers,
-        graphics::GraphicsContext,
-        shader::{lines::LineShader, physics::PhysicsUniformData},
-        state::SimulationState,
-    },
+    renderer::{graphics::GraphicsContext, shader::lines::LineShader, state::SimulationState},
 };
 
 pub(crate) type VsGlobals = gpu_shared::Globals;
-pub(crate) type VsCirclePrimitive = gpu_shared::Primitive;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
@@ -29,10 +24,13 @@ impl VsInput {
 pub(crate) struct CircleShader {
     globals: VsGlobals,
 
-    globals_buf: wgpu::Buffer,
+    globals_buf: Arc<wgpu::Buffer>,
     index_buf: wgpu::Buffer,
     vertex_buf: wgpu::Buffer,
 
+    // the particle buffers are reallocated when the particle count changes
+    primitives: Arc<wgpu::Buffer>,
+    bind_group_layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
     pipeline: wgpu::RenderPipeline,
 
@@ -77,12 +75,8 @@ impl CircleShader {
             contents: bytemuck::cast_slice(&indices),
             usage: wgpu::BufferUsages::INDEX,
         });
-        let globals_buf = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("circle/buffer:globals"),
-            size: std::mem::size_of::<VsGlobals>() as u64,
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        // shared with the physics pipelines, which need it to ray-cast the mouse
+        let globals_buf = Arc::clone(&buffers.uniform.globals.buffer);
 
         let shader = super::shader_module(device);
 
@@ -112,33 +106,16 @@ impl CircleShader {
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new(
-                            buffers.drawing.primitives.buffer.size(),
-                        ),
+                        min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<
+                            gpu_shared::Primitive,
+                        >() as u64),
                     },
                     count: None,
                 },
             ],
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("circle/bindgroup"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: globals_buf.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: buffers.uniform.settings.buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: buffers.drawing.primitives.buffer.as_entire_binding(),
-                },
-            ],
-        });
+        let bind_group = Self::bind_group(device, &bind_group_layout, &globals_buf, buffers);
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("circle/pipeline_layout"),
@@ -210,10 +187,15 @@ impl CircleShader {
         });
 
         Self {
-            globals: VsGlobals::default(),
+            globals: VsGlobals {
+                resolution: screen,
+                ..VsGlobals::default()
+            },
             globals_buf,
             index_buf,
             vertex_buf,
+            primitives: Arc::clone(&buffers.drawing.primitives.buffer),
+            bind_group_layout,
             bind_group,
             pipeline,
             msaa_tex,
@@ -223,6 +205,45 @@ impl CircleShader {
         }
     }
 
+    fn bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        globals_buf: &wgpu::Buffer,
+        buffers: &Buffers,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("circle/bindgroup"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: globals_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: buffers.uniform.settings.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: buffers.drawing.primitives.buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    /// Rebinds the particle buffers if the physics shader has reallocated
+    /// them since the last frame.
+    pub(crate) fn rebind(&mut self, device: &wgpu::Device, buffers: &Buffers) {
+        let primitives = &buffers.drawing.primitives.buffer;
+        if Arc::ptr_eq(&self.primitives, primitives) {
+            return;
+        }
+
+        self.bind_group =
+            Self::bind_group(device, &self.bind_group_layout, &self.globals_buf, buffers);
+        self.primitives = Arc::clone(primitives);
+    }
+
     fn create_depth(device: &wgpu::Device, screen: UVec2) -> (wgpu::Texture, wgpu::TextureView) {
         let tex = device.create_texture(&wgpu::TextureDescriptor {
             label: Some("circle/texture:depth"),
@@ -267,23 +288,29 @@ impl CircleShader {
         (self.depth_tex, self.depth_view) = Self::create_depth(&ctx.device, screen);
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub(crate) fn draw(
+    /// Updates the camera matrices. Called before the physics step, since the
+    /// mouse interaction force ray-casts through them.
+    pub(crate) fn update_globals(
         &mut self,
-        ctx: &GraphicsContext,
-        encoder: &mut wgpu::CommandEncoder,
-        surface_view: &wgpu::TextureView,
+        queue: &wgpu::Queue,
         state: &SimulationState,
-        udata: &PhysicsUniformData,
-        lines: &LineShader,
         screen: UVec2,
-    ) {
+    ) -> &VsGlobals {
         self.globals.view = state.player.view_matrix();
         self.globals.projection = state.player.projection_matrix(screen);
 
-        ctx.queue
-            .write_buffer(&self.globals_buf, 0, bytemuck::cast_slice(&[self.globals]));
+        queue.write_buffer(&self.globals_buf, 0, bytemuck::cast_slice(&[self.globals]));
 
+        &self.globals
+    }
+
+    pub(crate) fn draw(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        surface_view: &wgpu::TextureView,
+        udata: &PhysicsUniformData,
+        lines: &LineShader,
+    ) {
         let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                 view: &self.msaa_view,
@@ -312,7 +339,7 @@ impl CircleShader {
         pass.draw_indexed(
             0..6,
             0,
-            udata.boundary_particles()..udata.num_particles(), // skip boundary
+            udata.boundary_particles()..udata.max_particles(), // skip boundary
         );
 
         lines.draw(&mut pass);
@@ -1,7 +1,8 @@
-use std::mem;
+use std::{f32::consts::TAU, mem};
 
+use fluidsim_core::{Obstacle, obstacles::Shape};
 use glam::{Quat, Vec3};
-use gpu_shared::LineVertex;
+use gpu_shared::{LineVertex, Settings};
 use wgpu::util::DeviceExt;
 
 pub struct LineShader {
@@ -9,6 +10,8 @@ pub struct LineShader {
     vertex_buf: wgpu::Buffer,
     globals_bind: wgpu::BindGroup,
     vertex_count: u32,
+    // where the simulation last had the box
+    followed: (Vec3, Quat),
 }
 
 fn axis_lines(len: f32) -> Vec<LineVertex> {
@@ -41,7 +44,7 @@ fn axis_lines(len: f32) -> Vec<LineVertex> {
     ]
 }
 
-fn box_lines(size: Vec3, rot: Quat) -> Vec<LineVertex> {
+fn box_lines(size: Vec3, position: Vec3, rot: Quat) -> Vec<LineVertex> {
     let c = [0.55f32, 0.55, 0.55];
 
     let corners = [
@@ -55,7 +58,10 @@ fn box_lines(size: Vec3, rot: Quat) -> Vec<LineVertex> {
         Vec3::new(0.0, size.y, size.z),
     ];
 
-    let corners: Vec<[f32; 3]> = corners.iter().map(|p| (rot * *p).to_array()).collect();
+    let corners: Vec<[f32; 3]> = corners
+        .iter()
+        .map(|p| (rot * *p + position).to_array())
+        .collect();
 
     let edges = [
         (0, 1),
@@ -89,6 +95,110 @@ fn box_lines(size: Vec3, rot: Quat) -> Vec<LineVertex> {
         .collect()
 }
 
+/// Segments of an arc of `radius` around `z`, from angle `from` to `to`.
+fn arc(radius: f32, from: f32, to: f32) -> impl Iterator<Item = [Vec3; 2]> {
+    const SEGMENTS: usize = 12;
+
+    let point = move |i: usize| {
+        let angle = from + (to - from) * i as f32 / SEGMENTS as f32;
+        let (sin, cos) = angle.sin_cos();
+        Vec3::new(cos * radius, sin * radius, 0.0)
+    };
+
+    (0..SEGMENTS).map(move |i| [point(i), point(i + 1)])
+}
+
+fn obstacle_lines(
+    obstacles: &[Obstacle],
+    size: Vec3,
+    position: Vec3,
+    rot: Quat,
+) -> Vec<LineVertex> {
+    let c = [0.8f32, 0.5, 0.3];
+    let mut segments = vec![];
+
+    // turn arcs around z to lie around y, or around x
+    let flat = Quat::from_rotation_x(TAU / 4.0);
+    let side = Quat::from_rotation_y(TAU / 4.0);
+    let turned = |q: Quat, s: [Vec3; 2], offset: Vec3| s.map(|p| q * p + offset);
+
+    for obstacle in obstacles {
+        let mut local = vec![];
+
+        match &obstacle.shape {
+            Shape::Sphere { radius } => {
+                for q in [Quat::IDENTITY, flat, side] {
+                    local.extend(arc(*radius, 0.0, TAU).map(|s| turned(q, s, Vec3::ZERO)));
+                }
+            }
+            Shape::Capsule {
+                radius,
+                length: height,
+            }
+            | Shape::Cylinder { radius, height } => {
+                let h = Vec3::Y * height / 2.0;
+                for end in [h, -h] {
+                    local.extend(arc(*radius, 0.0, TAU).map(|s| turned(flat, s, end)));
+                }
+                for q in [Quat::IDENTITY, side] {
+                    let (a, b) = (q * Vec3::X * *radius, q * Vec3::NEG_X * *radius);
+                    local.extend([[a - h, a + h], [b - h, b + h]]);
+                }
+
+                // the caps, as arcs over the top and under the bottom
+                if matches!(obstacle.shape, Shape::Capsule { .. }) {
+                    for q in [Quat::IDENTITY, side] {
+                        local.extend(arc(*radius, 0.0, TAU / 2.0).map(|s| turned(q, s, h)));
+                        local.extend(arc(*radius, TAU / 2.0, TAU).map(|s| turned(q, s, -h)));
+                    }
+                }
+            }
+            Shape::Box { size } => {
+                let half = *size / 2.0;
+                let corner = |i: usize| {
+                    Vec3::new(
+                        if i & 1 == 0 { -half.x } else { half.x },
+                        if i & 2 == 0 { -half.y } else { half.y },
+                        if i & 4 == 0 { -half.z } else { half.z },
+                    )
+                };
+
+                // corners one bit apart share an edge
+                for i in 0..8 {
+                    for bit in [1, 2, 4] {
+                        if i & bit == 0 {
+                            local.push([corner(i), corner(i | bit)]);
+                        }
+                    }
+                }
+            }
+            Shape::Mesh { vertices, faces } => {
+                for face in faces {
+                    let [a, b, c] = face.map(|i| vertices[i as usize]);
+                    local.extend([[a, b], [b, c], [c, a]]);
+                }
+            }
+        }
+
+        let center = size / 2.0 + obstacle.center;
+        segments.extend(
+            local
+                .into_iter()
+                .map(|s| s.map(|p| rot * (center + obstacle.rotation * p) + position)),
+        );
+    }
+
+    segments
+        .into_iter()
+        .flat_map(|s| {
+            s.map(|p| LineVertex {
+                position: p.to_array(),
+                color: c,
+            })
+        })
+        .collect()
+}
+
 impl LineShader {
     pub fn new(
         device: &wgpu::Device,
@@ -96,6 +206,7 @@ impl LineShader {
         globals_buf: &wgpu::Buffer,
         box_size: Vec3,
         box_quat: Quat,
+        obstacles: &[Obstacle],
     ) -> Self {
         // bind group layout: just globals
         let bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -173,7 +284,7 @@ impl LineShader {
             multiview_mask: None,
         });
 
-        let vertices = Self::build_vertices(box_size, box_quat);
+        let vertices = Self::build_vertices(box_size, Vec3::ZERO, box_quat, obstacles);
         let vertex_count = vertices.len() as u32;
         let vertex_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("lines/buffer:vertex"),
@@ -186,18 +297,32 @@ impl LineShader {
             vertex_buf,
             globals_bind,
             vertex_count,
+            followed: (Vec3::ZERO, box_quat),
         }
     }
 
-    fn build_vertices(box_size: Vec3, box_rot: Quat) -> Vec<LineVertex> {
+    fn build_vertices(
+        box_size: Vec3,
+        box_position: Vec3,
+        box_rot: Quat,
+        obstacles: &[Obstacle],
+    ) -> Vec<LineVertex> {
         let mut v = Vec::new();
-        v.extend(box_lines(box_size, box_rot));
+        v.extend(box_lines(box_size, box_position, box_rot));
+        v.extend(obstacle_lines(obstacles, box_size, box_position, box_rot));
         v.extend(axis_lines(box_size.x.min(box_size.y).min(box_size.z) * 0.5));
         v
     }
 
-    pub fn rebuild(&mut self, device: &wgpu::Device, box_size: Vec3, box_rot: Quat) {
-        let vertices = Self::build_vertices(box_size, box_rot);
+    pub fn rebuild(
+        &mut self,
+        device: &wgpu::Device,
+        box_size: Vec3,
+        box_position: Vec3,
+        box_rot: Quat,
+        obstacles: &[Obstacle],
+    ) {
+        let vertices = Self::build_vertices(box_size, box_position, box_rot, obstacles);
         self.vertex_count = vertices.len() as u32;
         self.vertex_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("lines/buffer:vertex"),
@@ -206,6 +331,15 @@ impl LineShader {
         });
     }
 
+    /// Rebuilds the lines when the simulation moved the box since the last
+    /// call, so they keep up with it while it runs.
+    pub fn follow(&mut self, device: &wgpu::Device, settings: &Settings, obstacles: &[Obstacle]) {
+        let pose = (settings.box_position, settings.box_quat);
+        if mem::replace(&mut self.followed, pose) != pose {
+            self.rebuild(device, settings.box_size, pose.0, pose.1, obstacles);
+        }
+    }
+
     pub fn draw<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>) {
         pass.set_pipeline(&self.pipeline);
         pass.set_bind_group(0, &self.globals_bind, &[]);
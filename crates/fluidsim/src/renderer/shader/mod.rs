@@ -1,15 +1,4 @@
-use std::sync::OnceLock;
-
-use wgpu::include_spirv;
+pub(crate) use fluidsim_core::shader_module;
 
 pub(super) mod circles;
 pub mod lines;
-pub mod physics;
-pub mod pipelines;
-
-pub(crate) fn shader_module(device: &wgpu::Device) -> &wgpu::ShaderModule {
-    const SHADER: wgpu::ShaderModuleDescriptor<'static> = include_spirv!(env!("physics.spv"));
-    static MODULE: OnceLock<wgpu::ShaderModule> = OnceLock::new();
-
-    MODULE.get_or_init(|| device.create_shader_module(SHADER))
-}
@@ -1,8 +1,9 @@
-use std::f32;
+use std::{f32, path::PathBuf};
 
+use fluidsim_core::{checkpoint::Camera, colors::ColorSettings};
 use glam::{Mat4, Quat, Vec3};
 
-use crate::prelude::*;
+use crate::{cli::Args, prelude::*};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum TimeState {
@@ -40,6 +41,26 @@ pub(crate) struct PlayerTransform {
     pub fov: f32, // vertical field of view in radians
 }
 
+impl From<PlayerTransform> for Camera {
+    fn from(player: PlayerTransform) -> Self {
+        Camera {
+            translate: player.translate,
+            fov: player.fov,
+            rotation: player.q,
+        }
+    }
+}
+
+impl From<Camera> for PlayerTransform {
+    fn from(camera: Camera) -> Self {
+        PlayerTransform {
+            translate: camera.translate,
+            q: camera.rotation,
+            fov: camera.fov,
+        }
+    }
+}
+
 impl PlayerTransform {
     pub(crate) fn view_matrix(&self) -> Mat4 {
         (Mat4::from_translation(self.translate) * Mat4::from_quat(self.q)).inverse()
@@ -61,15 +82,47 @@ pub(crate) struct SimulationState {
     pub(crate) time: TimeState,
     pub(crate) gfx: GraphicsSettings,
     pub(crate) init: InitialConditions,
+    pub(crate) colors: ColorSettings,
     pub(crate) player: PlayerTransform,
+    /// Seconds per step regardless of the frame time, for reproducible runs
+    pub(crate) fixed_dtime: Option<f32>,
+
+    /// Simulation steps run so far
+    pub(crate) step: u64,
+    /// Steps left before exiting, if limited from the command line
+    pub(crate) steps_left: Option<u64>,
+    /// Where scenes and other output are written
+    pub(crate) output: PathBuf,
+    /// Checkpoint to write on exit, relative to `output`
+    pub(crate) save_checkpoint: Option<PathBuf>,
 }
 
 impl SimulationState {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        args: &Args,
+        init: InitialConditions,
+        colors: ColorSettings,
+        fixed_dtime: Option<f32>,
+    ) -> Self {
+        let time = if args.run || args.steps.is_some() {
+            TimeState::Running(Instant::now())
+        } else {
+            TimeState::Paused
+        };
+
         Self {
-            gfx: GraphicsSettings::default(),
-            init: InitialConditions::default(),
-            time: TimeState::Paused,
+            gfx: GraphicsSettings {
+                backend: args.backend,
+                ..GraphicsSettings::default()
+            },
+            init,
+            colors,
+            fixed_dtime,
+            time,
+            step: 0,
+            steps_left: args.steps,
+            output: args.output.clone(),
+            save_checkpoint: args.save_checkpoint.clone(),
             player: PlayerTransform {
                 translate: Vec3::new(12.69, 5.29, 11.57),
                 q: Quat::from_xyzw(-0.05, 0.30, 0.00, 0.95),
@@ -78,19 +131,42 @@ impl SimulationState {
         }
     }
 
-    /// Calculates the change in time for this simulation frame.
+    /// Counts up to `n` steps against the step limit, returning how many may
+    /// run.
+    pub(crate) fn take_steps(&mut self, n: u32) -> u32 {
+        let Some(left) = &mut self.steps_left else {
+            return n;
+        };
+
+        let n = n.min(u32::try_from(*left).unwrap_or(u32::MAX));
+        *left -= u64::from(n);
+        n
+    }
+
+    pub(crate) fn finished(&self) -> bool {
+        self.steps_left == Some(0)
+    }
+
+    /// Calculates the change in time for each of this frame's steps.
     pub(crate) fn dtime(&mut self) -> f32 {
+        let steps = self.gfx.steps_per_frame as f32;
+
         match &mut self.time {
             TimeState::Running(prv) => {
                 let now = Instant::now();
                 let dtime = now.duration_since(*prv).as_secs_f64();
                 *prv = now;
-                (dtime as f32).min(1.0 / 60.0) * self.gfx.speed
+
+                match self.fixed_dtime {
+                    Some(fixed) => fixed,
+                    None => (dtime as f32).min(1.0 / 60.0) * self.gfx.speed / steps,
+                }
             }
             TimeState::Paused => 0.0,
             TimeState::StepRequested => {
                 self.time = TimeState::Paused;
-                self.gfx.step_time / 1000.0
+                self.fixed_dtime
+                    .unwrap_or(self.gfx.step_time / 1000.0 / steps)
             }
         }
     }
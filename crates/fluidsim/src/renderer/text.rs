@@ -1,3 +1,6 @@
+use std::fmt::Write;
+
+use fluidsim_core::pipelines::Timings;
 use glyphon::{
     Attrs, Buffer, Cache, Color, Family, FontSystem, Metrics, Resolution, SwashCache, TextArea,
     TextAtlas, TextBounds, TextRenderer, Viewport, Weight, cosmic_text::Align,
@@ -39,6 +42,7 @@ pub(crate) struct PerformanceDisplay {
     renderer: TextRenderer,
     buffer_fps: Buffer,
     buffer_xyzrpy: Buffer,
+    buffer_kernels: Buffer,
 
     pub(crate) show: bool,
 
@@ -65,6 +69,7 @@ impl PerformanceDisplay {
 
         let buffer_fps = Self::make_buffer(&mut font_system, size, scale);
         let buffer_xyzrpy = Self::make_buffer(&mut font_system, size, scale);
+        let buffer_kernels = Self::make_buffer(&mut font_system, size, scale);
 
         Self {
             font_system,
@@ -74,6 +79,7 @@ impl PerformanceDisplay {
             renderer,
             buffer_fps,
             buffer_xyzrpy,
+            buffer_kernels,
             show: false,
             timer: Instant::now(),
             frames: 0,
@@ -102,6 +108,7 @@ impl PerformanceDisplay {
         let (w, h) = (Some(size.x as f32 * scale), Some(size.y as f32 * scale));
         self.buffer_fps.set_size(&mut self.font_system, w, h);
         self.buffer_xyzrpy.set_size(&mut self.font_system, w, h);
+        self.buffer_kernels.set_size(&mut self.font_system, w, h);
         self.scale = scale;
     }
 
@@ -125,8 +132,9 @@ impl PerformanceDisplay {
         encoder: &mut CommandEncoder,
         view: &TextureView,
         state: &SimulationState,
+        timings: Option<&Timings>,
     ) -> Result<(), TextError> {
-        let fps_text = format!("FPS: {:.2}", self.fps);
+        let fps_text = format!("FPS: {:.2}\nStep: {}", self.fps, state.step);
 
         self.buffer_fps.set_text(
             &mut self.font_system,
@@ -163,6 +171,28 @@ impl PerformanceDisplay {
         self.buffer_xyzrpy
             .shape_until_scroll(&mut self.font_system, false);
 
+        let kernels_text = match timings {
+            Some(timings) => {
+                let mut text = String::new();
+                for (name, ms) in timings.entries() {
+                    _ = writeln!(text, "{name}: {ms:.3}ms");
+                }
+                _ = write!(text, "total: {:.3}ms", timings.total);
+                text
+            }
+            None => String::new(),
+        };
+
+        self.buffer_kernels.set_text(
+            &mut self.font_system,
+            &kernels_text,
+            &Self::attrs(),
+            glyphon::Shaping::Advanced,
+            Some(Align::Left),
+        );
+        self.buffer_kernels
+            .shape_until_scroll(&mut self.font_system, false);
+
         let UVec2 { x: w, y: h } = wgpu.window.inner_size().to_uvec2();
         let fps_lines = fps_text.lines().count() as f32;
         let xyz_lines = xyzrpy_text.lines().count() as f32;
@@ -182,6 +212,7 @@ impl PerformanceDisplay {
             renderer,
             buffer_fps,
             buffer_xyzrpy,
+            buffer_kernels,
             viewport,
             ..
         } = self;
@@ -208,6 +239,20 @@ impl PerformanceDisplay {
                         default_color: Color::rgb(255, 255, 255),
                         custom_glyphs: &[],
                     },
+                    TextArea {
+                        buffer: buffer_kernels,
+                        left: 10.0,
+                        top: 10.0,
+                        scale: 1.0,
+                        bounds: TextBounds {
+                            left: 0,
+                            top: 0,
+                            right: w as i32,
+                            bottom: h as i32,
+                        },
+                        default_color: Color::rgb(255, 255, 255),
+                        custom_glyphs: &[],
+                    },
                     TextArea {
                         buffer: buffer_xyzrpy,
                         left: w as f32 * (1.0 - self.scale) - 10.0,
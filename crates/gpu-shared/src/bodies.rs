@@ -0,0 +1,205 @@
+//! Rigid bodies that float and sink in the fluid. Each is sampled with
+//! boundary particles, which the fluid pushes against like the walls, and
+//! which push back on the body.
+
+#[cfg(not(target_arch = "spirv"))]
+use bytemuck::{Pod, Zeroable};
+use glam::{Quat, Vec3};
+#[cfg(target_arch = "spirv")]
+use spirv_std::{glam, num_traits::Float};
+
+use crate::Settings;
+
+pub const MAX_BODIES: usize = 64;
+
+/// Fixed-point scale of [`RigidBody::force`] and [`RigidBody::torque`], which
+/// are summed with integer atomics
+pub const FORCE_SCALE: f32 = 256.0;
+
+/// One body particle's share of a force or torque component, as the bits of
+/// an `i32`. Clamped so that one stray particle can't throw the body across
+/// the box, and so the share fits in the low word of a [`FixedSum`].
+pub fn fixed(value: f32) -> u32 {
+    (value.clamp(-1.0e5, 1.0e5) * FORCE_SCALE) as i32 as u32
+}
+
+/// A vector summed in fixed point with integer atomics, each component the
+/// low and high words of an `i64`, so a large body's particles can't
+/// overflow it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct FixedSum {
+    pub low: [u32; 3],
+    pub _pad: u32,
+    pub high: [u32; 3],
+    pub _pad1: u32,
+}
+
+impl FixedSum {
+    /// What to add to the high word after adding a [`fixed`] `share` to a
+    /// low word that was `before`: the share's sign, plus one if the low word
+    /// wrapped around.
+    pub fn carry(before: u32, share: u32) -> u32 {
+        let sign = if (share as i32) < 0 { u32::MAX } else { 0 };
+        let wrapped = if before.checked_add(share).is_none() {
+            1
+        } else {
+            0
+        };
+
+        sign.wrapping_add(wrapped)
+    }
+
+    /// Adds one particle's share of each component, on the cpu.
+    #[cfg(not(target_arch = "spirv"))]
+    pub fn add(&mut self, value: Vec3) {
+        for (i, value) in value.to_array().into_iter().enumerate() {
+            let share = fixed(value);
+            self.high[i] = self.high[i].wrapping_add(Self::carry(self.low[i], share));
+            self.low[i] = self.low[i].wrapping_add(share);
+        }
+    }
+
+    pub fn value(&self) -> Vec3 {
+        let word = |i: usize| self.high[i] as i32 as f32 * 4_294_967_296.0 + self.low[i] as f32;
+        Vec3::new(word(0), word(1), word(2)) / FORCE_SCALE
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct RigidBody {
+    /// Center of mass
+    pub position: Vec3,
+    /// Zero pins the body in place
+    pub inv_mass: f32,
+    pub rotation: Quat,
+    pub velocity: Vec3,
+    /// Bounding sphere around the center of mass, kept inside the box
+    pub radius: f32,
+    pub angular_velocity: Vec3,
+    pub _pad: f32,
+    /// Inverse of the inertia about the body's own axes
+    pub inv_inertia: Vec3,
+    pub _pad1: f32,
+    /// Force from the fluid this step, see [`fixed`]
+    pub force: FixedSum,
+    /// Torque about the center of mass from the fluid this step
+    pub torque: FixedSum,
+}
+
+impl RigidBody {
+    /// Where the point at `local` in the body's frame is now.
+    pub fn world(&self, local: Vec3) -> Vec3 {
+        self.position + self.rotation * local
+    }
+
+    /// Velocity of the body at `point`, including its spin.
+    pub fn velocity_at(&self, point: Vec3) -> Vec3 {
+        self.velocity + self.angular_velocity.cross(point - self.position)
+    }
+
+    /// Applies gravity and the force and torque the fluid added this step,
+    /// then moves the body and keeps its bounding sphere inside the box.
+    pub fn integrate(&mut self, settings: &Settings) {
+        let force = self.force.value();
+        let torque = self.torque.value();
+        self.force = FixedSum::default();
+        self.torque = FixedSum::default();
+
+        if self.inv_mass <= 0.0 {
+            return;
+        }
+
+        let dt = settings.dtime;
+        let rot = self.rotation;
+
+        self.velocity += (settings.gravity + force * self.inv_mass) * dt;
+        self.angular_velocity += rot * (self.inv_inertia * (rot.conjugate() * torque)) * dt;
+        self.position += self.velocity * dt;
+
+        let w = self.angular_velocity;
+        let spin = Quat::from_xyzw(w.x, w.y, w.z, 0.0) * rot * (0.5 * dt);
+        self.rotation = (rot + spin).normalize();
+
+        // bounced relative to the walls, which may be moving
+        let wall = settings.wall_velocity(self.position);
+        let mut lpos = settings.to_box(self.position);
+        let mut lvel = settings.box_quat.conjugate() * (self.velocity - wall);
+        let lo = self.radius;
+        let hi = settings.box_size - self.radius;
+        let damping = settings.collision_damping;
+
+        bounce(&mut lpos.x, &mut lvel.x, lo, hi.x, damping);
+        bounce(&mut lpos.y, &mut lvel.y, lo, hi.y, damping);
+        bounce(&mut lpos.z, &mut lvel.z, lo, hi.z, damping);
+
+        self.position = settings.from_box(lpos);
+        self.velocity = settings.box_quat * lvel + wall;
+    }
+}
+
+fn bounce(pos: &mut f32, vel: &mut f32, lo: f32, hi: f32, damping: f32) {
+    if *pos < lo {
+        *pos = lo;
+        *vel = vel.abs() * damping;
+    } else if *pos > hi {
+        *pos = hi;
+        *vel = -vel.abs() * damping;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Bodies {
+    /// The first [`Settings::num_bodies`] are in use
+    pub bodies: [RigidBody; MAX_BODIES],
+}
+
+impl Default for Bodies {
+    fn default() -> Self {
+        Self {
+            bodies: [RigidBody::default(); MAX_BODIES],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_past_the_low_word() {
+        const PARTICLES: u32 = 100_000;
+
+        let mut sum = FixedSum::default();
+        for _ in 0..PARTICLES {
+            sum.add(Vec3::new(1.0e5, -1.0e5, 0.5));
+        }
+
+        let expected = PARTICLES as f32 * 1.0e5;
+        let value = sum.value();
+        assert!((value.x / expected - 1.0).abs() < 1e-6, "{value}");
+        assert!((value.y / -expected - 1.0).abs() < 1e-6, "{value}");
+        assert!(
+            (value.z / (PARTICLES as f32 * 0.5) - 1.0).abs() < 1e-6,
+            "{value}"
+        );
+    }
+
+    #[test]
+    fn opposite_shares_cancel() {
+        let mut sum = FixedSum::default();
+        for _ in 0..1000 {
+            sum.add(Vec3::splat(3.0e4));
+        }
+        for _ in 0..1000 {
+            sum.add(Vec3::splat(-3.0e4));
+        }
+
+        assert_eq!(sum, FixedSum::default());
+    }
+}
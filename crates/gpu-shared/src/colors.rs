@@ -0,0 +1,64 @@
+#[cfg(not(target_arch = "spirv"))]
+use bytemuck::{Pod, Zeroable};
+use glam::Vec4;
+#[cfg(target_arch = "spirv")]
+use spirv_std::glam;
+
+pub const MAX_STOPS: usize = 8;
+
+/// How fluid particles are colored. Each particle's value in `mode` is mapped
+/// from `[min, max]` onto the stops.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Coloring {
+    /// `[r, g, b, position]`, color in `[0, 1]` and sorted by position
+    pub stops: [Vec4; MAX_STOPS],
+    pub num_stops: u32,
+    pub mode: u32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Coloring {
+    pub const DENSITY: u32 = 1;
+    pub const HEIGHT: u32 = 4;
+    pub const ID: u32 = 5;
+    pub const NEAR_DENSITY: u32 = 2;
+    pub const NEIGHBORS: u32 = 6;
+    /// The color of each particle's material, ignoring the stops
+    pub const PHASE: u32 = 7;
+    pub const PRESSURE: u32 = 3;
+    pub const SPEED: u32 = 0;
+
+    /// Maps `value` onto the stops, clamping outside the range.
+    pub fn sample(&self, value: f32) -> Vec4 {
+        let span = self.max - self.min;
+        let t = if span.abs() > f32::EPSILON {
+            ((value - self.min) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        let n = (self.num_stops as usize).clamp(1, MAX_STOPS);
+        let first = self.stops[0];
+        if t <= first.w {
+            return first.truncate().extend(1.0);
+        }
+
+        let mut i = 1;
+        while i < n {
+            let c0 = self.stops[i - 1];
+            let c1 = self.stops[i];
+
+            if t <= c1.w {
+                let local = (t - c0.w) / (c1.w - c0.w).max(f32::EPSILON);
+                return (c0 + (c1 - c0) * local).truncate().extend(1.0);
+            }
+
+            i += 1;
+        }
+
+        self.stops[n - 1].truncate().extend(1.0)
+    }
+}
@@ -0,0 +1,64 @@
+use core::f32;
+
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::real::Real;
+
+// \int_0^{2\pi}
+//      \int_0^\pi
+//          \int_0^h [(h-r)^2 \times r^3 \times \sin \theta] dr
+//      d\theta
+// d\phi
+//
+// = (\int_0^{2\pi} d\phi)
+//   (\int_0^{\pi} \sin \theta d\theta)
+//   (\int_0^h [(h-r)^2 \times r^3] dr)
+//
+// = \frac{\pi \times h^6}{15}
+pub fn density_near(dist: f32, radius: f32) -> f32 {
+    if dist >= radius {
+        return 0.0;
+    }
+
+    const VOLUME: f32 = f32::consts::PI / 15.0;
+    const VOLUME_INVERSE: f32 = 1.0 / VOLUME;
+
+    (radius - dist).powi(3) * (VOLUME_INVERSE / radius.powi(6))
+}
+
+// \frac{d}{dr} (h-r)^3 = -3(h-r)
+// \frac{-3(h-r)}{V} = (r-h) * \frac{45}{\pi \times h^6}
+pub fn ndensity_deriv(dist: f32, radius: f32) -> f32 {
+    if dist >= radius || dist == 0.0 {
+        return 0.0;
+    }
+
+    const SCALE: f32 = 45.0 / f32::consts::PI;
+    (dist - radius).powi(2) * (SCALE / radius.powi(6))
+}
+
+// Akinci et al. 2013's cohesion spline, attracting past h/2 and repelling
+// closer in
+//
+// \frac{32}{\pi h^9} (h-r)^3 r^3                     for h/2 < r <= h
+// \frac{32}{\pi h^9} (2(h-r)^3 r^3 - \frac{h^6}{64})  for 0 < r <= h/2
+pub fn cohesion(dist: f32, radius: f32) -> f32 {
+    if dist >= radius || dist <= 0.0 {
+        return 0.0;
+    }
+
+    const SCALE: f32 = 32.0 / f32::consts::PI;
+    let spline = (radius - dist).powi(3) * dist.powi(3);
+
+    let value = if 2.0 * dist > radius {
+        spline
+    } else {
+        2.0 * spline - radius.powi(6) / 64.0
+    };
+
+    value * (SCALE / radius.powi(9))
+}
+
+pub fn density_to_pressure(density: f32, target: f32, multiplier: f32) -> f32 {
+    let err = density - target;
+    err * multiplier
+}
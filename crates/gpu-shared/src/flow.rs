@@ -0,0 +1,254 @@
+//! Emitters that add fluid during a run and sinks that take it away. While
+//! either is in use the particle count lives on the gpu, in [`FlowState`].
+
+use core::f32::consts::PI;
+
+#[cfg(not(target_arch = "spirv"))]
+use bytemuck::{Pod, Zeroable};
+use glam::{UVec3, Vec3, vec3};
+#[cfg(target_arch = "spirv")]
+use spirv_std::{glam, num_traits::Float};
+
+use crate::{Settings, obstacles::Obstacle};
+
+pub const MAX_EMITTERS: usize = 8;
+pub const MAX_SINKS: usize = 8;
+
+/// Slots counted together while compacting what the sinks leave, one
+/// workgroup's worth
+pub const COMPACT_BLOCK: u32 = 256;
+
+/// Shoots particles out of a disc, spread over it along a sunflower spiral
+pub const NOZZLE: u32 = 0;
+/// Fills a box with a grid of particles, once or over and over
+pub const VOLUME: u32 = 1;
+
+// between consecutive points of the spiral, so each lands in the largest gap
+const GOLDEN_ANGLE: f32 = 2.399_963;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Emitter {
+    /// Center, in the frame of the box
+    pub position: Vec3,
+    /// [`NOZZLE`] or [`VOLUME`]
+    pub kind: u32,
+    /// Unit normal of a nozzle's opening
+    pub direction: Vec3,
+    /// Of a nozzle's opening
+    pub radius: f32,
+    /// Half of each side of a volume
+    pub half: Vec3,
+    /// Particles a second out of a nozzle
+    pub rate: f32,
+    /// Of the new particles, in the frame of the box
+    pub velocity: Vec3,
+    pub phase: u32,
+    /// Seconds after a reset the emitter starts
+    pub start: f32,
+    /// Seconds between the fills of a volume, zero fills it once
+    pub interval: f32,
+    pub _pad: f32,
+    pub _pad1: f32,
+}
+
+impl Emitter {
+    /// Grid points along each side of a volume, `spacing` apart.
+    pub fn lattice(&self, spacing: f32) -> UVec3 {
+        (self.half * 2.0 / spacing).floor().as_uvec3() + 1
+    }
+
+    /// Particles that fit side by side across a nozzle's opening.
+    pub fn opening(&self, spacing: f32) -> u32 {
+        let across = self.radius / spacing;
+        ((PI * across * across) as u32).max(1)
+    }
+
+    /// How many particles the emitter adds over the step from `from` to `to`
+    /// seconds after a reset. A nozzle carries the fraction over in `owed`.
+    pub fn due(&self, from: f32, to: f32, spacing: f32, owed: &mut f32) -> u32 {
+        let (t0, t1) = (from - self.start, to - self.start);
+        if t1 <= 0.0 {
+            return 0;
+        }
+
+        if self.kind == NOZZLE {
+            *owed += self.rate * (t1 - t0.max(0.0));
+            let due = owed.floor();
+            *owed -= due;
+            return due as u32;
+        }
+
+        // a fill is due when the step crosses a multiple of the interval
+        let fills = if self.interval > 0.0 {
+            (t1 / self.interval).ceil() > (t0.max(0.0) / self.interval).ceil()
+        } else {
+            t0 <= 0.0
+        };
+
+        if fills {
+            let lattice = self.lattice(spacing);
+            lattice.x * lattice.y * lattice.z
+        } else {
+            0
+        }
+    }
+
+    /// Where the `i`th of the `due` particles added over a step of `dtime`
+    /// goes, and its velocity, in the frame of the box. `serial` counts the
+    /// particles the emitter added before the step.
+    pub fn spawn(
+        &self,
+        serial: u32,
+        i: u32,
+        due: u32,
+        spacing: f32,
+        dtime: f32,
+        seed: u32,
+    ) -> (Vec3, Vec3) {
+        if self.kind == NOZZLE {
+            let n = self.opening(spacing);
+            let k = (serial + i) % n;
+            let r = self.radius * ((k as f32 + 0.5) / n as f32).sqrt();
+            let angle = k as f32 * GOLDEN_ANGLE;
+
+            let dir = self.direction;
+            let helper = if dir.x.abs() < 0.9 { Vec3::X } else { Vec3::Y };
+            let u = dir.cross(helper).normalize();
+            let v = dir.cross(u);
+
+            // the first particles of the step have been travelling the longest
+            let travelled = self.velocity.length() * dtime * (1.0 - (i as f32 + 0.5) / due as f32);
+            let offset = (u * angle.cos() + v * angle.sin()) * r + dir * travelled;
+
+            return (self.position + offset, self.velocity);
+        }
+
+        let lattice = self.lattice(spacing);
+        let cell = vec3(
+            (i % lattice.x) as f32,
+            (i / lattice.x % lattice.y) as f32,
+            (i / (lattice.x * lattice.y)) as f32,
+        );
+        let corner = self.position - (lattice - 1).as_vec3() * spacing / 2.0;
+
+        (
+            corner + cell * spacing + crate::jitter(serial + i, seed),
+            self.velocity,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Flow {
+    pub emitters: [Emitter; MAX_EMITTERS],
+    /// Fluid that gets inside one of these is removed. Any shape of obstacle
+    /// but a mesh
+    pub sinks: [Obstacle; MAX_SINKS],
+    pub num_emitters: u32,
+    pub num_sinks: u32,
+    /// Between the new particles
+    pub spacing: f32,
+    /// Most particles added in one step, as many as the host dispatches for
+    pub max_added: u32,
+}
+
+impl Flow {
+    /// Whether `p`, in the frame of the box, is inside a sink.
+    pub fn drains(&self, p: Vec3) -> bool {
+        let mut i = 0;
+        while i < self.num_sinks as usize {
+            if self.sinks[i].analytic(p) < 0.0 {
+                return true;
+            }
+            i += 1;
+        }
+
+        false
+    }
+}
+
+/// Written by the kernels, which remove the particles the sinks marked by
+/// moving the last ones into their slots, then add the new ones at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct FlowState {
+    /// Particles in use, boundary included
+    pub count: u32,
+    /// Fluid particles the sinks marked this step
+    pub removed: u32,
+    /// Slots under the new count the sinks emptied, each filled by a
+    /// particle from past it
+    pub holes: u32,
+    /// Where this step's new particles start
+    pub base: u32,
+    /// New particles this step
+    pub added: u32,
+    /// Seconds since the reset
+    pub time: f32,
+    /// Length of the step the new particles were added over
+    pub dtime: f32,
+    /// Particles each emitter added before this step
+    pub serial: [u32; MAX_EMITTERS],
+    /// This step's new particles from each emitter, in order after those of
+    /// the ones before it
+    pub due: [u32; MAX_EMITTERS],
+    /// Fraction of a particle each nozzle owes
+    pub owed: [f32; MAX_EMITTERS],
+}
+
+impl FlowState {
+    /// Drops the particles the sinks removed and shares out the new ones
+    /// over a step of `dtime`, up to `settings.max_particles`.
+    pub fn plan(&mut self, flow: &Flow, settings: &Settings, dtime: f32) {
+        self.count -= self.removed;
+        self.removed = 0;
+        self.holes = 0;
+
+        let room = if settings.max_particles > self.count {
+            (settings.max_particles - self.count).min(flow.max_added)
+        } else {
+            0
+        };
+
+        let from = self.time;
+        let to = from + dtime;
+        let mut added = 0;
+
+        let mut i = 0;
+        while i < MAX_EMITTERS {
+            self.serial[i] += self.due[i];
+            self.due[i] = 0;
+
+            if i < flow.num_emitters as usize {
+                let due = flow.emitters[i].due(from, to, flow.spacing, &mut self.owed[i]);
+                self.due[i] = due.min(room - added);
+                added += self.due[i];
+            }
+            i += 1;
+        }
+
+        self.base = self.count;
+        self.added = added;
+        self.count += added;
+        self.time = to;
+        self.dtime = dtime;
+    }
+
+    /// The emitter the `i`th new particle of this step comes from, and which
+    /// of its particles it is.
+    pub fn source(&self, i: u32) -> (usize, u32) {
+        let mut first = 0;
+        let mut e = 0;
+        while e + 1 < MAX_EMITTERS && i >= first + self.due[e] {
+            first += self.due[e];
+            e += 1;
+        }
+
+        (e, i - first)
+    }
+}
@@ -0,0 +1,225 @@
+//! Smoothing kernels the density, its gradient and the viscosity are summed
+//! with, stored in [`crate::Settings::kernel`] and
+//! [`crate::Settings::viscosity_kernel`]. Each is normalized to integrate to
+//! one over the sphere of the smoothing radius `h`, with `q = r / h`.
+
+use core::f32;
+
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::real::Real;
+
+/// `(h-r)^2`, sharp in the middle so close particles push apart hard
+pub const SPIKY: u32 = 0;
+/// `(h^2-r^2)^3`, smooth but with a gradient that fades out up close
+pub const POLY6: u32 = 1;
+/// The cubic B-spline of Monaghan, the usual SPH kernel
+pub const CUBIC_SPLINE: u32 = 2;
+/// Wendland's C2 kernel, which doesn't let particles pair up
+pub const WENDLAND_C2: u32 = 3;
+/// Wendland's C4 kernel, smoother still and best with many neighbours
+pub const WENDLAND_C4: u32 = 4;
+
+#[cfg(not(target_arch = "spirv"))]
+pub const ALL: [u32; 5] = [SPIKY, POLY6, CUBIC_SPLINE, WENDLAND_C2, WENDLAND_C4];
+
+#[cfg(not(target_arch = "spirv"))]
+pub fn name(kernel: u32) -> &'static str {
+    match kernel {
+        POLY6 => "poly6",
+        CUBIC_SPLINE => "cubic_spline",
+        WENDLAND_C2 => "wendland_c2",
+        WENDLAND_C4 => "wendland_c4",
+        _ => "spiky",
+    }
+}
+
+/// `W(r, h)` of `kernel`, zero from `radius` out.
+pub fn value(kernel: u32, dist: f32, radius: f32) -> f32 {
+    if dist >= radius {
+        return 0.0;
+    }
+
+    let q = dist / radius;
+    let shape = match kernel {
+        POLY6 => poly6(q),
+        CUBIC_SPLINE => cubic_spline(q),
+        WENDLAND_C2 => wendland_c2(q),
+        WENDLAND_C4 => wendland_c4(q),
+        _ => spiky(q),
+    };
+
+    shape / radius.powi(3)
+}
+
+/// `dW/dr` of `kernel`, which times the direction away from the other
+/// particle is the gradient. Zero at the center, where that direction is
+/// undefined.
+pub fn deriv(kernel: u32, dist: f32, radius: f32) -> f32 {
+    if dist >= radius || dist == 0.0 {
+        return 0.0;
+    }
+
+    let q = dist / radius;
+    let slope = match kernel {
+        POLY6 => poly6_deriv(q),
+        CUBIC_SPLINE => cubic_spline_deriv(q),
+        WENDLAND_C2 => wendland_c2_deriv(q),
+        WENDLAND_C4 => wendland_c4_deriv(q),
+        _ => spiky_deriv(q),
+    };
+
+    slope / radius.powi(4)
+}
+
+// Each shape below is `h^3 W` as a function of `q`, and each slope
+// `h^4 dW/dr`, so that
+//
+// \int_0^1 4\pi q^2 shape(q) dq = 1
+
+// \int_0^1 4\pi q^2 (1-q)^2 dq = \frac{2\pi}{15}
+fn spiky(q: f32) -> f32 {
+    const SCALE: f32 = 15.0 / (2.0 * f32::consts::PI);
+    SCALE * (1.0 - q).powi(2)
+}
+
+// \frac{d}{dq} (1-q)^2 = -2(1-q)
+fn spiky_deriv(q: f32) -> f32 {
+    const SCALE: f32 = 15.0 / f32::consts::PI;
+    -SCALE * (1.0 - q)
+}
+
+// \int_0^1 4\pi q^2 (1-q^2)^3 dq = \frac{64\pi}{315}
+fn poly6(q: f32) -> f32 {
+    const SCALE: f32 = 315.0 / (64.0 * f32::consts::PI);
+    SCALE * (1.0 - q * q).powi(3)
+}
+
+// \frac{d}{dq} (1-q^2)^3 = -6q(1-q^2)^2
+fn poly6_deriv(q: f32) -> f32 {
+    const SCALE: f32 = 945.0 / (32.0 * f32::consts::PI);
+    -SCALE * q * (1.0 - q * q).powi(2)
+}
+
+// 6q^3 - 6q^2 + 1  for q <= 1/2
+// 2(1-q)^3         for 1/2 < q <= 1
+//
+// \int_0^1 4\pi q^2 [...] dq = \frac{\pi}{8}
+fn cubic_spline(q: f32) -> f32 {
+    const SCALE: f32 = 8.0 / f32::consts::PI;
+
+    if q <= 0.5 {
+        SCALE * (6.0 * q * q * (q - 1.0) + 1.0)
+    } else {
+        SCALE * 2.0 * (1.0 - q).powi(3)
+    }
+}
+
+// 18q^2 - 12q = 6q(3q-2)  for q <= 1/2
+// -6(1-q)^2               for 1/2 < q <= 1
+fn cubic_spline_deriv(q: f32) -> f32 {
+    const SCALE: f32 = 48.0 / f32::consts::PI;
+
+    if q <= 0.5 {
+        SCALE * q * (3.0 * q - 2.0)
+    } else {
+        -SCALE * (1.0 - q).powi(2)
+    }
+}
+
+// \int_0^1 4\pi q^2 (1-q)^4 (1+4q) dq = \frac{2\pi}{21}
+fn wendland_c2(q: f32) -> f32 {
+    const SCALE: f32 = 21.0 / (2.0 * f32::consts::PI);
+    SCALE * (1.0 - q).powi(4) * (1.0 + 4.0 * q)
+}
+
+// \frac{d}{dq} (1-q)^4 (1+4q) = -20q(1-q)^3
+fn wendland_c2_deriv(q: f32) -> f32 {
+    const SCALE: f32 = 210.0 / f32::consts::PI;
+    -SCALE * q * (1.0 - q).powi(3)
+}
+
+// \int_0^1 4\pi q^2 (1-q)^6 (1+6q+\frac{35}{3}q^2) dq = \frac{32\pi}{495}
+fn wendland_c4(q: f32) -> f32 {
+    const SCALE: f32 = 495.0 / (32.0 * f32::consts::PI);
+    SCALE * (1.0 - q).powi(6) * (1.0 + 6.0 * q + 35.0 / 3.0 * q * q)
+}
+
+// \frac{d}{dq} (1-q)^6 (1+6q+\frac{35}{3}q^2) = -\frac{56}{3}q(1+5q)(1-q)^5
+fn wendland_c4_deriv(q: f32) -> f32 {
+    const SCALE: f32 = 1155.0 / (4.0 * f32::consts::PI);
+    -SCALE * q * (1.0 + 5.0 * q) * (1.0 - q).powi(5)
+}
+
+/// Reads and writes kernels by name in scene files.
+#[cfg(not(target_arch = "spirv"))]
+pub(crate) mod by_name {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(kernel: &u32, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(super::name(*kernel))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+        let name = String::deserialize(d)?;
+
+        super::ALL
+            .into_iter()
+            .find(|&kernel| super::name(kernel) == name.to_ascii_lowercase())
+            .ok_or_else(|| D::Error::custom(format!("unknown kernel {name:?}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADII: [f32; 3] = [0.4, 1.0, 2.5];
+
+    #[test]
+    fn kernels_integrate_to_one() {
+        const STEPS: usize = 100_000;
+
+        for kernel in ALL {
+            for h in RADII {
+                // midpoint rule over q, in f64 so the sum doesn't drift
+                let integral = (0..STEPS)
+                    .map(|i| {
+                        let q = (i as f64 + 0.5) / STEPS as f64;
+                        let w = f64::from(value(kernel, q as f32 * h, h)) * f64::from(h).powi(3);
+                        4.0 * core::f64::consts::PI * q * q * w / STEPS as f64
+                    })
+                    .sum::<f64>();
+
+                assert!(
+                    (integral - 1.0).abs() < 1e-3,
+                    "{} at h = {h} integrates to {integral}",
+                    name(kernel)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn derivatives_match_finite_differences() {
+        for kernel in ALL {
+            for h in RADII {
+                let eps = 1e-3 * h;
+                let scale = value(kernel, 0.0, h) / h;
+
+                for i in 1..100 {
+                    let r = i as f32 / 100.0 * h;
+                    let central =
+                        (value(kernel, r + eps, h) - value(kernel, r - eps, h)) / (2.0 * eps);
+                    let error = (deriv(kernel, r, h) - central).abs() / scale;
+
+                    assert!(
+                        error < 1e-2,
+                        "{} at h = {h}, r = {r} has dW/dr {} but {central} numerically",
+                        name(kernel),
+                        deriv(kernel, r, h)
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,21 +1,38 @@
-#![no_std]
+#![cfg_attr(target_arch = "spirv", no_std)]
 #![allow(unexpected_cfgs)]
 
 #[cfg(not(target_arch = "spirv"))]
 use bytemuck::{Pod, Zeroable};
-use glam::{Mat4, Quat, UVec2, UVec3, Vec2, Vec3, Vec4, vec3};
+use glam::{BVec3, Mat4, Quat, UVec2, UVec3, Vec2, Vec3, Vec4, vec3};
 #[cfg(target_arch = "spirv")]
-use spirv_std::glam;
+use spirv_std::{glam, num_traits::Float};
+
+pub mod bodies;
+pub mod colors;
+pub mod curves;
+pub mod flow;
+pub mod kernel;
+pub mod materials;
+pub mod obstacles;
+pub mod rheology;
+pub mod solver;
+pub mod sp_hash;
+pub mod timestep;
 
 pub const DEFAULT_BOX_SIZE: Vec3 = Vec3::new(10., 8., 6.);
 pub const DEFAULT_PARTICLES: UVec3 = UVec3::new(15, 15, 15);
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[cfg_attr(
+    not(target_arch = "spirv"),
+    derive(Pod, Zeroable, serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 #[repr(C)]
 pub struct Settings {
     pub gravity: Vec3,
 
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
     pub dtime: f32,
     pub collision_damping: f32,
 
@@ -30,13 +47,164 @@ pub struct Settings {
 
     pub viscosity_strength: f32,
 
+    // derived from the initial conditions on reset, so not part of a scene
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
     pub num_particles: u32,
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
     pub boundary_particles: u32,
     pub particle_radius: f32,
 
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
     pub box_size: Vec3,
-    pub _pad: f32,
+    /// A bit per axis of the box that wraps around instead of having walls,
+    /// derived from the initial conditions
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub periodic: u32,
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
     pub box_quat: Quat,
+
+    /// One of the constants in [`solver`]
+    #[cfg_attr(not(target_arch = "spirv"), serde(with = "solver::by_name"))]
+    pub solver: u32,
+    /// Constraint iterations per step, for PBF
+    pub solver_iterations: u32,
+    /// Softens the PBF density constraint, keeping sparse particles stable
+    pub relaxation: f32,
+    /// Strength of the PBF artificial pressure, which stops particles clumping
+    pub tensile_strength: f32,
+
+    /// Average density error the DFSPH density solver stops at, relative to
+    /// the target density
+    pub density_tolerance: f32,
+    /// Average density change over a step the DFSPH divergence solver stops
+    /// at, relative to the target density
+    pub divergence_tolerance: f32,
+    pub max_density_iterations: u32,
+    pub max_divergence_iterations: u32,
+
+    /// Nonzero to shorten steps below `dtime` when particles move too fast,
+    /// see [`timestep::TimeStep::choose`]
+    #[cfg_attr(not(target_arch = "spirv"), serde(with = "flag"))]
+    pub adaptive_timestep: u32,
+    /// Fraction of the smoothing radius a particle may move in one adaptive
+    /// step
+    pub cfl_number: f32,
+    /// Adaptive steps never get shorter than this, so the simulation can't
+    /// stall
+    pub min_dtime: f32,
+    /// Picks the offsets of [`jitter`], derived from the scene's seed on reset
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub jitter_seed: u32,
+
+    /// Strength of the cohesion and curvature forces that pull the surface
+    /// together. Zero skips them
+    pub surface_tension: f32,
+    /// Rigid bodies in [`bodies::Bodies`], derived from the initial conditions
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub num_bodies: u32,
+    /// The last `body_particles` boundary particles belong to the rigid
+    /// bodies, the rest are the box
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub body_particles: u32,
+    /// Particles the buffers make room for, boundary included. Kernels run
+    /// this many threads and stop at `num_particles`, which emitters and sinks
+    /// change on the gpu
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub max_particles: u32,
+
+    /// Where the corner of the box is, which `box_quat` turns it about. The
+    /// rest of the box's pose and motion follow from the initial conditions
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub box_position: Vec3,
+    /// Seconds the box has been moving since the reset
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub box_time: f32,
+    /// Of the box's corner over the last step
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub box_velocity: Vec3,
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub _pad: f32,
+    /// Angular velocity of the box over the last step
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub box_spin: Vec3,
+    #[cfg_attr(not(target_arch = "spirv"), serde(skip))]
+    pub _pad1: f32,
+
+    /// One of the constants in [`kernel`], which the density and its gradient
+    /// are summed with
+    #[cfg_attr(not(target_arch = "spirv"), serde(with = "kernel::by_name"))]
+    pub kernel: u32,
+    /// One of the constants in [`kernel`], which weights the velocities the
+    /// viscosity evens out
+    #[cfg_attr(not(target_arch = "spirv"), serde(with = "kernel::by_name"))]
+    pub viscosity_kernel: u32,
+
+    /// One of the constants in [`rheology`], which sets how the viscosity
+    /// follows the shear rate
+    #[cfg_attr(not(target_arch = "spirv"), serde(with = "rheology::by_name"))]
+    pub viscosity_model: u32,
+    /// Below one the fluid thins as it shears and above one it thickens, for
+    /// the power law and Carreau models
+    pub flow_index: f32,
+    /// Seconds a Carreau fluid takes to start thinning, the inverse of the
+    /// shear rate it does
+    pub shear_time: f32,
+    /// What a Carreau fluid thins to at high shear rates
+    pub infinite_viscosity: f32,
+    /// The stress a Bingham fluid holds before it flows
+    pub yield_stress: f32,
+    /// Caps the non-Newtonian viscosities, which blow up at low shear rates,
+    /// so the step stays stable
+    pub max_viscosity: f32,
+}
+
+impl Settings {
+    /// Whether particle `id` samples a rigid body rather than the box.
+    pub fn is_body(&self, id: u32) -> bool {
+        id < self.boundary_particles && id + self.body_particles >= self.boundary_particles
+    }
+
+    /// `p` in the frame of the box, where it spans `[0, box_size]`.
+    pub fn to_box(&self, p: Vec3) -> Vec3 {
+        self.box_quat.conjugate() * (p - self.box_position)
+    }
+
+    /// `local`, in the frame of the box, in the world.
+    pub fn from_box(&self, local: Vec3) -> Vec3 {
+        self.box_quat * local + self.box_position
+    }
+
+    /// How fast the walls, and everything fixed to them, move at `p`.
+    pub fn wall_velocity(&self, p: Vec3) -> Vec3 {
+        self.box_velocity + self.box_spin.cross(p - self.box_position)
+    }
+
+    /// The axes of the box that wrap around.
+    pub fn periodic_axes(&self) -> BVec3 {
+        BVec3::new(
+            self.periodic & 1 != 0,
+            self.periodic & 2 != 0,
+            self.periodic & 4 != 0,
+        )
+    }
+
+    /// Brings `lpos`, in the frame of the box, back into it across the
+    /// periodic axes.
+    pub fn wrap(&self, lpos: Vec3) -> Vec3 {
+        let size = self.box_size;
+        Vec3::select(
+            self.periodic_axes(),
+            lpos - size * (lpos / size).floor(),
+            lpos,
+        )
+    }
+
+    /// Clamps `lpos`, in the frame of the box, to `margin` inside its walls,
+    /// leaving the periodic axes alone.
+    pub fn confine(&self, lpos: Vec3, margin: f32) -> Vec3 {
+        let clamped = lpos.clamp(Vec3::splat(margin), self.box_size - margin);
+        Vec3::select(self.periodic_axes(), lpos, clamped)
+    }
 }
 
 impl Default for Settings {
@@ -64,11 +232,62 @@ impl Default for Settings {
 
             mass: 1.0,
             particle_radius: 0.05,
+            periodic: 0,
+
+            solver: solver::SPH,
+            solver_iterations: 4,
+            relaxation: 100.0,
+            tensile_strength: 0.01,
+
+            density_tolerance: 0.001,
+            divergence_tolerance: 0.001,
+            max_density_iterations: 16,
+            max_divergence_iterations: 16,
+
+            adaptive_timestep: 0,
+            cfl_number: 0.4,
+            min_dtime: 0.0001,
+            jitter_seed: 0,
+
+            surface_tension: 0.0,
+            num_bodies: 0,
+            body_particles: 0,
+            max_particles: 0,
+
+            box_position: Vec3::ZERO,
+            box_time: 0.0,
+            box_velocity: Vec3::ZERO,
             _pad: 0.0,
+            box_spin: Vec3::ZERO,
+            _pad1: 0.0,
+
+            kernel: kernel::SPIKY,
+            viscosity_kernel: kernel::POLY6,
+
+            viscosity_model: rheology::NEWTONIAN,
+            flow_index: 0.5,
+            shear_time: 1.0,
+            infinite_viscosity: 0.0,
+            yield_stress: 1.0,
+            max_viscosity: 2.0,
         }
     }
 }
 
+/// Reads and writes a `u32` flag as a bool in scene files.
+#[cfg(not(target_arch = "spirv"))]
+mod flag {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(flag: &u32, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bool(*flag != 0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+        bool::deserialize(d).map(u32::from)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 #[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
 #[repr(C)]
@@ -111,6 +330,68 @@ impl MouseState {
         self.position = px.unwrap_or(self.position);
         self.clickmask = (left as u32) | ((right as u32) << 1);
     }
+
+    /// Casts a ray from the camera through the cursor and clips it against the
+    /// (rotated) simulation box. Returns the midpoint of the clipped segment,
+    /// and whether the ray hit the box at all.
+    pub fn target(&self, globals: &Globals, settings: &Settings) -> (Vec3, bool) {
+        let res = globals.resolution.as_vec2();
+        let ndc = Vec2::new(
+            2.0 * self.position.x / res.x - 1.0,
+            1.0 - 2.0 * self.position.y / res.y,
+        );
+
+        // wgpu clip space has z in [0, 1]
+        let inv = (globals.projection * globals.view).inverse();
+        let near = inv.project_point3(vec3(ndc.x, ndc.y, 0.0));
+        let far = inv.project_point3(vec3(ndc.x, ndc.y, 1.0));
+
+        // slab test in box-local space, where the box is [0, size]
+        let origin = settings.to_box(near);
+        let dir = (settings.box_quat.conjugate() * (far - near)).normalize();
+
+        let t0 = (Vec3::ZERO - origin) / dir;
+        let t1 = (settings.box_size - origin) / dir;
+        let t_enter = t0.min(t1).max_element().max(0.0);
+        let t_exit = t0.max(t1).min_element();
+
+        let local = origin + dir * (t_enter + t_exit) * 0.5;
+        (settings.from_box(local), t_exit >= t_enter)
+    }
+
+    /// Acceleration on a particle at `position`, including gravity, given the
+    /// interaction point from [`MouseState::target`].
+    pub fn acceleration(
+        &self,
+        target: Vec3,
+        position: Vec3,
+        velocity: Vec3,
+        settings: &Settings,
+    ) -> Vec3 {
+        let gravity = settings.gravity;
+        let offset = target - position;
+        let dist_sq = offset.dot(offset);
+        let radius = settings.interaction_radius;
+
+        if !self.active() || dist_sq >= radius * radius {
+            return gravity;
+        }
+
+        let dist = dist_sq.sqrt();
+        let dir = if dist > f32::EPSILON {
+            offset / dist
+        } else {
+            Vec3::ZERO
+        };
+
+        // strongest at the center of the interaction sphere, fading out to the edge.
+        // gravity is weakened so that particles can actually be lifted.
+        let center_t = 1.0 - dist / radius;
+        let strength = settings.interaction_strength * self.intensity();
+        let gravity_weight = 1.0 - center_t * (strength / 10.0).clamp(0.0, 1.0);
+
+        gravity * gravity_weight + (dir * strength - velocity) * center_t
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -140,6 +421,17 @@ pub struct LineVertex {
     pub color: [f32; 3],
 }
 
+/// Sin-hash offset that pushes apart particles on top of each other. Every
+/// `seed` below `1 << 16` gives each particle a different offset.
+pub fn jitter(id: u32, seed: u32) -> Vec3 {
+    let x = (id ^ seed) as f32;
+    let hash = |scale: f32| {
+        let v = (x * scale).sin() * 43758.54;
+        v - v.floor()
+    };
+
+    vec3(hash(12.9898) - 0.5, hash(78.233) - 0.5, hash(45.164) - 0.5) * 0.02
+}
+
 pub const SCALE: f32 = 100.0;
-pub const ARRAY_LEN: usize = 262144;
 pub const WORKGROUP_SIZE: u32 = 256;
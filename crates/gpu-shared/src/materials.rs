@@ -0,0 +1,66 @@
+//! Per-phase fluid properties, so that several fluids can share one
+//! simulation. Each particle's phase is kept in the `w` of its velocity.
+
+#[cfg(not(target_arch = "spirv"))]
+use bytemuck::{Pod, Zeroable};
+use glam::Vec4;
+#[cfg(target_arch = "spirv")]
+use spirv_std::glam;
+
+pub const MAX_PHASES: usize = 8;
+
+/// One fluid. Every phase packs at the same rest spacing, so `mass` follows
+/// `target_density`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Material {
+    /// `[r, g, b, 1]`, used when coloring by phase
+    pub color: Vec4,
+    pub mass: f32,
+    pub target_density: f32,
+    pub viscosity_strength: f32,
+    pub surface_tension: f32,
+}
+
+/// Phase 0 is the fluid the settings describe.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Materials {
+    pub phases: [Material; MAX_PHASES],
+}
+
+impl Materials {
+    /// The material of a particle moving at `velocity`.
+    pub fn of(&self, velocity: Vec4) -> Material {
+        self.phases[phase(velocity)]
+    }
+
+    pub fn max_viscosity(&self) -> f32 {
+        let mut max = 0.0f32;
+        let mut i = 0;
+        while i < MAX_PHASES {
+            max = max.max(self.phases[i].viscosity_strength);
+            i += 1;
+        }
+
+        max
+    }
+
+    pub fn max_surface_tension(&self) -> f32 {
+        let mut max = 0.0f32;
+        let mut i = 0;
+        while i < MAX_PHASES {
+            max = max.max(self.phases[i].surface_tension);
+            i += 1;
+        }
+
+        max
+    }
+}
+
+/// The phase kept in the `w` of a particle's velocity.
+pub fn phase(velocity: Vec4) -> usize {
+    (velocity.w.max(0.0) as usize).min(MAX_PHASES - 1)
+}
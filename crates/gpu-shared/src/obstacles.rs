@@ -0,0 +1,206 @@
+//! Static obstacles inside the box, described by signed distance functions.
+//! Particles that get within their radius of one are pushed back out and
+//! bounce off it like they do off the walls.
+
+#[cfg(not(target_arch = "spirv"))]
+use bytemuck::{Pod, Zeroable};
+use glam::{Quat, Vec2, Vec3, vec2, vec3};
+#[cfg(target_arch = "spirv")]
+use spirv_std::{glam, num_traits::Float};
+
+pub const MAX_OBSTACLES: usize = 16;
+/// Baked meshes, each with its own [`SdfGrid`]
+pub const MAX_SDF_GRIDS: usize = 4;
+/// Samples along each side of an [`SdfGrid`]
+pub const SDF_RESOLUTION: usize = 32;
+
+/// [`Obstacle::size`] `x` is the radius
+pub const SPHERE: u32 = 0;
+/// Along `y`, [`Obstacle::size`] `x` is the radius and `y` half the length
+/// between the centers of the caps
+pub const CAPSULE: u32 = 1;
+/// [`Obstacle::size`] is half of each side
+pub const BOX: u32 = 2;
+/// Along `y`, [`Obstacle::size`] `x` is the radius and `y` half the height
+pub const CYLINDER: u32 = 3;
+/// A mesh baked into [`Obstacle::grid`], which spans [`Obstacle::size`]
+/// either side of the center
+pub const MESH: u32 = 4;
+
+// step for the normals, by central differences
+const EPSILON: f32 = 1.0e-3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Obstacle {
+    /// Center, in the frame of the box
+    pub position: Vec3,
+    /// One of [`SPHERE`], [`CAPSULE`], [`BOX`], [`CYLINDER`] or [`MESH`]
+    pub shape: u32,
+    pub rotation: Quat,
+    pub size: Vec3,
+    /// Which of the [`SdfGrids`] a [`MESH`] is baked into
+    pub grid: u32,
+}
+
+impl Obstacle {
+    /// Signed distance from the surface to `p`, in the frame of the box.
+    /// Negative inside.
+    pub fn distance(&self, grids: &SdfGrids, p: Vec3) -> f32 {
+        if self.shape != MESH {
+            return self.analytic(p);
+        }
+
+        // outside the grid, add the distance back to it
+        let p = self.rotation.conjugate() * (p - self.position);
+        let inside = p.clamp(-self.size, self.size);
+        grids.grids[self.grid as usize].sample(inside, self.size) + (p - inside).length()
+    }
+
+    /// Like [`Obstacle::distance`], for every shape but a [`MESH`].
+    pub fn analytic(&self, p: Vec3) -> f32 {
+        let p = self.rotation.conjugate() * (p - self.position);
+        let size = self.size;
+
+        if self.shape == SPHERE {
+            p.length() - size.x
+        } else if self.shape == CAPSULE {
+            let y = p.y - p.y.clamp(-size.y, size.y);
+            vec3(p.x, y, p.z).length() - size.x
+        } else if self.shape == BOX {
+            let q = p.abs() - size;
+            q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+        } else {
+            let d = vec2(vec2(p.x, p.z).length() - size.x, p.y.abs() - size.y);
+            d.max(Vec2::ZERO).length() + d.max_element().min(0.0)
+        }
+    }
+
+    /// Outward normal at `p`, in the frame of the box.
+    pub fn normal(&self, grids: &SdfGrids, p: Vec3) -> Vec3 {
+        let k = [
+            vec3(1.0, -1.0, -1.0),
+            vec3(-1.0, -1.0, 1.0),
+            vec3(-1.0, 1.0, -1.0),
+            vec3(1.0, 1.0, 1.0),
+        ];
+
+        let mut normal = Vec3::ZERO;
+        let mut i = 0;
+        while i < 4 {
+            normal += k[i] * self.distance(grids, p + k[i] * EPSILON);
+            i += 1;
+        }
+
+        normal.normalize_or_zero()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Obstacles {
+    pub obstacles: [Obstacle; MAX_OBSTACLES],
+    /// The first `count` are in use
+    pub count: u32,
+    pub _pad: u32,
+    pub _pad1: u32,
+    pub _pad2: u32,
+}
+
+impl Obstacles {
+    /// Moves `pos`, in the frame of the box, out to `radius` from every
+    /// obstacle it is closer to.
+    pub fn push_out(&self, grids: &SdfGrids, pos: &mut Vec3, radius: f32) {
+        let mut i = 0;
+        while i < self.count as usize {
+            let obstacle = &self.obstacles[i];
+            let dist = obstacle.distance(grids, *pos);
+            if dist < radius {
+                *pos += obstacle.normal(grids, *pos) * (radius - dist);
+            }
+            i += 1;
+        }
+    }
+
+    /// Pushes a particle out of the obstacles and reverses its velocity into
+    /// them, scaled by `damping`, all in the frame of the box.
+    pub fn collide(
+        &self,
+        grids: &SdfGrids,
+        pos: &mut Vec3,
+        vel: &mut Vec3,
+        radius: f32,
+        damping: f32,
+    ) {
+        let mut i = 0;
+        while i < self.count as usize {
+            let obstacle = &self.obstacles[i];
+            let dist = obstacle.distance(grids, *pos);
+            if dist < radius {
+                let normal = obstacle.normal(grids, *pos);
+                *pos += normal * (radius - dist);
+
+                let into = vel.dot(normal);
+                if into < 0.0 {
+                    *vel -= normal * into * (1.0 + damping);
+                }
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Signed distances on a grid of [`SDF_RESOLUTION`] samples a side, indexed
+/// by `[z][y * SDF_RESOLUTION + x]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct SdfGrid {
+    pub values: [[f32; SDF_RESOLUTION * SDF_RESOLUTION]; SDF_RESOLUTION],
+}
+
+impl SdfGrid {
+    /// Where the sample at `index` is, for a grid spanning `size` either
+    /// side of its center.
+    pub fn point(index: [usize; 3], size: Vec3) -> Vec3 {
+        let step = size * 2.0 / (SDF_RESOLUTION - 1) as f32;
+        vec3(index[0] as f32, index[1] as f32, index[2] as f32) * step - size
+    }
+
+    /// Trilinear interpolation at `p`, within `size` of the center.
+    pub fn sample(&self, p: Vec3, size: Vec3) -> f32 {
+        let last = (SDF_RESOLUTION - 1) as f32;
+        let u = ((p + size) / (size * 2.0) * last).clamp(Vec3::ZERO, Vec3::splat(last));
+        let cell = u.floor().min(Vec3::splat(last - 1.0));
+        let f = u - cell;
+
+        let (x, y, z) = (cell.x as usize, cell.y as usize, cell.z as usize);
+        let at =
+            |i: usize, j: usize, k: usize| self.values[z + k][(y + j) * SDF_RESOLUTION + x + i];
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let y0 = lerp(
+            lerp(at(0, 0, 0), at(1, 0, 0), f.x),
+            lerp(at(0, 1, 0), at(1, 1, 0), f.x),
+            f.y,
+        );
+        let y1 = lerp(
+            lerp(at(0, 0, 1), at(1, 0, 1), f.x),
+            lerp(at(0, 1, 1), at(1, 1, 1), f.x),
+            f.y,
+        );
+
+        lerp(y0, y1, f.z)
+    }
+}
+
+/// Every baked mesh. Too large for the stack, so the host allocates it
+/// zeroed on the heap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct SdfGrids {
+    pub grids: [SdfGrid; MAX_SDF_GRIDS],
+}
@@ -0,0 +1,175 @@
+//! How the viscosity of the fluid depends on how fast it shears, stored in
+//! [`crate::Settings::viscosity_model`]. Each phase's viscosity strength is
+//! the scale of its model: the consistency of a power law fluid, the
+//! viscosity at rest of a Carreau fluid and the plastic viscosity of a
+//! Bingham one.
+
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use crate::Settings;
+
+/// The same viscosity at any shear rate, like water
+pub const NEWTONIAN: u32 = 0;
+/// `K γ^(n-1)`, thinning under shear below a flow index of one, like paint,
+/// and thickening above it
+pub const POWER_LAW: u32 = 1;
+/// `η∞ + (η0 - η∞)(1 + (λγ)^2)^((n-1)/2)`, a power law that levels off at
+/// both ends, like honey or blood
+pub const CARREAU: u32 = 2;
+/// `η + τ/γ`, which barely moves until the stress passes its yield stress,
+/// like ketchup or mud
+pub const BINGHAM: u32 = 3;
+
+#[cfg(not(target_arch = "spirv"))]
+pub const ALL: [u32; 4] = [NEWTONIAN, POWER_LAW, CARREAU, BINGHAM];
+
+/// Shear rates are clamped to at least this, where the power law and Bingham
+/// viscosities blow up
+const MIN_SHEAR: f32 = 1e-4;
+
+#[cfg(not(target_arch = "spirv"))]
+pub fn name(model: u32) -> &'static str {
+    match model {
+        POWER_LAW => "power_law",
+        CARREAU => "carreau",
+        BINGHAM => "bingham",
+        _ => "newtonian",
+    }
+}
+
+/// The viscosity of a phase of viscosity `strength` shearing at `shear` per
+/// second, up to [`Settings::max_viscosity`] unless it's Newtonian.
+pub fn viscosity(settings: &Settings, strength: f32, shear: f32) -> f32 {
+    let n = settings.flow_index;
+    let shear = shear.max(MIN_SHEAR);
+
+    let viscosity = match settings.viscosity_model {
+        POWER_LAW => strength * shear.powf(n - 1.0),
+        CARREAU => {
+            let rest = strength - settings.infinite_viscosity;
+            let thinning = (1.0 + (settings.shear_time * shear).powi(2)).powf((n - 1.0) / 2.0);
+            settings.infinite_viscosity + rest * thinning
+        }
+        BINGHAM => strength + settings.yield_stress / shear,
+        _ => return strength,
+    };
+
+    viscosity.max(0.0).min(settings.max_viscosity)
+}
+
+/// The most viscous a phase of viscosity `strength` can get, at any shear
+/// rate.
+pub fn peak(settings: &Settings, strength: f32) -> f32 {
+    match settings.viscosity_model {
+        NEWTONIAN => strength,
+        CARREAU => strength
+            .max(settings.infinite_viscosity)
+            .min(settings.max_viscosity),
+        _ => settings.max_viscosity,
+    }
+}
+
+/// Reads and writes viscosity models by name in scene files.
+#[cfg(not(target_arch = "spirv"))]
+pub(crate) mod by_name {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(model: &u32, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(super::name(*model))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+        let name = String::deserialize(d)?;
+
+        super::ALL
+            .into_iter()
+            .find(|&model| super::name(model) == name.to_ascii_lowercase())
+            .ok_or_else(|| D::Error::custom(format!("unknown viscosity model {name:?}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(viscosity_model: u32, flow_index: f32) -> Settings {
+        Settings {
+            viscosity_model,
+            flow_index,
+            infinite_viscosity: 0.01,
+            shear_time: 1.0,
+            yield_stress: 2.0,
+            max_viscosity: 50.0,
+            ..Settings::default()
+        }
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!(
+            (value - expected).abs() <= expected * 1e-4,
+            "{value} != {expected}"
+        );
+    }
+
+    const SHEARS: [f32; 6] = [0.0, 0.01, 0.5, 1.0, 30.0, 1e4];
+
+    #[test]
+    fn newtonian_ignores_shear_and_the_cap() {
+        let settings = settings(NEWTONIAN, 0.5);
+
+        for shear in SHEARS {
+            assert_near(viscosity(&settings, 80.0, shear), 80.0);
+        }
+        assert_near(peak(&settings, 80.0), 80.0);
+    }
+
+    #[test]
+    fn power_law_thins_or_thickens_with_its_index() {
+        let thinning = settings(POWER_LAW, 0.5);
+        assert_near(viscosity(&thinning, 2.0, 4.0), 1.0);
+        assert!(viscosity(&thinning, 2.0, 100.0) < viscosity(&thinning, 2.0, 10.0));
+
+        let thickening = settings(POWER_LAW, 2.0);
+        assert_near(viscosity(&thickening, 2.0, 4.0), 8.0);
+
+        // at rest it would be infinite
+        assert_near(viscosity(&thinning, 2.0, 0.0), 50.0);
+        assert_near(viscosity(&settings(POWER_LAW, 1.0), 2.0, 30.0), 2.0);
+    }
+
+    #[test]
+    fn carreau_levels_off_at_both_ends() {
+        let settings = settings(CARREAU, 0.5);
+
+        assert_near(viscosity(&settings, 1.0, 0.0), 1.0);
+        assert!(viscosity(&settings, 1.0, 1e6) < 0.012);
+
+        let curve = SHEARS.map(|shear| viscosity(&settings, 1.0, shear));
+        assert!(curve.is_sorted_by(|a, b| a >= b), "{curve:?}");
+    }
+
+    #[test]
+    fn bingham_adds_the_yield_stress_over_the_shear() {
+        let settings = settings(BINGHAM, 1.0);
+
+        assert_near(viscosity(&settings, 1.0, 4.0), 1.5);
+        assert_near(viscosity(&settings, 1.0, 1e4), 1.0002);
+        assert_near(viscosity(&settings, 1.0, 0.0), 50.0);
+    }
+
+    #[test]
+    fn peaks_bound_every_shear() {
+        for model in ALL {
+            for strength in [0.0, 0.005, 1.0, 80.0] {
+                let settings = settings(model, 0.5);
+                let peak = peak(&settings, strength);
+
+                for shear in SHEARS {
+                    let viscosity = viscosity(&settings, strength, shear);
+                    assert!(viscosity <= peak, "{}: {viscosity} > {peak}", name(model));
+                }
+            }
+        }
+    }
+}
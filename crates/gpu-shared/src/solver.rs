@@ -0,0 +1,141 @@
+//! Which scheme enforces incompressibility in each step, stored in
+//! [`crate::Settings::solver`].
+
+#[cfg(not(target_arch = "spirv"))]
+use bytemuck::{Pod, Zeroable};
+
+/// Double-density SPH, with pressure from the density error
+pub const SPH: u32 = 0;
+/// Position Based Fluids, an iterative density constraint on the predicted
+/// positions
+pub const PBF: u32 = 1;
+/// Divergence-free SPH, which solves for the pressure that keeps the density
+/// and its rate of change within a tolerance
+pub const DFSPH: u32 = 2;
+
+#[cfg(not(target_arch = "spirv"))]
+pub const ALL: [u32; 3] = [SPH, PBF, DFSPH];
+
+#[cfg(not(target_arch = "spirv"))]
+pub fn name(solver: u32) -> &'static str {
+    match solver {
+        PBF => "pbf",
+        DFSPH => "dfsph",
+        _ => "sph",
+    }
+}
+
+/// Fixed-point scale of [`Convergence::error`], which is summed with integer
+/// atomics
+pub const ERROR_SCALE: f32 = 16384.0;
+
+/// How far one DFSPH solver has got through a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct Convergence {
+    /// Relative error summed over the fluid this iteration, see
+    /// [`Convergence::fixed`], modulo 2^32
+    pub error: u32,
+    /// Iterations that have applied a correction
+    pub iterations: u32,
+    /// Nonzero once the error is within tolerance
+    pub done: u32,
+    /// Times [`Convergence::error`] has wrapped around this iteration, so
+    /// the sum doesn't overflow past a million particles
+    pub carry: u32,
+}
+
+impl Convergence {
+    /// One particle's share of [`Convergence::error`]. Clamped so that a few
+    /// stray particles can't keep the solver going on their own.
+    pub fn fixed(error: f32) -> u32 {
+        (error.clamp(0.0, 0.25) * ERROR_SCALE) as u32
+    }
+
+    /// Whether adding `share` to an error that was `before` wrapped it
+    /// around, and [`Convergence::carry`] needs a one added.
+    pub fn wrapped(before: u32, share: u32) -> bool {
+        before.checked_add(share).is_none()
+    }
+
+    /// Adds one particle's [`Convergence::fixed`] error, on the cpu.
+    #[cfg(not(target_arch = "spirv"))]
+    pub fn add(&mut self, share: u32) {
+        if Self::wrapped(self.error, share) {
+            self.carry += 1;
+        }
+        self.error = self.error.wrapping_add(share);
+    }
+
+    /// Ends an iteration, after every particle has added its error. Stops the
+    /// solver once it has run `min_iterations` and the average error over
+    /// `fluid` particles is within `tolerance`.
+    pub fn check(&mut self, fluid: u32, tolerance: f32, min_iterations: u32) {
+        let total = self.carry as f32 * 4_294_967_296.0 + self.error as f32;
+        let average = total / ERROR_SCALE / fluid.max(1) as f32;
+
+        if self.iterations >= min_iterations && average <= tolerance {
+            self.done = 1;
+        } else {
+            self.iterations += 1;
+        }
+
+        self.error = 0;
+        self.carry = 0;
+    }
+}
+
+/// Shared by every invocation of the DFSPH kernels, and reset each step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct SolverState {
+    pub density: Convergence,
+    pub divergence: Convergence,
+}
+
+/// Reads and writes the solver by name in scene files.
+#[cfg(not(target_arch = "spirv"))]
+pub(crate) mod by_name {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(solver: &u32, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(super::name(*solver))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+        let name = String::deserialize(d)?;
+
+        super::ALL
+            .into_iter()
+            .find(|&solver| super::name(solver) == name.to_ascii_lowercase())
+            .ok_or_else(|| D::Error::custom(format!("unknown solver {name:?}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_sum_carries_past_a_million_particles() {
+        const FLUID: u32 = 4_000_000;
+
+        let mut convergence = Convergence::default();
+        for _ in 0..FLUID {
+            convergence.add(Convergence::fixed(0.1));
+        }
+        assert!(convergence.carry > 0);
+
+        // an average of 0.1 is far outside a tolerance of 0.05
+        convergence.check(FLUID, 0.05, 0);
+        assert_eq!(convergence.done, 0);
+
+        for _ in 0..FLUID {
+            convergence.add(Convergence::fixed(0.01));
+        }
+        convergence.check(FLUID, 0.05, 0);
+        assert_eq!(convergence.done, 1);
+    }
+}
@@ -0,0 +1,299 @@
+use glam::{IVec3, Quat, Vec3, ivec3, vec3};
+#[cfg(target_arch = "spirv")]
+use spirv_std::{glam, num_traits::Float};
+
+use crate::Settings;
+
+pub const NEIGHBORS: [IVec3; 27] = const {
+    let mut neighbors = [ivec3(0, 0, 0); 27];
+    const OFFSETS: [i32; 3] = [-1, 0, 1];
+
+    let mut x = 0;
+    let mut idx = 0;
+    while x < 3 {
+        let mut y = 0;
+        while y < 3 {
+            let mut z = 0;
+            while z < 3 {
+                neighbors[idx] = ivec3(OFFSETS[x], OFFSETS[y], OFFSETS[z]);
+                z += 1;
+                idx += 1;
+            }
+            y += 1;
+        }
+        x += 1;
+    }
+
+    neighbors
+};
+
+/// Convert position to grid cell coordinates
+pub fn pos_to_cell(pos: Vec3, cell_size: f32) -> IVec3 {
+    (pos / cell_size).floor().as_ivec3()
+}
+
+/// The cells of the hash. While an axis of the box is periodic they are laid
+/// out in the frame of the box, so the cells and the distances between
+/// particles wrap around with it.
+#[derive(Clone, Copy)]
+pub struct Grid {
+    cell_size: f32,
+    size: Vec3,
+    corner: Vec3,
+    rot: Quat,
+    /// Cells across each periodic axis, zero across the walled ones
+    cells: IVec3,
+}
+
+impl Grid {
+    /// Axes narrower than two cells can't wrap and are hashed as if walled.
+    pub fn new(settings: &Settings) -> Self {
+        let cell_size = settings.smoothing_radius;
+        let size = settings.box_size;
+        let periodic = settings.periodic_axes();
+        let across = |on: bool, side: f32| {
+            let n = (side / cell_size) as i32;
+            if on && n >= 2 { n } else { 0 }
+        };
+
+        Self {
+            cell_size,
+            size,
+            corner: settings.box_position,
+            rot: settings.box_quat,
+            cells: ivec3(
+                across(periodic.x, size.x),
+                across(periodic.y, size.y),
+                across(periodic.z, size.z),
+            ),
+        }
+    }
+
+    fn wraps(&self) -> bool {
+        self.cells != IVec3::ZERO
+    }
+
+    /// The cell `pos` falls in. The last cell across a periodic axis takes in
+    /// what's left of the box, so no cell is narrower than the smoothing
+    /// radius.
+    pub fn cell(&self, pos: Vec3) -> IVec3 {
+        if !self.wraps() {
+            return pos_to_cell(pos, self.cell_size);
+        }
+
+        let local = self.rot.conjugate() * (pos - self.corner);
+        let axis = |x: f32, side: f32, cells: i32| {
+            if cells == 0 {
+                return (x / self.cell_size).floor() as i32;
+            }
+
+            let x = x - side * (x / side).floor();
+            ((x / self.cell_size) as i32).min(cells - 1)
+        };
+
+        ivec3(
+            axis(local.x, self.size.x, self.cells.x),
+            axis(local.y, self.size.y, self.cells.y),
+            axis(local.z, self.size.z, self.cells.z),
+        )
+    }
+
+    /// The cell at `offset` from `cell`, one of [`NEIGHBORS`], and whether
+    /// to visit it. Across a periodic axis two cells wide, a step either way
+    /// lands in the same cell, which is only visited once.
+    pub fn neighbor(&self, cell: IVec3, offset: IVec3) -> (IVec3, bool) {
+        if !self.wraps() {
+            return (cell + offset, true);
+        }
+
+        let axis = |c: i32, o: i32, cells: i32| {
+            if cells == 0 {
+                (c + o, true)
+            } else {
+                ((c + o + cells) % cells, cells > 2 || o < 1)
+            }
+        };
+
+        let (x, vx) = axis(cell.x, offset.x, self.cells.x);
+        let (y, vy) = axis(cell.y, offset.y, self.cells.y);
+        let (z, vz) = axis(cell.z, offset.z, self.cells.z);
+
+        (ivec3(x, y, z), vx && vy && vz)
+    }
+
+    /// From `from` to the nearest image of `to`.
+    pub fn offset(&self, from: Vec3, to: Vec3) -> Vec3 {
+        let offset = to - from;
+        if !self.wraps() {
+            return offset;
+        }
+
+        let local = self.rot.conjugate() * offset;
+        let axis = |d: f32, side: f32, cells: i32| {
+            if cells == 0 {
+                d
+            } else {
+                d - side * (d / side).round()
+            }
+        };
+
+        self.rot
+            * vec3(
+                axis(local.x, self.size.x, self.cells.x),
+                axis(local.y, self.size.y, self.cells.y),
+                axis(local.z, self.size.z, self.cells.z),
+            )
+    }
+}
+
+/// Hash a cell coordinate to a hash value
+pub fn cell_hash(IVec3 { x, y, z }: IVec3) -> u32 {
+    const P: IVec3 = ivec3(391, 193, 719); // primes
+    let hash = (x * P.x) ^ (y * P.y) ^ (z * P.z);
+    hash as u32
+}
+
+/// Convert hash to key (modulo num_particles for bucket assignment)
+pub fn key_from_hash(hash: u32, num_particles: u32) -> u32 {
+    hash % num_particles
+}
+
+/// Convert cell directly to key
+pub fn cell_key(cell: IVec3, num_particles: u32) -> u32 {
+    let hash = cell_hash(cell);
+    key_from_hash(hash, num_particles)
+}
+
+/// Convert position directly to key (convenience function)
+pub fn pos_to_key(pos: Vec3, cell_size: f32, num_particles: u32) -> u32 {
+    cell_key(pos_to_cell(pos, cell_size), num_particles)
+}
+
+/// Get the range of particles for a given key (old approach - kept for
+/// compatibility) Note: This is slower than the new while-loop approach in the
+/// shaders
+pub fn get_by_key(key: u32, starts: &[u32], num_particles: u32) -> (u32, u32) {
+    if key >= num_particles {
+        return (0, 0);
+    }
+
+    let idx = starts[key as usize];
+    if idx == u32::MAX {
+        return (0, 0);
+    }
+
+    let mut end = num_particles;
+    for i in key + 1..end {
+        if starts[i as usize] != u32::MAX {
+            end = starts[i as usize];
+            break;
+        }
+    }
+
+    if end <= idx {
+        return (0, 0);
+    }
+
+    (idx, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a box four smoothing radii wide, away from the origin
+    fn grid(periodic: u32, box_quat: Quat) -> Grid {
+        Grid::new(&Settings {
+            smoothing_radius: 1.0,
+            box_size: Vec3::splat(4.0),
+            box_position: vec3(1.0, 2.0, 3.0),
+            box_quat,
+            periodic,
+            ..Settings::default()
+        })
+    }
+
+    // the 26 directions to the faces, edges and corners of a box
+    fn directions() -> impl Iterator<Item = Vec3> {
+        NEIGHBORS
+            .iter()
+            .filter(|&&o| o != IVec3::ZERO)
+            .map(|o| o.as_vec3())
+    }
+
+    #[test]
+    fn cells_wrap_around_the_box() {
+        let grid = grid(0b111, Quat::IDENTITY);
+        let corner = vec3(1.0, 2.0, 3.0);
+
+        assert_eq!(grid.cell(corner + vec3(0.5, 1.5, 3.5)), ivec3(0, 1, 3));
+        assert_eq!(grid.cell(corner + vec3(4.5, -0.5, 8.5)), ivec3(0, 3, 0));
+    }
+
+    #[test]
+    fn last_cell_takes_the_rest_of_the_box() {
+        let grid = Grid::new(&Settings {
+            smoothing_radius: 1.0,
+            box_size: Vec3::splat(4.5),
+            periodic: 0b111,
+            ..Settings::default()
+        });
+
+        assert_eq!(grid.cell(Vec3::splat(4.3)), IVec3::splat(3));
+    }
+
+    #[test]
+    fn neighbors_wrap_around_the_box() {
+        let grid = grid(0b001, Quat::IDENTITY);
+
+        assert_eq!(
+            grid.neighbor(ivec3(3, 5, 5), IVec3::X),
+            (ivec3(0, 5, 5), true)
+        );
+        assert_eq!(
+            grid.neighbor(ivec3(0, 5, 5), -IVec3::X),
+            (ivec3(3, 5, 5), true)
+        );
+        // the walled axes carry on past the box
+        assert_eq!(
+            grid.neighbor(ivec3(0, 0, 0), -IVec3::Y),
+            (ivec3(0, -1, 0), true)
+        );
+
+        // across two cells, both steps land in the same one
+        let narrow = Grid::new(&Settings {
+            smoothing_radius: 1.0,
+            box_size: Vec3::splat(2.0),
+            periodic: 0b001,
+            ..Settings::default()
+        });
+        assert_eq!(narrow.neighbor(IVec3::ZERO, -IVec3::X), (IVec3::X, true));
+        assert_eq!(narrow.neighbor(IVec3::ZERO, IVec3::X), (IVec3::X, false));
+    }
+
+    #[test]
+    fn offsets_reach_the_nearest_image_at_every_face_and_corner() {
+        for rotation in [Quat::IDENTITY, Quat::from_rotation_y(0.7)] {
+            let grid = grid(0b111, rotation);
+            let center = vec3(1.0, 2.0, 3.0) + rotation * Vec3::splat(2.0);
+
+            for dir in directions() {
+                // near one side, and near the other across the box
+                let from = center + rotation * (dir * 1.8);
+                let to = center - rotation * (dir * 1.8);
+
+                let offset = grid.offset(from, to);
+                let expected = rotation * (dir * 0.4);
+                assert!(offset.distance(expected) < 1e-5, "{dir}: {offset}");
+            }
+        }
+    }
+
+    #[test]
+    fn offsets_stay_inside_walled_axes() {
+        let grid = grid(0b001, Quat::IDENTITY);
+        let offset = grid.offset(vec3(1.2, 2.2, 3.2), vec3(4.8, 5.8, 6.8));
+
+        assert!(offset.distance(vec3(-0.4, 3.6, 3.6)) < 1e-5, "{offset}");
+    }
+}
@@ -0,0 +1,134 @@
+//! Adaptive time steps, chosen each step from how fast the particles move and
+//! accelerate.
+
+#[cfg(not(target_arch = "spirv"))]
+use bytemuck::{Pod, Zeroable};
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use crate::{Settings, materials::Materials, rheology};
+
+/// Shared by the time step kernels. The speeds are `f32` bits, which order the
+/// same as the floats while positive, so they can be maxed with integer
+/// atomics.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Pod, Zeroable))]
+#[repr(C)]
+pub struct TimeStep {
+    /// The longest step allowed, written by the host before each substep
+    pub requested: f32,
+    /// The step chosen for the current step, and then the last one
+    pub dtime: f32,
+    pub max_speed: u32,
+    pub max_acceleration: u32,
+}
+
+impl TimeStep {
+    /// The longest step under `requested` that no particle moves more than
+    /// `cfl_number` smoothing radii in, and that the forces and viscosity
+    /// stay stable over, in the most viscous phase at its most viscous.
+    pub fn choose(&self, settings: &Settings, materials: &Materials) -> f32 {
+        if self.requested <= 0.0 {
+            return 0.0;
+        }
+
+        let h = settings.smoothing_radius;
+        let speed = f32::from_bits(self.max_speed);
+        let acceleration = f32::from_bits(self.max_acceleration);
+        let mut dtime = self.requested;
+
+        if speed > 0.0 {
+            dtime = dtime.min(settings.cfl_number * h / speed);
+        }
+        if acceleration > 0.0 {
+            dtime = dtime.min(settings.cfl_number * (h / acceleration).sqrt());
+        }
+
+        // the viscosity kernel integrates to one over `target_density / mass`
+        // neighbors, the same in every phase
+        let peak = rheology::peak(settings, materials.max_viscosity());
+        let viscosity = peak * settings.target_density / settings.mass;
+        if viscosity > 0.0 {
+            dtime = dtime.min(0.5 / viscosity);
+        }
+
+        dtime.max(settings.min_dtime.min(self.requested))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::Material;
+
+    fn fluid(viscosity_strength: f32) -> (Settings, Materials) {
+        let settings = Settings {
+            smoothing_radius: 0.5,
+            cfl_number: 0.4,
+            min_dtime: 1e-4,
+            ..Settings::default()
+        };
+        let mut materials = Materials::default();
+        materials.phases[0] = Material {
+            mass: settings.mass,
+            target_density: settings.target_density,
+            viscosity_strength,
+            ..Material::default()
+        };
+
+        (settings, materials)
+    }
+
+    fn step(requested: f32, speed: f32, acceleration: f32) -> TimeStep {
+        TimeStep {
+            requested,
+            dtime: 0.0,
+            max_speed: speed.to_bits(),
+            max_acceleration: acceleration.to_bits(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_requested_step_at_rest() {
+        let (settings, materials) = fluid(0.0);
+        assert_eq!(step(0.01, 0.0, 0.0).choose(&settings, &materials), 0.01);
+        assert_eq!(step(0.0, 100.0, 0.0).choose(&settings, &materials), 0.0);
+    }
+
+    #[test]
+    fn fast_particles_move_a_fraction_of_the_radius() {
+        let (settings, materials) = fluid(0.0);
+        let dtime = step(0.01, 100.0, 0.0).choose(&settings, &materials);
+        assert!((dtime - 0.4 * 0.5 / 100.0).abs() < 1e-7);
+    }
+
+    #[test]
+    fn accelerating_particles_shorten_the_step() {
+        let (settings, materials) = fluid(0.0);
+        let dtime = step(0.01, 0.0, 5000.0).choose(&settings, &materials);
+        assert!((dtime - 0.4 * (0.5f32 / 5000.0).sqrt()).abs() < 1e-7);
+
+        // the tighter of the two bounds wins
+        let both = step(0.01, 100.0, 5000.0).choose(&settings, &materials);
+        assert_eq!(both, dtime.min(0.4 * 0.5 / 100.0));
+    }
+
+    #[test]
+    fn viscous_phases_shorten_the_step() {
+        let (settings, materials) = fluid(2.0);
+        let dtime = step(1.0, 0.0, 0.0).choose(&settings, &materials);
+        let viscosity = 2.0 * settings.target_density / settings.mass;
+        assert!((dtime - 0.5 / viscosity).abs() < 1e-6);
+    }
+
+    #[test]
+    fn steps_never_drop_below_the_floor() {
+        let (settings, materials) = fluid(0.0);
+        let dtime = step(0.01, 1e6, 1e9).choose(&settings, &materials);
+        assert_eq!(dtime, settings.min_dtime);
+
+        // unless less than the floor was asked for
+        let dtime = step(1e-5, 1e6, 1e9).choose(&settings, &materials);
+        assert_eq!(dtime, 1e-5);
+    }
+}
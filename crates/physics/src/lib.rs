@@ -3,9 +3,24 @@
 
 use core::f32;
 
-use gpu_shared::{ARRAY_LEN, Globals, MouseState, Primitive, SCALE, Settings};
+use gpu_shared::{
+    Globals, MouseState, Primitive, SCALE, Settings,
+    bodies::{self, Bodies, FixedSum},
+    colors::Coloring,
+    curves,
+    flow::{COMPACT_BLOCK, Flow, FlowState},
+    jitter, kernel,
+    materials::Materials,
+    obstacles::{Obstacles, SdfGrids},
+    rheology,
+    solver::{self, Convergence, SolverState},
+    sp_hash,
+    timestep::TimeStep,
+};
 use spirv_std::{
-    glam::{UVec3, Vec2, Vec3, Vec4, vec2, vec3, vec4},
+    arch::{atomic_i_add, atomic_u_max},
+    glam::{Mat3, UVec2, UVec3, Vec2, Vec3, Vec4, vec2, vec3, vec4},
+    memory::{Scope, Semantics},
     num_traits::Float,
     spirv,
 };
@@ -26,10 +41,6 @@ fn q_rsqrt(value: f32) -> f32 {
 // +Y == UP
 // +Z == OUT OF SCREEN
 
-pub mod curves;
-pub mod gradient;
-pub mod sp_hash;
-
 #[spirv(fragment(depth_replacing))]
 pub fn fs_main(
     in_view_center: Vec3,
@@ -76,14 +87,24 @@ pub fn vs_main(
     #[spirv(instance_index)] instance_idx: u32,
     #[spirv(uniform, descriptor_set = 0, binding = 0)] globals: &Globals,
     #[spirv(uniform, descriptor_set = 0, binding = 1)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] primitives: &[Primitive; ARRAY_LEN],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] primitives: &[Primitive],
     #[spirv(position)] out_pos: &mut Vec4,
 
     out_view_center: &mut Vec3,
     out_quad: &mut Vec2,
     out_color: &mut Vec4,
 ) {
-    let prim = primitives[(a_prim_id + instance_idx) as usize];
+    // instances past the particles in use collapse to nothing
+    let id = a_prim_id + instance_idx;
+    if id >= settings.num_particles {
+        *out_pos = Vec4::ZERO;
+        *out_view_center = Vec3::ZERO;
+        *out_quad = Vec2::ZERO;
+        *out_color = Vec4::ZERO;
+        return;
+    }
+
+    let prim = primitives[id as usize];
     let r = settings.particle_radius;
 
     let view_center = (globals.view * prim.translate.extend(1.0)).truncate();
@@ -111,10 +132,11 @@ pub fn vs_lines(
 #[spirv(compute(threads(256)))]
 pub fn external_forces(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(uniform, descriptor_set = 0, binding = 1)] _mouse: &MouseState,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] velocities: &mut [Vec4; ARRAY_LEN],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] mouse: &MouseState,
+    #[spirv(uniform, descriptor_set = 0, binding = 2)] globals: &Globals,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] velocities: &mut [Vec4],
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
@@ -124,8 +146,19 @@ pub fn external_forces(
     }
 
     let idx = id as usize;
-    let gravity = settings.gravity;
-    let force = gravity;
+    let mut force = settings.gravity;
+
+    if mouse.active() {
+        let (target, hit) = mouse.target(globals, settings);
+        if hit {
+            force = mouse.acceleration(
+                target,
+                positions[idx].truncate(),
+                velocities[idx].truncate(),
+                settings,
+            );
+        }
+    }
 
     velocities[idx] += (force * settings.dtime).extend(0.0);
 
@@ -134,37 +167,89 @@ pub fn external_forces(
     predictions[idx] = positions[idx] + velocities[idx] * LOOKAHEAD;
 }
 
+/// Maxes the speed and acceleration of each particle into `timestep`.
+#[spirv(compute(threads(256)))]
+pub fn measure_step(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] previous: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] timestep: &mut TimeStep,
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let velocity = velocities[idx].truncate();
+
+    unsafe {
+        atomic_u_max::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+            &mut timestep.max_speed,
+            velocity.length().to_bits(),
+        );
+    }
+
+    // no previous step to compare against after a reset or pause
+    if timestep.dtime > 0.0 {
+        let acceleration = (velocity - previous[idx].truncate()).length() / timestep.dtime;
+
+        unsafe {
+            atomic_u_max::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                &mut timestep.max_acceleration,
+                acceleration.to_bits(),
+            );
+        }
+    }
+}
+
+#[spirv(compute(threads(1)))]
+pub fn choose_step(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] timestep: &mut TimeStep,
+) {
+    timestep.dtime = timestep.choose(settings, materials);
+    timestep.max_speed = 0;
+    timestep.max_acceleration = 0;
+}
+
 #[spirv(compute(threads(256)))]
 pub fn pre_sort(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32; ARRAY_LEN],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
     let id = id.x;
-    if id >= settings.num_particles {
+    if id >= settings.max_particles {
         return;
     }
 
     let idx = id as usize;
+    lookup[idx] = id;
+
+    // past the particles in use, which the sort leaves at the end
+    if id >= settings.num_particles {
+        keys[idx] = u32::MAX;
+        return;
+    }
 
     starts[idx] = u32::MAX;
-    lookup[idx] = id;
-    keys[idx] = sp_hash::pos_to_key(
-        predictions[idx].truncate(),
-        settings.smoothing_radius,
-        settings.num_particles,
-    );
+    let cell = sp_hash::Grid::new(settings).cell(predictions[idx].truncate());
+    keys[idx] = sp_hash::cell_key(cell, settings.num_particles);
 }
 
 #[spirv(compute(threads(256)))]
 pub fn post_sort(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] starts: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] keys: &mut [u32; ARRAY_LEN],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] keys: &mut [u32],
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
@@ -183,11 +268,14 @@ pub fn post_sort(
 #[spirv(compute(threads(256)))]
 pub fn update_densities(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] densities: &mut [Vec2; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32; ARRAY_LEN],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] deltas: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
@@ -201,13 +289,20 @@ pub fn update_densities(
 
     let idx = id as usize;
     let my_pos = predictions[idx].truncate();
-    let cell = sp_hash::pos_to_cell(my_pos, settings.smoothing_radius);
+    let me = materials.of(velocities[idx]);
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
     let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
     let mut density = 0.0;
     let mut near_density = 0.0;
+    let mut nudge = Vec3::ZERO;
+    let mut neighbors = 0;
 
     for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
-        let other_cell = cell + sp_hash::NEIGHBORS[neighbor_id];
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
         let hash = sp_hash::cell_hash(other_cell);
         let key = sp_hash::key_from_hash(hash, settings.num_particles);
         let start = starts[key as usize];
@@ -221,7 +316,7 @@ pub fn update_densities(
             let other_id = lookup[i as usize];
             let other_idx = other_id as usize;
 
-            let offset = predictions[other_idx].truncate() - my_pos;
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
             let dist_sq = offset.dot(offset);
 
             if dist_sq > smoothing_radius_sq {
@@ -231,36 +326,66 @@ pub fn update_densities(
             let dist = dist_sq.sqrt();
 
             if dist < 0.5 * settings.particle_radius {
-                let rng_x = ((id as f32) * 12.9898).sin() * 43758.54;
-                let rng_x = rng_x - rng_x.floor();
-                let rng_y = ((id as f32) * 78.233).sin() * 43758.54;
-                let rng_y = rng_y - rng_y.floor();
-                let rng_z = ((id as f32) * 45.164).sin() * 43758.54;
-                let rng_z = rng_z - rng_z.floor();
-                let random_offset = vec3(rng_x - 0.5, rng_y - 0.5, rng_z - 0.5) * 0.02;
-                predictions[idx] += random_offset.extend(0.0);
+                nudge += jitter(id, settings.jitter_seed);
             }
 
-            let influence = curves::density(dist, settings.smoothing_radius);
+            let influence = kernel::value(settings.kernel, dist, settings.smoothing_radius);
             let near_influence = curves::density_near(dist, settings.smoothing_radius);
 
-            density += settings.mass * influence;
-            near_density += settings.mass * near_influence;
+            // fluid neighbors count at this particle's mass, so the density
+            // doesn't jump where two phases meet (Solenthaler and Pajarola
+            // 2008), boundary ones at their volume (Akinci et al. 2012)
+            let weight = if other_id < settings.boundary_particles {
+                me.target_density * densities[other_idx].x
+            } else {
+                me.mass
+            };
+
+            neighbors += 1;
+            density += weight * influence;
+            near_density += weight * near_influence;
         }
     }
 
     densities[idx] = vec2(density, near_density);
+    // w is otherwise unused, keep the neighbor count there for coloring
+    predictions[idx].w = neighbors as f32;
+    // the neighbors may still be reading this particle, so kernels that move
+    // it or change its velocity write the difference to the deltas and leave
+    // adding it to a pass of its own: `apply_jitter` here, `pbf_apply` and
+    // `apply_forces` further on
+    deltas[idx] = nudge.extend(0.0);
+}
+
+/// Moves each particle by the jitter `update_densities` or `pbf_lambda` left
+/// in the deltas.
+#[spirv(compute(threads(256)))]
+pub fn apply_jitter(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] deltas: &mut [Vec4],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    predictions[idx] += deltas[idx];
 }
 
 #[spirv(compute(threads(256)))]
 pub fn pressure_force(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32; ARRAY_LEN],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
@@ -276,14 +401,13 @@ pub fn pressure_force(
     let this_density = densities[idx].x;
     let this_ndensity = densities[idx].y;
     let this_position = predictions[idx].truncate();
-    let this_pressure = curves::density_to_pressure(
-        this_density,
-        settings.target_density,
-        settings.pressure_multiplier,
-    );
+    let this_target = materials.of(velocities[idx]).target_density;
+    let this_pressure =
+        curves::density_to_pressure(this_density, this_target, settings.pressure_multiplier);
     let this_npressure = this_ndensity * settings.near_pressure_multiplier;
 
-    let cell = sp_hash::pos_to_cell(this_position, settings.smoothing_radius);
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(this_position);
     let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
 
     let this_pressure_term = this_pressure / this_density.powi(2);
@@ -292,7 +416,10 @@ pub fn pressure_force(
     let mut force = Vec3::ZERO;
 
     for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
-        let other_cell = cell + sp_hash::NEIGHBORS[neighbor_id];
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
         let hash = sp_hash::cell_hash(other_cell);
         let key = sp_hash::key_from_hash(hash, settings.num_particles);
         let start = starts[key as usize];
@@ -312,7 +439,7 @@ pub fn pressure_force(
                 continue;
             }
 
-            let offset = predictions[other_idx].truncate() - this_position;
+            let offset = grid.offset(this_position, predictions[other_idx].truncate());
             let dist_sq = offset.dot(offset);
 
             if dist_sq > smoothing_radius_sq || dist_sq < f32::EPSILON {
@@ -323,41 +450,40 @@ pub fn pressure_force(
             let dist = 1.0 / inv_dist;
             let dir = offset * inv_dist;
 
-            let other_is_boundary = other_id < settings.boundary_particles;
-
-            let other_density: f32;
-            let other_ndensity: f32;
-            let other_pressure: f32;
-            let other_npressure: f32;
+            let smoothing_term =
+                dir * kernel::deriv(settings.kernel, dist, settings.smoothing_radius);
+            let nsmoothing_term = dir * curves::ndensity_deriv(dist, settings.smoothing_radius);
 
-            if other_is_boundary {
-                other_density = settings.target_density;
-                other_ndensity = this_ndensity;
-                other_pressure = this_pressure.max(0.0);
-                other_npressure = this_npressure;
-            } else {
-                other_density = densities[other_idx].x;
-                other_ndensity = densities[other_idx].y;
-                other_pressure = curves::density_to_pressure(
-                    other_density,
-                    settings.target_density,
-                    settings.pressure_multiplier,
-                );
-                other_npressure = other_ndensity * settings.near_pressure_multiplier;
+            // boundary particles only push, with this particle's pressure
+            // over their volume (Akinci et al. 2012)
+            if other_id < settings.boundary_particles {
+                let psi = this_target * densities[other_idx].x;
+                let pressure_term = this_pressure.max(0.0) / this_density.powi(2);
+                force += psi * pressure_term * smoothing_term;
+                force += psi * this_npressure_term * nsmoothing_term;
+                continue;
             }
 
+            let other = materials.of(velocities[other_idx]);
+            let other_density = densities[other_idx].x;
+            let other_ndensity = densities[other_idx].y;
+            let other_pressure = curves::density_to_pressure(
+                other_density,
+                other.target_density,
+                settings.pressure_multiplier,
+            );
+            let other_npressure = other_ndensity * settings.near_pressure_multiplier;
+
             let other_pressure_term = other_pressure / other_density.powi(2);
             let other_npressure_term = other_npressure / other_ndensity.max(f32::EPSILON).powi(2);
 
             // Regular pressure
-            let smoothing_term = dir * curves::density_deriv(dist, settings.smoothing_radius);
             let pressure_term = this_pressure_term + other_pressure_term;
-            force += settings.mass * pressure_term * smoothing_term;
+            force += other.mass * pressure_term * smoothing_term;
 
             // Near pressure
-            let nsmoothing_term = dir * curves::ndensity_deriv(dist, settings.smoothing_radius);
             let npressure_term = this_npressure_term + other_npressure_term;
-            force += settings.mass * npressure_term * nsmoothing_term;
+            force += other.mass * npressure_term * nsmoothing_term;
         }
     }
 
@@ -365,32 +491,86 @@ pub fn pressure_force(
 }
 
 #[spirv(compute(threads(256)))]
-pub fn viscosity(
+pub fn pbf_predict(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32; ARRAY_LEN],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] mouse: &MouseState,
+    #[spirv(uniform, descriptor_set = 0, binding = 2)] globals: &Globals,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] velocities: &mut [Vec4],
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
     let id = id.x;
-    if id >= settings.num_particles {
+    if id >= settings.num_particles || id < settings.boundary_particles {
         return;
     }
-    if id < settings.boundary_particles {
+
+    let idx = id as usize;
+    let mut force = settings.gravity;
+
+    if mouse.active() {
+        let (target, hit) = mouse.target(globals, settings);
+        if hit {
+            force = mouse.acceleration(
+                target,
+                positions[idx].truncate(),
+                velocities[idx].truncate(),
+                settings,
+            );
+        }
+    }
+
+    velocities[idx] += (force * settings.dtime).extend(0.0);
+
+    // unlike sph, the constraints act on the position at the end of the step
+    predictions[idx] = (positions[idx] + velocities[idx] * settings.dtime)
+        .truncate()
+        .extend(0.0);
+}
+
+/// Density constraint `C = max(density / target - 1, 0)` and its multiplier
+/// `lambda = -C / (sum w |grad C|^2 + relaxation)`, where `w` is the inverse
+/// mass relative to phase 0.
+#[spirv(compute(threads(256)))]
+pub fn pbf_lambda(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] deltas: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
         return;
     }
 
     let idx = id as usize;
-    let position = predictions[idx].truncate();
-    let cell = sp_hash::pos_to_cell(position, settings.smoothing_radius);
+    let my_pos = predictions[idx].truncate();
+    let me = materials.of(velocities[idx]);
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
     let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
-    let mut force = Vec3::ZERO;
+    // the rest volume, the same in every phase
+    let scale = settings.mass / settings.target_density;
+
+    let mut density = 0.0;
+    let mut grad_i = Vec3::ZERO;
+    let mut grad_sq = 0.0;
+    let mut nudge = Vec3::ZERO;
+    let mut neighbors = 0;
 
     for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
-        let other_cell = cell + sp_hash::NEIGHBORS[neighbor_id];
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
         let hash = sp_hash::cell_hash(other_cell);
         let key = sp_hash::key_from_hash(hash, settings.num_particles);
         let start = starts[key as usize];
@@ -404,141 +584,1614 @@ pub fn viscosity(
             let other_id = lookup[i as usize];
             let other_idx = other_id as usize;
 
-            if idx == other_idx {
-                continue;
-            }
-
-            let offset = predictions[other_idx].truncate() - position;
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
             let dist_sq = offset.dot(offset);
 
             if dist_sq > smoothing_radius_sq {
                 continue;
             }
 
-            let dist = dist_sq.sqrt();
-            let influence = curves::viscosity(dist, settings.smoothing_radius);
+            // boundary particles count at their volume (Akinci et al. 2012)
+            let boundary = other_id < settings.boundary_particles;
+            let volume = if boundary {
+                densities[other_idx].x
+            } else {
+                scale
+            };
 
-            let other_velocity = if other_id < settings.boundary_particles {
-                Vec3::ZERO
+            let dist = dist_sq.sqrt();
+            let weight = if boundary {
+                me.target_density * volume
             } else {
-                velocities[other_idx].truncate()
+                me.mass
             };
+            density += weight * kernel::value(settings.kernel, dist, settings.smoothing_radius);
+            neighbors += 1;
+
+            if other_idx == idx {
+                continue;
+            }
+
+            // the corrections can't separate particles on top of each other
+            if dist < 0.5 * settings.particle_radius {
+                nudge += jitter(id, settings.jitter_seed);
+            }
+
+            if dist < f32::EPSILON {
+                continue;
+            }
 
-            force += (other_velocity - velocities[idx].truncate()) * influence;
+            // gradient of the constraint with respect to the neighbor
+            let grad_j = offset / dist
+                * kernel::deriv(settings.kernel, dist, settings.smoothing_radius)
+                * volume;
+            grad_i -= grad_j;
+
+            // the boundary doesn't give way
+            if !boundary {
+                grad_sq +=
+                    settings.mass / materials.of(velocities[other_idx]).mass * grad_j.dot(grad_j);
+            }
         }
     }
 
-    velocities[idx] += (force * settings.viscosity_strength * settings.dtime).extend(0.0);
+    let constraint = (density / me.target_density - 1.0).max(0.0);
+    let grad_sq = grad_sq + settings.mass / me.mass * grad_i.dot(grad_i);
+    let lambda = -constraint / (grad_sq + settings.relaxation);
+
+    densities[idx] = vec2(density, lambda);
+    predictions[idx].w = neighbors as f32;
+    deltas[idx] = nudge.extend(0.0);
 }
 
 #[spirv(compute(threads(256)))]
-pub fn update_positions(
+pub fn pbf_delta(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4; ARRAY_LEN],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] deltas: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
     let id = id.x;
-    if id >= settings.num_particles {
-        return;
-    }
-    if id < settings.boundary_particles {
+    if id >= settings.num_particles || id < settings.boundary_particles {
         return;
     }
 
     let idx = id as usize;
-    positions[idx] += velocities[idx] * settings.dtime;
+    let my_pos = predictions[idx].truncate();
+    let my_lambda = densities[idx].y;
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
+    let radius = settings.smoothing_radius;
+    let smoothing_radius_sq = radius * radius;
+    // heavier phases move less
+    let inv_mass = settings.mass / materials.of(velocities[idx]).mass;
+    let scale = settings.mass / settings.target_density * inv_mass;
+
+    let mut delta = Vec3::ZERO;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            if idx == other_idx {
+                continue;
+            }
+
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq || dist_sq < f32::EPSILON {
+                continue;
+            }
+
+            let dist = dist_sq.sqrt();
+            let grad = -offset / dist * kernel::deriv(settings.kernel, dist, radius);
+
+            // boundary particles have no constraint of their own, only this
+            // particle's over their volume
+            if other_id < settings.boundary_particles {
+                delta += my_lambda * densities[other_idx].x * inv_mass * grad;
+                continue;
+            }
+
+            // artificial pressure, relative to the kernel at 0.2h
+            let ratio = (radius - dist) / (0.8 * radius);
+            let s_corr = -settings.tensile_strength * ratio.powi(8);
+
+            let other_lambda = densities[other_idx].y;
+            delta += (my_lambda + other_lambda + s_corr) * scale * grad;
+        }
+    }
+
+    deltas[idx] = delta.extend(0.0);
 }
 
 #[spirv(compute(threads(256)))]
-pub fn collide(
+pub fn pbf_apply(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4; ARRAY_LEN],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] obstacles: &Obstacles,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] deltas: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] sdfs: &SdfGrids,
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
     let id = id.x;
-    if id >= settings.num_particles {
-        return;
-    }
-    // Boundary particles live outside [0, size]; don't clamp them back in.
-    if id < settings.boundary_particles {
+    if id >= settings.num_particles || id < settings.boundary_particles {
         return;
     }
 
     let idx = id as usize;
-    let size = settings.box_size;
-    let rot = settings.box_quat;
-    let radius = settings.particle_radius;
-    let damping = settings.collision_damping;
 
-    let pos = positions[idx].truncate();
-    let vel = velocities[idx].truncate();
+    let pos = predictions[idx].truncate() + deltas[idx].truncate();
+    let mut lpos = settings.to_box(pos);
+    obstacles.push_out(sdfs, &mut lpos, settings.particle_radius);
+    let lpos = settings.confine(lpos, settings.particle_radius);
 
-    let inv = rot.conjugate();
-    let mut lpos = inv * pos;
-    let mut lvel = inv * vel;
+    predictions[idx] = settings.from_box(lpos).extend(predictions[idx].w);
+}
 
-    if lpos.x < radius {
-        lpos.x = radius;
-        lvel.x *= -damping;
-    } else if lpos.x > size.x - radius {
-        lpos.x = size.x - radius;
-        lvel.x *= -damping;
-    }
+#[spirv(compute(threads(256)))]
+pub fn pbf_velocity(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] velocities: &mut [Vec4],
 
-    if lpos.y < radius {
-        lpos.y = radius;
-        lvel.y *= -damping;
-    } else if lpos.y > size.y - radius {
-        lpos.y = size.y - radius;
-        lvel.y *= -damping;
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles || settings.dtime <= 0.0 {
+        return;
     }
 
-    if lpos.z < radius {
-        lpos.z = radius;
-        lvel.z *= -damping;
-    } else if lpos.z > size.z - radius {
-        lpos.z = size.z - radius;
-        lvel.z *= -damping;
-    }
+    let idx = id as usize;
+    let travelled = predictions[idx].truncate() - positions[idx].truncate();
+    velocities[idx] = (travelled / settings.dtime).extend(velocities[idx].w);
+}
+
+#[spirv(compute(threads(256)))]
+pub fn dfsph_prepare(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4],
 
-    let pos = rot * lpos;
-    let vel = rot * lvel;
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
 
-    positions[idx] = pos.extend(0.0);
-    velocities[idx] = vel.extend(0.0);
+    // dfsph searches the neighbors of the current positions
+    let idx = id as usize;
+    predictions[idx] = positions[idx].truncate().extend(0.0);
 }
 
+/// Density and the factor `alpha` that turns a density error into a stiffness.
 #[spirv(compute(threads(256)))]
-pub fn copy_prims(
+pub fn dfsph_factors(
     #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4; ARRAY_LEN],
-    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] prims: &mut [Primitive; ARRAY_LEN],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
 
     #[spirv(global_invocation_id)] id: UVec3,
 ) {
     let id = id.x;
-    if id >= settings.num_particles {
+    if id >= settings.num_particles || id < settings.boundary_particles {
         return;
     }
 
     let idx = id as usize;
-    const MAX_VEL: f32 = 15.0;
+    let my_pos = predictions[idx].truncate();
+    let me = materials.of(velocities[idx]);
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
+    let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
 
-    if id < settings.boundary_particles {
-        prims[idx].translate = positions[idx].truncate();
-        prims[idx].color = vec4(0.2, 0.2, 0.2, 0.25);
-        return;
+    let mut density = 0.0;
+    let mut grad_sum = Vec3::ZERO;
+    let mut grad_sq = 0.0;
+    let mut neighbors = 0;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq {
+                continue;
+            }
+
+            // boundary particles count at their volume (Akinci et al. 2012)
+            let boundary = other_id < settings.boundary_particles;
+            let psi = me.target_density * densities[other_idx].x;
+
+            let dist = dist_sq.sqrt();
+            let weight = if boundary { psi } else { me.mass };
+            density += weight * kernel::value(settings.kernel, dist, settings.smoothing_radius);
+            neighbors += 1;
+
+            if other_idx == idx || dist < f32::EPSILON {
+                continue;
+            }
+
+            let grad =
+                -offset / dist * kernel::deriv(settings.kernel, dist, settings.smoothing_radius);
+            if boundary {
+                // the boundary doesn't give way
+                grad_sum += psi * grad;
+            } else {
+                let grad = grad * materials.of(velocities[other_idx]).mass;
+                grad_sum += grad;
+                grad_sq += grad.dot(grad);
+            }
+        }
     }
 
-    let speed = velocities[idx].length().clamp(0.0, MAX_VEL);
-    let t = speed / MAX_VEL;
-    let color = gradient::sample(gradient::VELOCITY, t);
+    let denominator = grad_sum.dot(grad_sum) + grad_sq;
+    let alpha = if denominator > 1e-6 {
+        density / denominator
+    } else {
+        0.0
+    };
 
-    prims[idx].translate = positions[idx].truncate();
-    prims[idx].color = color;
+    densities[idx] = vec2(density, alpha);
+    predictions[idx].w = neighbors as f32;
+}
+
+/// `sum m_i (v_i - v_j) . grad W_ij`, how fast the density of `idx` is
+/// growing. Boundary particles move with their body or the walls, and count
+/// at their volume.
+fn density_rate(
+    settings: &Settings,
+    materials: &Materials,
+    idx: usize,
+    predictions: &[Vec4],
+    velocities: &[Vec4],
+    densities: &[Vec2],
+    starts: &[u32],
+    lookup: &[u32],
+    keys: &[u32],
+) -> f32 {
+    let my_pos = predictions[idx].truncate();
+    let my_vel = velocities[idx].truncate();
+    let me = materials.of(velocities[idx]);
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
+    let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
+    let mut rate = 0.0;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            if idx == other_idx {
+                continue;
+            }
+
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq || dist_sq < f32::EPSILON {
+                continue;
+            }
+
+            let dist = dist_sq.sqrt();
+            let other_vel = velocities[other_idx].truncate();
+
+            let mass = if other_id < settings.boundary_particles {
+                me.target_density * densities[other_idx].x
+            } else {
+                me.mass
+            };
+
+            let grad =
+                -offset / dist * kernel::deriv(settings.kernel, dist, settings.smoothing_radius);
+            rate += mass * (my_vel - other_vel).dot(grad);
+        }
+    }
+
+    rate
+}
+
+/// `-dt sum m (k_i / rho_i + k_j / rho_j) grad W_ij`, the change in velocity
+/// from the stiffnesses. Boundary particles only take `k_i / rho_i`, over
+/// their volume.
+fn pressure_impulse(
+    settings: &Settings,
+    materials: &Materials,
+    idx: usize,
+    predictions: &[Vec4],
+    velocities: &[Vec4],
+    densities: &[Vec2],
+    stiffness: &[f32],
+    starts: &[u32],
+    lookup: &[u32],
+    keys: &[u32],
+) -> Vec3 {
+    let my_pos = predictions[idx].truncate();
+    let my_term = stiffness[idx] / densities[idx].x.max(f32::EPSILON);
+    let my_target = materials.of(velocities[idx]).target_density;
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
+    let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
+    let mut impulse = Vec3::ZERO;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            if idx == other_idx {
+                continue;
+            }
+
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq || dist_sq < f32::EPSILON {
+                continue;
+            }
+
+            let dist = dist_sq.sqrt();
+            let grad =
+                -offset / dist * kernel::deriv(settings.kernel, dist, settings.smoothing_radius);
+
+            if other_id < settings.boundary_particles {
+                let psi = my_target * densities[other_idx].x;
+                impulse -= settings.dtime * psi * my_term * grad;
+                continue;
+            }
+
+            let other_term = stiffness[other_idx] / densities[other_idx].x.max(f32::EPSILON);
+            let mass = materials.of(velocities[other_idx]).mass;
+            impulse -= settings.dtime * mass * (my_term + other_term) * grad;
+        }
+    }
+
+    impulse
+}
+
+fn add_error(convergence: &mut Convergence, error: f32) {
+    let share = Convergence::fixed(error);
+
+    unsafe {
+        let before = atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+            &mut convergence.error,
+            share,
+        );
+
+        // each add sees the sum just before it, so exactly one carries per wrap
+        if Convergence::wrapped(before, share) {
+            atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                &mut convergence.carry,
+                1,
+            );
+        }
+    }
+}
+
+#[spirv(compute(threads(256)))]
+pub fn dfsph_divergence_source(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] stiffness: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 4)] state: &mut SolverState,
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+    if state.divergence.done != 0 || settings.dtime <= 0.0 {
+        return;
+    }
+
+    let idx = id as usize;
+    // only compression is corrected, so the free surface can separate
+    let rate = density_rate(
+        settings,
+        materials,
+        idx,
+        predictions,
+        velocities,
+        densities,
+        starts,
+        lookup,
+        keys,
+    )
+    .max(0.0);
+
+    let target = materials.of(velocities[idx]).target_density;
+    stiffness[idx] = rate * densities[idx].y / settings.dtime;
+    add_error(&mut state.divergence, rate * settings.dtime / target);
+}
+
+#[spirv(compute(threads(1)))]
+pub fn dfsph_divergence_check(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] state: &mut SolverState,
+) {
+    let fluid = settings.num_particles - settings.boundary_particles;
+    state
+        .divergence
+        .check(fluid, settings.divergence_tolerance, 1);
+}
+
+#[spirv(compute(threads(256)))]
+pub fn dfsph_divergence_apply(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] stiffness: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 4)] state: &mut SolverState,
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+    // nothing more to apply, and nothing more for the bodies to feel
+    if state.divergence.done != 0 {
+        stiffness[id as usize] = 0.0;
+        return;
+    }
+
+    let idx = id as usize;
+    let impulse = pressure_impulse(
+        settings,
+        materials,
+        idx,
+        predictions,
+        velocities,
+        densities,
+        stiffness,
+        starts,
+        lookup,
+        keys,
+    );
+
+    velocities[idx] += impulse.extend(0.0);
+}
+
+#[spirv(compute(threads(256)))]
+pub fn dfsph_forces(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] mouse: &MouseState,
+    #[spirv(uniform, descriptor_set = 0, binding = 2)] globals: &Globals,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let mut force = settings.gravity;
+
+    if mouse.active() {
+        let (target, hit) = mouse.target(globals, settings);
+        if hit {
+            force = mouse.acceleration(
+                target,
+                positions[idx].truncate(),
+                velocities[idx].truncate(),
+                settings,
+            );
+        }
+    }
+
+    velocities[idx] += (force * settings.dtime).extend(0.0);
+}
+
+#[spirv(compute(threads(256)))]
+pub fn dfsph_density_source(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] stiffness: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 4)] state: &mut SolverState,
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+    if state.density.done != 0 || settings.dtime <= 0.0 {
+        return;
+    }
+
+    let idx = id as usize;
+    let rate = density_rate(
+        settings,
+        materials,
+        idx,
+        predictions,
+        velocities,
+        densities,
+        starts,
+        lookup,
+        keys,
+    );
+
+    // the density at the end of the step, if nothing else changes
+    let target = materials.of(velocities[idx]).target_density;
+    let predicted = densities[idx].x + settings.dtime * rate;
+    let error = (predicted - target).max(0.0);
+
+    stiffness[idx] = error * densities[idx].y / (settings.dtime * settings.dtime);
+    add_error(&mut state.density, error / target);
+}
+
+#[spirv(compute(threads(1)))]
+pub fn dfsph_density_check(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] state: &mut SolverState,
+) {
+    let fluid = settings.num_particles - settings.boundary_particles;
+    state.density.check(fluid, settings.density_tolerance, 2);
+}
+
+#[spirv(compute(threads(256)))]
+pub fn dfsph_density_apply(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] stiffness: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 4)] state: &mut SolverState,
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+    // nothing more to apply, and nothing more for the bodies to feel
+    if state.density.done != 0 {
+        stiffness[id as usize] = 0.0;
+        return;
+    }
+
+    let idx = id as usize;
+    let impulse = pressure_impulse(
+        settings,
+        materials,
+        idx,
+        predictions,
+        velocities,
+        densities,
+        stiffness,
+        starts,
+        lookup,
+        keys,
+    );
+
+    velocities[idx] += impulse.extend(0.0);
+}
+
+/// Scaled surface normal of each particle, pointing out of the fluid. Zero
+/// away from the surface.
+#[spirv(compute(threads(256)))]
+pub fn surface_normals(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] normals: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let my_pos = predictions[idx].truncate();
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
+    let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
+    let mut normal = Vec3::ZERO;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            // the walls aren't a surface
+            if idx == other_idx || other_id < settings.boundary_particles {
+                continue;
+            }
+
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq || dist_sq < f32::EPSILON {
+                continue;
+            }
+
+            let dist = dist_sq.sqrt();
+            let grad =
+                -offset / dist * kernel::deriv(settings.kernel, dist, settings.smoothing_radius);
+            let mass = materials.of(velocities[other_idx]).mass;
+            normal -= mass / densities[other_idx].x.max(f32::EPSILON) * grad;
+        }
+    }
+
+    normals[idx] = (normal * settings.smoothing_radius).extend(0.0);
+}
+
+/// Cohesion and curvature forces after Akinci et al. 2013, at the average
+/// surface tension of each pair of phases.
+#[spirv(compute(threads(256)))]
+pub fn surface_tension(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] normals: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 4)] deltas: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let my_pos = predictions[idx].truncate();
+    let my_density = densities[idx].x;
+    let my_normal = normals[idx].truncate();
+    let me = materials.of(velocities[idx]);
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
+    let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
+    let mut force = Vec3::ZERO;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            if idx == other_idx || other_id < settings.boundary_particles {
+                continue;
+            }
+
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq || dist_sq < f32::EPSILON {
+                continue;
+            }
+
+            let dist = dist_sq.sqrt();
+            let other = materials.of(velocities[other_idx]);
+
+            // pulls harder where the density is low, at the surface
+            let correction = (me.target_density + other.target_density)
+                / (my_density + densities[other_idx].x).max(f32::EPSILON);
+
+            let cohesion =
+                offset / dist * other.mass * curves::cohesion(dist, settings.smoothing_radius);
+            let curvature = normals[other_idx].truncate() - my_normal;
+            let tension = 0.5 * (me.surface_tension + other.surface_tension);
+
+            force += tension * correction * (cohesion + curvature);
+        }
+    }
+
+    deltas[idx] = (force * settings.dtime).extend(0.0);
+}
+
+/// How fast the flow shears around each fluid particle, `sqrt(2 D:D)` of the
+/// symmetric part `D` of its velocity gradient, for the non-Newtonian
+/// viscosities. The walls count at their own velocity and volume.
+#[spirv(compute(threads(256)))]
+pub fn shear_rates(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] shear: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let my_pos = predictions[idx].truncate();
+    let my_velocity = velocities[idx].truncate();
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
+    let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
+    let mut gradient = Mat3::ZERO;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            if idx == other_idx {
+                continue;
+            }
+
+            let offset = grid.offset(my_pos, predictions[other_idx].truncate());
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq || dist_sq < f32::EPSILON {
+                continue;
+            }
+
+            let dist = dist_sq.sqrt();
+            let grad =
+                -offset / dist * kernel::deriv(settings.kernel, dist, settings.smoothing_radius);
+            let volume = if other_id < settings.boundary_particles {
+                densities[other_idx].x
+            } else {
+                let mass = materials.of(velocities[other_idx]).mass;
+                mass / densities[other_idx].x.max(f32::EPSILON)
+            };
+
+            let dv = (velocities[other_idx].truncate() - my_velocity) * volume;
+            gradient += Mat3::from_cols(dv * grad.x, dv * grad.y, dv * grad.z);
+        }
+    }
+
+    let strain = (gradient + gradient.transpose()) * 0.5;
+    let strain_sq = strain.x_axis.length_squared()
+        + strain.y_axis.length_squared()
+        + strain.z_axis.length_squared();
+
+    shear[idx] = (2.0 * strain_sq).sqrt();
+}
+
+/// Evens out the velocities of neighbors, at the viscosity of each phase's
+/// model for the shear rates of the pair.
+#[spirv(compute(threads(256)))]
+pub fn viscosity(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] shear: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] deltas: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles {
+        return;
+    }
+    if id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let position = predictions[idx].truncate();
+    let my_shear = shear[idx];
+    let strength = rheology::viscosity(
+        settings,
+        materials.of(velocities[idx]).viscosity_strength,
+        my_shear,
+    );
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(position);
+    let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
+    let mut force = Vec3::ZERO;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            if idx == other_idx {
+                continue;
+            }
+
+            let offset = grid.offset(position, predictions[other_idx].truncate());
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq {
+                continue;
+            }
+
+            let dist = dist_sq.sqrt();
+            let influence =
+                kernel::value(settings.viscosity_kernel, dist, settings.smoothing_radius);
+
+            // the walls' motion for the box, the body's for body particles
+            let other_velocity = velocities[other_idx].truncate();
+            // the walls shear at the particle's rate
+            let other_shear = if other_id < settings.boundary_particles {
+                my_shear
+            } else {
+                shear[other_idx]
+            };
+            let other_strength = rheology::viscosity(
+                settings,
+                materials.of(velocities[other_idx]).viscosity_strength,
+                other_shear,
+            );
+
+            force += (other_velocity - velocities[idx].truncate())
+                * influence
+                * 0.5
+                * (strength + other_strength);
+        }
+    }
+
+    deltas[idx] = (force * settings.dtime).extend(0.0);
+}
+
+/// Adds the velocity change `surface_tension` or `viscosity` left in the
+/// deltas.
+#[spirv(compute(threads(256)))]
+pub fn apply_forces(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] deltas: &mut [Vec4],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    velocities[idx] += deltas[idx];
+}
+
+/// Acceleration the fluid particle `idx` gets from a boundary particle of
+/// `volume` at `offset` from it, in one pressure iteration: from the pressure
+/// under SPH, the constraint multiplier under PBF and the stiffness of either
+/// solve under DFSPH.
+fn boundary_push(
+    settings: &Settings,
+    materials: &Materials,
+    idx: usize,
+    offset: Vec3,
+    dist: f32,
+    volume: f32,
+    velocities: &[Vec4],
+    densities: &[Vec2],
+    stiffness: &[f32],
+) -> Vec3 {
+    let me = materials.of(velocities[idx]);
+    let radius = settings.smoothing_radius;
+    let dir = offset / dist;
+    let grad = -dir * kernel::deriv(settings.kernel, dist, radius);
+    let [density, other] = densities[idx].to_array();
+    let psi = me.target_density * volume;
+
+    match settings.solver {
+        solver::PBF => {
+            let scale = volume * settings.mass / me.mass;
+            other * scale * grad / (settings.dtime * settings.dtime)
+        }
+        solver::DFSPH => {
+            let term = stiffness[idx] / density.max(f32::EPSILON);
+            -psi * term * grad
+        }
+        _ => {
+            let pressure = curves::density_to_pressure(
+                density,
+                me.target_density,
+                settings.pressure_multiplier,
+            );
+            let npressure = other * settings.near_pressure_multiplier;
+
+            let pressure_term = pressure.max(0.0) / density.powi(2);
+            let npressure_term = npressure / other.max(f32::EPSILON).powi(2);
+
+            psi * (pressure_term * kernel::deriv(settings.kernel, dist, radius)
+                + npressure_term * curves::ndensity_deriv(dist, radius))
+                * dir
+        }
+    }
+}
+
+/// Adds the reaction to what the fluid around each body particle got from it
+/// to the force and torque on its body.
+#[spirv(compute(threads(256)))]
+pub fn body_forces(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] stiffness: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 4)] bodies: &mut Bodies,
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] starts: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 0)] lookup: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 3, binding = 1)] keys: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || !settings.is_body(id) || settings.dtime <= 0.0 {
+        return;
+    }
+
+    let idx = id as usize;
+    let my_pos = predictions[idx].truncate();
+    let volume = densities[idx].x;
+    // body particles keep their body in w
+    let body = predictions[idx].w as usize;
+    let grid = sp_hash::Grid::new(settings);
+    let cell = grid.cell(my_pos);
+    let smoothing_radius_sq = settings.smoothing_radius * settings.smoothing_radius;
+    let mut force = Vec3::ZERO;
+
+    for neighbor_id in 0..sp_hash::NEIGHBORS.len() {
+        let (other_cell, visit) = grid.neighbor(cell, sp_hash::NEIGHBORS[neighbor_id]);
+        if !visit {
+            continue;
+        }
+        let hash = sp_hash::cell_hash(other_cell);
+        let key = sp_hash::key_from_hash(hash, settings.num_particles);
+        let start = starts[key as usize];
+
+        for i in start..settings.num_particles {
+            let particle_key = keys[i as usize];
+            if particle_key != key {
+                break;
+            }
+
+            let other_id = lookup[i as usize];
+            let other_idx = other_id as usize;
+
+            if other_id < settings.boundary_particles {
+                continue;
+            }
+
+            let offset = grid.offset(predictions[other_idx].truncate(), my_pos);
+            let dist_sq = offset.dot(offset);
+
+            if dist_sq > smoothing_radius_sq || dist_sq < f32::EPSILON {
+                continue;
+            }
+
+            let push = boundary_push(
+                settings,
+                materials,
+                other_idx,
+                offset,
+                dist_sq.sqrt(),
+                volume,
+                velocities,
+                densities,
+                stiffness,
+            );
+            force -= materials.of(velocities[other_idx]).mass * push;
+        }
+    }
+
+    let torque = (my_pos - bodies.bodies[body].position).cross(force);
+    let body = &mut bodies.bodies[body];
+    add_fixed(&mut body.force, force);
+    add_fixed(&mut body.torque, torque);
+}
+
+fn add_fixed(sum: &mut FixedSum, value: Vec3) {
+    for i in 0..3 {
+        let share = bodies::fixed(value[i]);
+
+        unsafe {
+            let before = atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                &mut sum.low[i],
+                share,
+            );
+
+            // as in `add_error`, each add sees the sum just before it
+            let carry = FixedSum::carry(before, share);
+            if carry != 0 {
+                atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+                    &mut sum.high[i],
+                    carry,
+                );
+            }
+        }
+    }
+}
+
+#[spirv(compute(threads(64)))]
+pub fn integrate_bodies(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] bodies: &mut Bodies,
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_bodies {
+        return;
+    }
+
+    bodies.bodies[id as usize].integrate(settings);
+}
+
+/// Carries each body particle along with its body, from its `anchor` in the
+/// body's frame. `w` of the anchor is the body.
+#[spirv(compute(threads(256)))]
+pub fn move_bodies(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] anchors: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 4)] bodies: &mut Bodies,
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles || !settings.is_body(id) {
+        return;
+    }
+
+    let idx = id as usize;
+    let anchor = anchors[idx];
+    let body = bodies.bodies[anchor.w as usize];
+    let position = body.world(anchor.truncate());
+
+    positions[idx] = position.extend(0.0);
+    predictions[idx] = position.extend(anchor.w);
+    velocities[idx] = body.velocity_at(position).extend(0.0);
+}
+
+/// Carries the walls of the box and the meshes fixed to it along with the
+/// box, from their `anchor` in its frame.
+#[spirv(compute(threads(256)))]
+pub fn move_box(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] anchors: &mut [Vec4],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id + settings.body_particles >= settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let position = settings.from_box(anchors[idx].truncate());
+
+    positions[idx] = position.extend(0.0);
+    predictions[idx] = position.extend(0.0);
+    velocities[idx] = settings.wall_velocity(position).extend(0.0);
+}
+
+#[spirv(compute(threads(256)))]
+pub fn update_positions(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles {
+        return;
+    }
+    if id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    // w of the velocity is the phase
+    positions[idx] += (velocities[idx].truncate() * settings.dtime).extend(0.0);
+}
+
+#[spirv(compute(threads(256)))]
+pub fn collide(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] obstacles: &Obstacles,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] sdfs: &SdfGrids,
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles {
+        return;
+    }
+    // Boundary particles live outside [0, size]; don't clamp them back in.
+    if id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let size = settings.box_size;
+    let rot = settings.box_quat;
+    let radius = settings.particle_radius;
+    let damping = settings.collision_damping;
+
+    let pos = positions[idx].truncate();
+    let vel = velocities[idx].truncate();
+
+    // bounced relative to the walls, which may be moving
+    let wall = settings.wall_velocity(pos);
+    let mut lpos = settings.to_box(pos);
+    let mut lvel = rot.conjugate() * (vel - wall);
+
+    // particles that crossed a periodic face come in through the other one
+    lpos = settings.wrap(lpos);
+
+    // the walls come last, so nothing is pushed out of the box
+    obstacles.collide(sdfs, &mut lpos, &mut lvel, radius, damping);
+
+    let walls = !settings.periodic_axes();
+
+    if walls.x && lpos.x < radius {
+        lpos.x = radius;
+        lvel.x *= -damping;
+    } else if walls.x && lpos.x > size.x - radius {
+        lpos.x = size.x - radius;
+        lvel.x *= -damping;
+    }
+
+    if walls.y && lpos.y < radius {
+        lpos.y = radius;
+        lvel.y *= -damping;
+    } else if walls.y && lpos.y > size.y - radius {
+        lpos.y = size.y - radius;
+        lvel.y *= -damping;
+    }
+
+    if walls.z && lpos.z < radius {
+        lpos.z = radius;
+        lvel.z *= -damping;
+    } else if walls.z && lpos.z > size.z - radius {
+        lpos.z = size.z - radius;
+        lvel.z *= -damping;
+    }
+
+    let pos = settings.from_box(lpos);
+    let vel = rot * lvel + wall;
+
+    positions[idx] = pos.extend(0.0);
+    velocities[idx] = vel.extend(velocities[idx].w);
+}
+
+// marks the fluid inside a sink, to be compacted away
+#[spirv(compute(threads(256)))]
+pub fn drain(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] flow: &Flow,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] state: &mut FlowState,
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= state.count || id < settings.boundary_particles {
+        return;
+    }
+
+    let idx = id as usize;
+    let local = settings.to_box(positions[idx].truncate());
+    if !flow.drains(local) {
+        return;
+    }
+
+    positions[idx].w = 1.0;
+    unsafe {
+        atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+            &mut state.removed,
+            1,
+        );
+    }
+}
+
+// whether the slot is a hole under the new count, or holds a particle past it
+// that moves into one, and which of the two counts in `blocks` it adds to
+fn compaction(id: u32, positions: &[Vec4], state: &FlowState) -> Option<usize> {
+    let keep = state.count - state.removed;
+    let marked = positions[id as usize].w != 0.0;
+
+    if id < keep && marked {
+        Some(0)
+    } else if id >= keep && !marked {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+// counts the holes and the particles moving into them in each block of slots
+#[spirv(compute(threads(256)))]
+pub fn count_holes(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] state: &mut FlowState,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] blocks: &mut [u32],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= state.count || id < settings.boundary_particles {
+        return;
+    }
+
+    let Some(kind) = compaction(id, positions, state) else {
+        return;
+    };
+
+    let block = (id / COMPACT_BLOCK) as usize;
+    unsafe {
+        atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+            &mut blocks[block * 2 + kind],
+            1,
+        );
+    }
+}
+
+// turns the counts of each block into the counts before it. A few thousand
+// blocks at most, so one thread walks them
+#[spirv(compute(threads(1)))]
+pub fn scan_holes(
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] state: &mut FlowState,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] blocks: &mut [u32],
+) {
+    let len = state.count.div_ceil(COMPACT_BLOCK) as usize;
+    let mut before = [0, 0];
+
+    let mut i = 0;
+    while i < len * 2 {
+        let kind = i % 2;
+        let count = blocks[i];
+        blocks[i] = before[kind];
+        before[kind] += count;
+        i += 1;
+    }
+
+    state.holes = before[0];
+}
+
+// pairs the nth hole with the nth particle past the new count, both in order
+// of their slots, so the particles land in the same place every run
+#[spirv(compute(threads(256)))]
+pub fn find_holes(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] state: &mut FlowState,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] blocks: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] holes: &mut [UVec2],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= state.count || id < settings.boundary_particles {
+        return;
+    }
+
+    let Some(kind) = compaction(id, positions, state) else {
+        return;
+    };
+
+    let block = id / COMPACT_BLOCK;
+    let mut rank = blocks[block as usize * 2 + kind];
+    let mut other = settings.boundary_particles.max(block * COMPACT_BLOCK);
+    while other < id {
+        if compaction(other, positions, state) == Some(kind) {
+            rank += 1;
+        }
+        other += 1;
+    }
+
+    if kind == 0 {
+        holes[rank as usize].x = id;
+    } else {
+        holes[rank as usize].y = id;
+    }
+}
+
+// moves each particle past the new count into the hole it was paired with
+#[spirv(compute(threads(256)))]
+pub fn fill_holes(
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] stiffness: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] previous: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] state: &mut FlowState,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] holes: &mut [UVec2],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= state.holes {
+        return;
+    }
+
+    let pair = holes[id as usize];
+    let (dst, src) = (pair.x as usize, pair.y as usize);
+
+    positions[dst] = positions[src];
+    predictions[dst] = predictions[src];
+    velocities[dst] = velocities[src];
+    densities[dst] = densities[src];
+    stiffness[dst] = stiffness[src];
+    previous[dst] = previous[src];
+}
+
+#[spirv(compute(threads(1)))]
+pub fn plan_flow(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] flow: &Flow,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] state: &mut FlowState,
+) {
+    state.plan(flow, settings, settings.dtime);
+}
+
+#[spirv(compute(threads(256)))]
+pub fn emit(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] flow: &Flow,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 4)] stiffness: &mut [f32],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 5)] previous: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 6)] state: &mut FlowState,
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= state.added {
+        return;
+    }
+
+    let (e, i) = state.source(id);
+    let emitter = &flow.emitters[e];
+    let (pos, vel) = emitter.spawn(
+        state.serial[e],
+        i,
+        state.due[e],
+        flow.spacing,
+        state.dtime,
+        settings.jitter_seed,
+    );
+
+    // emitters are fixed to the box, and move with it
+    let pos = settings.from_box(pos);
+    let vel = settings.box_quat * vel + settings.wall_velocity(pos);
+    let pos = pos.extend(0.0);
+    let vel = vel.extend(emitter.phase as f32);
+    let idx = (state.base + id) as usize;
+
+    positions[idx] = pos;
+    predictions[idx] = pos;
+    velocities[idx] = vel;
+    densities[idx] = Vec2::ZERO;
+    stiffness[idx] = 0.0;
+    previous[idx] = vel;
+}
+
+#[spirv(compute(threads(256)))]
+pub fn copy_prims(
+    #[spirv(uniform, descriptor_set = 0, binding = 0)] settings: &Settings,
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] coloring: &Coloring,
+    #[spirv(uniform, descriptor_set = 0, binding = 2)] materials: &Materials,
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 0)] positions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 1)] predictions: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 2)] velocities: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 1, binding = 3)] densities: &mut [Vec2],
+    #[spirv(storage_buffer, descriptor_set = 2, binding = 0)] prims: &mut [Primitive],
+
+    #[spirv(global_invocation_id)] id: UVec3,
+) {
+    let id = id.x;
+    if id >= settings.num_particles {
+        return;
+    }
+
+    let idx = id as usize;
+
+    if id < settings.boundary_particles {
+        prims[idx].translate = positions[idx].truncate();
+        prims[idx].color = if settings.is_body(id) {
+            vec4(0.55, 0.4, 0.3, 1.0)
+        } else {
+            vec4(0.2, 0.2, 0.2, 0.25)
+        };
+        return;
+    }
+
+    let material = materials.of(velocities[idx]);
+    prims[idx].translate = positions[idx].truncate();
+
+    if coloring.mode == Coloring::PHASE {
+        prims[idx].color = material.color;
+        return;
+    }
+
+    let value = match coloring.mode {
+        Coloring::DENSITY => densities[idx].x,
+        Coloring::NEAR_DENSITY => densities[idx].y,
+        Coloring::PRESSURE => curves::density_to_pressure(
+            densities[idx].x,
+            material.target_density,
+            settings.pressure_multiplier,
+        ),
+        Coloring::HEIGHT => settings.to_box(positions[idx].truncate()).y,
+        Coloring::ID => (id - settings.boundary_particles) as f32,
+        Coloring::NEIGHBORS => predictions[idx].w,
+        _ => velocities[idx].truncate().length(),
+    };
+
+    prims[idx].color = coloring.sample(value);
 }